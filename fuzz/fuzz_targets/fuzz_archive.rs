@@ -0,0 +1,132 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate archive_cache;
+
+use archive_cache::auth::Scope;
+use archive_cache::cache::{ArchiveCache, PathU8};
+use archive_cache::fs::Fs;
+use archive_cache::limits::Limits;
+use archive_cache::listing::EntryKind;
+use std::sync::RwLock;
+
+// small enough that a crafted archive hits them quickly
+const LIMITS: Limits = Limits {
+    max_depth: 2,
+    max_entry_size: 1024 * 1024,
+    max_ratio: 100,
+    max_extract: 256 * 1024,
+};
+
+// dirs followed inside one archive, so a deep tree does not stall
+const MAX_DIRS: usize = 16;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// zip holding data as single stored entry
+fn stored_zip(name: &str, data: &[u8]) -> Vec<u8> {
+    let crc = crc32(data);
+    let len = data.len() as u32;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+    out.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(data);
+
+    let central = out.len() as u32;
+    out.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+    out.extend_from_slice(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    out.extend_from_slice(&[0; 12]);
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+
+    let central_len = out.len() as u32 - central;
+    out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+    out.extend_from_slice(&central_len.to_le_bytes());
+    out.extend_from_slice(&central.to_le_bytes());
+    out.extend_from_slice(&[0, 0]);
+    out
+}
+
+// whatever is served, nothing may extract more than limits allow.
+// depth counts archives inside archives on the way to path, 0 for
+// archive on disk. disk_len is size of archive on disk holding path
+// directly, None once nested
+fn walk(
+    filesystem: &Fs,
+    cache: &RwLock<ArchiveCache>,
+    path: &PathU8,
+    depth: usize,
+    disk_len: Option<usize>,
+    dirs: usize,
+) {
+    let entries = match filesystem.list(cache, path, &Scope::All) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries {
+        let child = path.join(&entry.name);
+
+        match entry.kind {
+            EntryKind::File => {
+                let mut out = Vec::new();
+                if filesystem.read(cache, &child, &mut out, &Scope::All).is_ok() {
+                    assert!(out.len() <= LIMITS.max_entry_size);
+                    assert!(out.len() <= LIMITS.max_extract);
+                    if let Some(disk_len) = disk_len {
+                        assert!(out.len() <= disk_len * LIMITS.max_ratio);
+                    }
+                }
+            }
+            EntryKind::Archive if depth + 1 > LIMITS.max_depth => {
+                assert!(filesystem.list(cache, &child, &Scope::All).is_err());
+            }
+            EntryKind::Archive => walk(filesystem, cache, &child, depth + 1, None, 0),
+            EntryKind::Dir if dirs < MAX_DIRS => {
+                walk(filesystem, cache, &child, depth, disk_len, dirs + 1)
+            }
+            EntryKind::Dir => {}
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+
+    let root = std::env::temp_dir().join(format!("archive-cache-fuzz-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+
+    // same input on disk, and nested as deep as max_depth allows so
+    // any archive inside it crosses the limit
+    let mut nested = data.to_vec();
+    for _ in 0..LIMITS.max_depth {
+        nested = stored_zip("inner.zip", &nested);
+    }
+    std::fs::write(root.join("fuzz.zip"), data).unwrap();
+    std::fs::write(root.join("nested.zip"), &nested).unwrap();
+
+    let filesystem = Fs::with_roots(&[root.clone()]).unwrap();
+    let cache = RwLock::new(ArchiveCache::new(8 * 1024 * 1024, 4).with_limits(LIMITS));
+
+    walk(&filesystem, &cache, &PathU8::from("fuzz.zip"), 0, Some(data.len()), 0);
+    walk(&filesystem, &cache, &PathU8::from("nested.zip"), 0, Some(nested.len()), 0);
+});
@@ -33,7 +33,7 @@ fuzz_target!(|data: &[u8]| {
     let manifest_dir = env!("CARGO_MANIFEST_DIR");
 
     let root_dir = archive_cache::cache::PathU8::from(manifest_dir).join("../");
-        archive_cache::server::run_server_from_listener(tcp,&root_dir,rx);
+        archive_cache::server::run_server_from_listener(tcp,&archive_cache::config::Config::with_root(&root_dir),rx);
     });
 
     //make http response
@@ -17,11 +17,11 @@ fuzz_target!(|data: &[u8]| {
     let root_dir = archive_cache::cache::PathU8::from(manifest_dir).join("../");
 
     let filesystem = archive_cache::fs::Fs::new(&root_dir).unwrap();
-    let cache = std::sync::Mutex::new(archive_cache::cache::ArchiveCache::new(100, 100));
+    let cache = std::sync::RwLock::new(archive_cache::cache::ArchiveCache::new(100, 100));
             
     let mut cursor = std::io::Cursor::new(Vec::new());
     let utf8 = decode.unwrap();
 
-    filesystem.read(&cache,&archive_cache::cache::PathU8::from(utf8),&mut cursor);
+    filesystem.read(&cache,&archive_cache::cache::PathU8::from(utf8),&mut cursor,&archive_cache::auth::Scope::All);
 
 });
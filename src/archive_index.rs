@@ -0,0 +1,188 @@
+extern crate serde_json;
+
+use super::disk_cache::key_of;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const TMP_SUFFIX: &str = ".tmp";
+
+//bump when IndexedEntry changes, older records are ignored
+const INDEX_VERSION: u32 = 4;
+
+//entry of archive as served, and how to find it again in archive
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IndexedEntry {
+    pub name: String,
+    pub size: usize,
+    //name archive library lists it by, may differ from decoded name
+    pub key: String,
+    //local header of zip entry, from central directory read by us
+    pub offset: Option<i64>,
+    //of zip entry, from central directory too
+    pub crc: Option<u32>,
+}
+
+//size and mtime (secs, nanos) of archive file when it was indexed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Stamp {
+    size: u64,
+    mtime: (u64, u32),
+}
+
+impl Stamp {
+    //None if file system does not report mtime, such file is not indexed
+    pub fn of(path: &Path) -> Option<Stamp> {
+        let meta = std::fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+        Some(Stamp {
+            size: meta.len(),
+            mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+        })
+    }
+
+    //stable text of stamp, for keys of things derived from the file
+    pub fn to_key(&self) -> String {
+        format!("{}-{}.{}", self.size, self.mtime.0, self.mtime.1)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    version: u32,
+    path: String,
+    stamp: Stamp,
+    //label of charset names were decoded by
+    charset: String,
+    entries: Vec<IndexedEntry>,
+}
+
+//entry lists of archive files, kept on disk so archives are not
+//enumerated again after restart. one file per archive path, a record
+//whose stamp (or charset) differs from the archive file is stale and
+//gets replaced
+pub struct ArchiveIndex {
+    dir: PathBuf,
+}
+
+impl ArchiveIndex {
+    pub fn open(dir: &PathBuf) -> std::io::Result<ArchiveIndex> {
+        std::fs::create_dir_all(dir)?;
+
+        for file in std::fs::read_dir(dir)? {
+            let file = file?;
+            if file.file_name().to_string_lossy().ends_with(TMP_SUFFIX) {
+                //crashed while writing
+                let _ = std::fs::remove_file(file.path());
+            }
+        }
+
+        Ok(ArchiveIndex { dir: dir.clone() })
+    }
+
+    fn path_of(&self, archive: &Path) -> PathBuf {
+        self.dir.join(key_of(&[&archive.to_string_lossy()]))
+    }
+
+    pub fn get(&self, archive: &Path, stamp: &Stamp, charset: &str) -> Option<Vec<IndexedEntry>> {
+        let bin = std::fs::read(self.path_of(archive)).ok()?;
+
+        let record: Record = match serde_json::from_slice(&bin) {
+            Ok(record) => record,
+            Err(e) => {
+                debug!("archive index of {:?} unreadable: {}", archive, e);
+                return None;
+            }
+        };
+
+        if record.version != INDEX_VERSION
+            || record.path != archive.to_string_lossy()
+            || record.stamp != *stamp
+            || record.charset != charset
+        {
+            trace!("archive index of {:?} is stale", archive);
+            return None;
+        }
+
+        trace!("archive index hit {:?}", archive);
+        Some(record.entries)
+    }
+
+    pub fn put(
+        &self,
+        archive: &Path,
+        stamp: &Stamp,
+        charset: &str,
+        entries: &[IndexedEntry],
+    ) -> std::io::Result<()> {
+        let record = Record {
+            version: INDEX_VERSION,
+            path: archive.to_string_lossy().into_owned(),
+            stamp: *stamp,
+            charset: charset.to_owned(),
+            entries: entries.to_vec(),
+        };
+
+        let bin = serde_json::to_vec(&record)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+        let path = self.path_of(archive);
+
+        //write then rename, so reader never sees partial file
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(TMP_SUFFIX);
+        {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(&bin)?;
+        }
+        std::fs::rename(&tmp, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        let dir = std::env::temp_dir().join(format!("archive-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let archive = dir.join("a.zip");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&archive, b"1234").unwrap();
+
+        let index = ArchiveIndex::open(&dir.join("index")).unwrap();
+        let stamp = Stamp::of(&archive).unwrap();
+
+        assert!(index.get(&archive, &stamp, "detect").is_none());
+
+        let entries = vec![IndexedEntry {
+            name: "1.jpg".to_owned(),
+            size: 10,
+            key: "1.jpg".to_owned(),
+            offset: Some(0),
+            crc: Some(0),
+        }];
+        index.put(&archive, &stamp, "detect", &entries).unwrap();
+        assert_eq!(index.get(&archive, &stamp, "detect").unwrap(), entries);
+
+        //names may decode differently
+        assert!(index.get(&archive, &stamp, "Shift_JIS").is_none());
+
+        //survives reopen
+        let index = ArchiveIndex::open(&dir.join("index")).unwrap();
+        assert_eq!(index.get(&archive, &stamp, "detect").unwrap(), entries);
+
+        //changed file
+        std::fs::write(&archive, b"12345").unwrap();
+        let changed = Stamp::of(&archive).unwrap();
+        assert_ne!(changed, stamp);
+        assert!(index.get(&archive, &changed, "detect").is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
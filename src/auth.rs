@@ -0,0 +1,361 @@
+extern crate argon2;
+extern crate base64;
+
+use super::cache::{join_may_empty, PathU8};
+use super::config::invalid;
+use super::disk_cache::key_of;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::path::Component;
+use std::sync::Mutex;
+
+pub const REALM: &str = "comix";
+
+//virtual paths a user may reach
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scope {
+    All,
+    Subtrees(Vec<PathU8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    //path is in an allowed subtree
+    Inside,
+    //path holds an allowed subtree, only listing it is allowed and
+    //children outside are hidden
+    Ancestor,
+    Outside,
+}
+
+//resolve . and .. without touching disk, None if it goes above top
+pub fn normalize(path: &PathU8) -> Option<PathU8> {
+    let mut ret = PathU8::new();
+
+    for comp in path.components() {
+        match comp {
+            Component::Normal(name) => ret.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !ret.pop() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    Some(ret)
+}
+
+impl Scope {
+    pub fn visibility(&self, path: &PathU8) -> Visibility {
+        let roots = match self {
+            Scope::All => return Visibility::Inside,
+            Scope::Subtrees(roots) => roots,
+        };
+
+        let path = match normalize(path) {
+            Some(path) => path,
+            None => return Visibility::Outside,
+        };
+
+        if roots.iter().any(|root| path.starts_with(root)) {
+            Visibility::Inside
+        } else if roots.iter().any(|root| root.starts_with(&path)) {
+            Visibility::Ancestor
+        } else {
+            Visibility::Outside
+        }
+    }
+
+    //PermissionDenied for path outside
+    pub fn check(&self, path: &PathU8) -> std::io::Result<Visibility> {
+        match self.visibility(path) {
+            Visibility::Outside => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{:?} is not allowed", path),
+            )),
+            visibility => Ok(visibility),
+        }
+    }
+
+    //name under dir leads into an allowed subtree
+    pub fn shows(&self, dir: &PathU8, name: &str) -> bool {
+        self.visibility(&join_may_empty(dir, &PathU8::from(name))) != Visibility::Outside
+    }
+}
+
+//[users.NAME] tables of user file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileUser {
+    //argon2 encoded hash, user without one can only use tokens
+    password: Option<String>,
+    //virtual paths, whole library if missing
+    allow: Option<Vec<String>>,
+    //sha256 (hex) of api tokens
+    tokens: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct UserFile {
+    users: HashMap<String, FileUser>,
+}
+
+struct User {
+    password: Option<String>,
+    scope: Scope,
+}
+
+//users allowed to access server, with Basic (user:password) or Bearer
+//(api token) credentials
+pub struct Auth {
+    users: HashMap<String, User>,
+    //token hash => user name
+    tokens: HashMap<String, String>,
+    //argon2 is slow on purpose, remember credentials once verified
+    verified: Mutex<HashSet<String>>,
+}
+
+impl Auth {
+    pub fn load(path: &PathU8) -> std::io::Result<Auth> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("can not read user file {:?}: {}", path, e),
+            )
+        })?;
+
+        Auth::parse(&content).map_err(|e| invalid(format!("user file {:?}: {}", path, e)))
+    }
+
+    pub fn parse(content: &str) -> std::io::Result<Auth> {
+        let file: UserFile = toml::from_str(content).map_err(|e| invalid(e.to_string()))?;
+
+        let mut users = HashMap::new();
+        let mut tokens = HashMap::new();
+
+        for (name, user) in file.users {
+            if name.contains(':') {
+                return Err(invalid(format!("user name {:?} has ':'", name)));
+            }
+
+            if let Some(ref hash) = user.password {
+                if !hash.starts_with("$argon2") {
+                    return Err(invalid(format!(
+                        "password of {:?} is not argon2 hash",
+                        name
+                    )));
+                }
+            }
+
+            for token in user.tokens.unwrap_or_default() {
+                let token = token.to_ascii_lowercase();
+                if let Some(other) = tokens.insert(token, name.clone()) {
+                    return Err(invalid(format!(
+                        "token of {:?} is used by {:?}",
+                        name, other
+                    )));
+                }
+            }
+
+            let scope = match user.allow {
+                None => Scope::All,
+                Some(allow) => {
+                    let mut roots = Vec::new();
+                    //compared to resolved request paths, so resolved too
+                    for entry in allow {
+                        let root = normalize(&PathU8::from(&entry)).ok_or_else(|| {
+                            invalid(format!(
+                                "allow {:?} of {:?} is absolute or goes above top",
+                                entry, name
+                            ))
+                        })?;
+                        roots.push(root);
+                    }
+                    Scope::Subtrees(roots)
+                }
+            };
+
+            users.insert(
+                name,
+                User {
+                    password: user.password,
+                    scope,
+                },
+            );
+        }
+
+        Ok(Auth {
+            users,
+            tokens,
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn basic(&self, encoded: &str) -> Option<(&str, &Scope)> {
+        let decoded = base64::decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+
+        let mut parts = decoded.splitn(2, ':');
+        let (name, password) = (parts.next()?, parts.next()?);
+
+        let (name, user) = self.users.get_key_value(name)?;
+        let hash = user.password.as_ref()?;
+
+        let key = key_of(&[name, hash, password]);
+
+        if !self.verified.lock().unwrap().contains(&key) {
+            if !argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false) {
+                debug!("wrong password of {:?}", name);
+                return None;
+            }
+            self.verified.lock().unwrap().insert(key);
+        }
+
+        Some((name, &user.scope))
+    }
+
+    fn token(&self, token: &str) -> Option<(&str, &Scope)> {
+        let name = self.tokens.get(&key_of(&[token.trim()]))?;
+        let (name, user) = self.users.get_key_value(name)?;
+        Some((name, &user.scope))
+    }
+
+    //user name and scope from Authorization header, Basic or Bearer
+    //token. tokens are not taken from query, urls end up in logs. None
+    //if credentials are missing or wrong
+    pub fn authenticate(&self, authorization: Option<&str>) -> Option<(&str, &Scope)> {
+        let mut parts = authorization?.trim().splitn(2, ' ');
+        let (scheme, value) = (parts.next()?, parts.next().unwrap_or(""));
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            self.basic(value)
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            self.token(value)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn users() -> String {
+        let hash =
+            argon2::hash_encoded(b"secret", b"saltsaltsalt", &argon2::Config::default()).unwrap();
+
+        format!(
+            r#"
+            [users.admin]
+            password = "{}"
+
+            [users.kid]
+            password = "{}"
+            allow = ["kids", "shared/a.zip"]
+            tokens = ["{}"]
+            "#,
+            hash,
+            hash,
+            key_of(&["app-token"])
+        )
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::encode(&format!("{}:{}", user, password))
+        )
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = Auth::parse(&users()).unwrap();
+
+        let (name, scope) = auth.authenticate(Some(&basic("admin", "secret"))).unwrap();
+        assert_eq!(name, "admin");
+        assert_eq!(*scope, Scope::All);
+
+        //verified one is remembered
+        assert!(auth.authenticate(Some(&basic("admin", "secret"))).is_some());
+
+        assert!(auth.authenticate(Some(&basic("admin", "wrong"))).is_none());
+        assert!(auth
+            .authenticate(Some(&basic("nobody", "secret")))
+            .is_none());
+        assert!(auth.authenticate(Some("Basic !!!")).is_none());
+        assert!(auth.authenticate(None).is_none());
+
+        assert_eq!(
+            auth.authenticate(Some("Bearer app-token")).unwrap().0,
+            "kid"
+        );
+        assert!(auth.authenticate(Some("Bearer other")).is_none());
+        assert!(auth.authenticate(Some("app-token")).is_none());
+
+        assert!(Auth::parse("[users.a]\npassword = \"plain\"").is_err());
+        assert!(Auth::parse("[users.\"a:b\"]").is_err());
+
+        let spelled =
+            Auth::parse("[users.a]\nallow = [\"./kids\", \"kids/\", \"x/../shared//a.zip\"]");
+        assert_eq!(
+            spelled.unwrap().users["a"].scope,
+            Scope::Subtrees(vec![
+                PathU8::from("kids"),
+                PathU8::from("kids"),
+                PathU8::from("shared/a.zip")
+            ])
+        );
+
+        for allow in &["/kids", "..", "kids/../.."] {
+            let file = format!("[users.a]\nallow = [{:?}]", allow);
+            assert!(Auth::parse(&file).is_err(), "{:?} is accepted", allow);
+        }
+    }
+
+    #[test]
+    fn test_scope() {
+        let scope = Scope::Subtrees(vec![PathU8::from("kids"), PathU8::from("shared/a.zip")]);
+
+        assert_eq!(scope.visibility(&PathU8::from("kids")), Visibility::Inside);
+        assert_eq!(
+            scope.visibility(&PathU8::from("kids/x.zip/1.jpg")),
+            Visibility::Inside
+        );
+        assert_eq!(scope.visibility(&PathU8::from("")), Visibility::Ancestor);
+        assert_eq!(
+            scope.visibility(&PathU8::from("shared")),
+            Visibility::Ancestor
+        );
+        //not a path prefix by component
+        assert_eq!(
+            scope.visibility(&PathU8::from("kidsx")),
+            Visibility::Outside
+        );
+        assert_eq!(
+            scope.visibility(&PathU8::from("kids/../adult")),
+            Visibility::Outside
+        );
+        assert_eq!(
+            scope.visibility(&PathU8::from("./shared/./a.zip/x/..")),
+            Visibility::Inside
+        );
+        assert_eq!(
+            scope
+                .check(&PathU8::from("shared/b.zip"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        assert!(scope.shows(&PathU8::from("shared"), "a.zip"));
+        assert!(!scope.shows(&PathU8::from("shared"), "b.zip"));
+        assert!(scope.shows(&PathU8::new(), "kids"));
+    }
+}
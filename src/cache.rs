@@ -3,17 +3,24 @@ extern crate unarr;
 
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-
-#[cfg(not(debug_assertions))]
-use std::hash::Hasher;
-
-use std::io::{Error, ErrorKind, Read};
-use std::iter::FromIterator;
+use std::io::{Cursor, Read, Seek};
 use std::string::String;
+use std::sync::{Arc, Mutex, RwLock};
 use std::vec::Vec;
 
 use lru::LruCache;
 
+use super::archive_index::{ArchiveIndex, IndexedEntry, Stamp};
+use super::charset::{decode_names, Charset};
+use super::error::{self, Error};
+use super::eviction::{Eviction, WeightedCache};
+use super::limits::{Budget, Limits};
+use super::listing::{mime_from_name, EntryInfo, EntryKind};
+use super::path_table::{NodeId, PathTable};
+use super::sort::sorted_names;
+use super::tarball::{self, Plain, PlainFile, Spool};
+use super::zipdir::{self, u32_at, Central, LOCAL_SIG};
+
 use unarr::{ArArchive, ArEntry, ArStream};
 
 pub type PathU8 = std::path::PathBuf;
@@ -22,12 +29,6 @@ pub type Binary = std::vec::Vec<u8>;
 
 pub const VIRTUAL_ROOT_PATH: &str = "*\\/virtual_root\\/*";
 
-#[cfg(not(debug_assertions))]
-type NodeId = u64;
-
-#[cfg(debug_assertions)]
-type NodeId = PathU8;
-
 pub struct RevPathWalker {
     root: PathU8,
     left: PathU8,
@@ -55,7 +56,7 @@ impl Iterator for RevPathWalker {
 
         //not first .test left is empty
         //
-        if self.root.to_str().unwrap().is_empty() {
+        if self.root.as_os_str().is_empty() {
             return None;
         }
 
@@ -73,11 +74,11 @@ pub enum FileOrMem<'a> {
 }
 
 pub fn join_may_empty(lhs: &PathU8, rhs: &PathU8) -> PathU8 {
-    if lhs.to_str().unwrap().is_empty() {
+    if lhs.as_os_str().is_empty() {
         return rhs.clone();
     }
 
-    if rhs.to_str().unwrap().is_empty() {
+    if rhs.as_os_str().is_empty() {
         return lhs.clone();
     }
 
@@ -85,22 +86,32 @@ pub fn join_may_empty(lhs: &PathU8, rhs: &PathU8) -> PathU8 {
 }
 
 pub fn is_archive(name: &PathU8, file_or_mem: FileOrMem) -> bool {
-    let archive_exts = vec!["zip", "cbz", "rar", "cbr", "tar", "7z"];
+    let archive_exts = vec!["zip", "cbz", "rar", "cbr", "tar", "cbt", "7z", "cb7"];
 
-    let ext = name.extension();
+    if tarball::is_tarball_name(name) {
+        return true;
+    }
 
-    if ext.is_some() {
+    if let Some(ext) = name.extension() {
         //not archive ext, and no left path
-        return archive_exts.contains(&ext.unwrap().to_str().unwrap());
+        return ext
+            .to_str()
+            .map_or(false, |ext| archive_exts.contains(&ext));
     }
 
     //no ext, try to detect by mime
     let mime;
     match file_or_mem {
         FileOrMem::Path(path) => {
+            if tarball::is_tarball_file(path) {
+                return true;
+            }
             mime = tree_magic::from_filepath(path);
         }
         FileOrMem::Mem(mem) => {
+            if tarball::is_tarball(mem) {
+                return true;
+            }
             mime = tree_magic::from_u8(mem);
         }
     }
@@ -113,104 +124,251 @@ pub fn is_archive(name: &PathU8, file_or_mem: FileOrMem) -> bool {
     false
 }
 
-fn path_to_id(path: &PathU8) -> NodeId {
-    #[cfg(debug_assertions)]
-    return path.clone();
+//bytes of archive entry or dir tree node beside its name
+const NODE_COST: usize = 64;
+
+//times to reopen archive evicted by concurrent requests
+const LOAD_RETRY: usize = 3;
+
+//blob hits under read lock waiting to count in eviction order, later
+//ones are not recorded until next write
+const TOUCH_BACKLOG: usize = 4096;
+
+//opened archive shared between requests. entries are read only after
+//open, so they can be looked up without waiting for extraction
+pub struct OpenedArchive {
+    //held while decompressing, per archive
+    ar: Mutex<Extractor>,
+    entries: HashMap<String, IndexedEntry>,
+    //bytes of archive file or of nested archive in memory
+    size: usize,
+    //plain archive on disk entries can be read again from: archive file
+    //itself or tar unpacked into spool, pinned there while opened.
+    //None if it is only in memory
+    file: Option<PlainFile>,
+    //0 for archive file, one more for each archive it is nested in
+    depth: usize,
+}
+
+//archive library handle with entries it lists, by key
+struct Extractor {
+    ar: ArArchive,
+    //listed on first read, so archive opened from index is not
+    //enumerated until something is read from it
+    listed: Option<HashMap<String, ArEntry>>,
+}
 
-    #[cfg(not(debug_assertions))]
-    {
-        let mut s = std::collections::hash_map::DefaultHasher::new();
-        s.write(path.to_str().unwrap().as_bytes());
-        s.finish()
+//zip central directory, empty for other formats
+fn central_in<R: Read + Seek>(mut reader: R) -> Vec<Central> {
+    let mut magic = [0; 4];
+    if reader.read_exact(&mut magic).is_err() || u32_at(&magic, 0) != LOCAL_SIG {
+        return Vec::new();
     }
+    zipdir::read_central(&mut reader).unwrap_or_default()
 }
 
-struct SizedLru {
-    lru: LruCache<NodeId, Binary>,
-    size: usize,
-    limit: usize,
+//zip entries are matched to central directory for where they are
+//and their names as stored, which are decoded by charset so legacy
+//encoded names are not left to whatever the archive library guessed.
+//other formats keep names the library gives
+fn enumerate(ar: &ArArchive, central: &[Central], charset: Charset) -> Vec<IndexedEntry> {
+    let entries: Vec<_> = ar.iter().collect();
+    let listed: Vec<(&str, usize)> = entries.iter().map(|e| (e.name(), e.size())).collect();
+    let paired = zipdir::pair(&listed, central);
+
+    let raws: Vec<&[u8]> = match paired {
+        Some(ref paired) => paired.iter().map(|c| &c.name[..]).collect(),
+        None => entries.iter().map(|e| e.name().as_bytes()).collect(),
+    };
+
+    decode_names(&raws, charset)
+        .into_iter()
+        .zip(entries.iter())
+        .enumerate()
+        .map(|(i, (name, e))| IndexedEntry {
+            name,
+            size: e.size(),
+            key: e.name().to_owned(),
+            offset: paired.as_ref().map(|p| p[i].offset),
+            crc: paired.as_ref().map(|p| p[i].crc),
+        })
+        .collect()
 }
 
-impl SizedLru {
-    fn new(limit: usize) -> SizedLru {
-        SizedLru {
-            lru: LruCache::new(4096),
-            size: 0,
-            limit,
-        }
+impl OpenedArchive {
+    //listing entries may take a while for large archive, do it
+    //before taking cache lock
+    pub fn new(ar: ArArchive, central: &[Central], charset: Charset, size: usize) -> OpenedArchive {
+        let entries = enumerate(&ar, central, charset);
+        OpenedArchive::with_entries(ar, entries, size)
     }
 
-    fn contains_key(&self, key: &NodeId) -> bool {
-        self.lru.contains(key)
+    fn with_entries(ar: ArArchive, entries: Vec<IndexedEntry>, size: usize) -> OpenedArchive {
+        OpenedArchive {
+            ar: Mutex::new(Extractor { ar, listed: None }),
+            entries: entries.into_iter().map(|e| (e.name.clone(), e)).collect(),
+            size,
+            file: None,
+            depth: 0,
+        }
     }
 
-    fn put(&mut self, key: &NodeId, binary: Binary) -> &Binary {
-        debug_assert!(self.lru.get(key).is_none(), "{:?} already exists!", key);
+    //archive bytes held in memory
+    fn held(&self) -> usize {
+        if self.file.is_some() {
+            0
+        } else {
+            self.size
+        }
+    }
 
-        trace!("set file cache {:?}", key);
+    //memory held beside archive bytes (if any)
+    fn entries_cost(&self) -> usize {
+        self.entries.keys().map(|name| name.len() + NODE_COST).sum()
+    }
 
-        self.size += binary.len();
+    //entries are taken from index if archive file is unchanged since
+    //it was indexed. compressed tarball is unpacked first, into spool
+    //if there is one, otherwise into memory up to mem_limit
+    pub fn open(
+        archive_path: &PathU8,
+        index: Option<&ArchiveIndex>,
+        charset: Charset,
+        spool: Option<&Spool>,
+        limits: &Limits,
+        mem_limit: usize,
+    ) -> error::Result<OpenedArchive> {
+        debug!("try to open {:?} as archive", archive_path);
 
-        self.lru.put(key.clone(), binary);
+        //taken before reading, so change during enumeration is noticed
+        let stamp = index.and_then(|_| Stamp::of(archive_path));
 
-        if self.size > self.limit {
-            self.recycle(key);
-        }
+        let indexed = match (index, stamp) {
+            (Some(index), Some(stamp)) => index.get(archive_path, &stamp, charset.label()),
+            _ => None,
+        };
 
-        debug_assert!(self.lru.contains(key));
+        //only needed to enumerate
+        let mut central = Vec::new();
 
-        self.lru.get(key).unwrap()
-    }
+        let (stream, size, file) = match tarball::plain_of(archive_path, spool, limits, mem_limit)?
+        {
+            Plain::File(file) => {
+                let size = std::fs::metadata(&file.path)
+                    .map_err(|e| Error::io(format!("stat {:?}", file.path), e))?
+                    .len() as usize;
+
+                if indexed.is_none() {
+                    central = std::fs::File::open(&file.path)
+                        .map(central_in)
+                        .unwrap_or_default();
+                }
 
-    fn get(&mut self, key: &NodeId) -> Option<&Binary> {
-        self.lru.get(key)
-    }
+                let stream = ArStream::from_file(&file.path)
+                    .map_err(|e| Error::io(format!("open {:?}", file.path), e))?;
 
-    fn remove(&mut self, node_id: &NodeId) {
-        match self.lru.get(node_id) {
-            None => {
-                return;
-            }
-            Some(v) => {
-                debug_assert!(self.size >= v.len());
-                self.size -= v.len();
+                (stream, size, Some(file))
             }
-        }
-    }
-
-    fn recycle(&mut self, preserved_key: &NodeId) {
-        debug_assert!(self.size > self.limit);
-
-        let mut keys: Vec<NodeId> = Vec::new();
+            Plain::Mem(binary) => {
+                if indexed.is_none() {
+                    central = central_in(Cursor::new(&binary));
+                }
 
-        //try to remove most unused ones
-        for (id, bin) in self.lru.iter().rev() {
-            if id == preserved_key {
-                continue;
+                let size = binary.len();
+                (ArStream::from_memory(binary), size, None)
             }
+        };
 
-            keys.push(id.clone());
+        let ar = ArArchive::new(stream, None).map_err(|e| {
+            Error::Unsupported(format!(
+                "{:?} can not be opened as archive: {}",
+                archive_path, e
+            ))
+        })?;
 
-            debug_assert!(self.size >= bin.len());
+        let entries = match indexed {
+            Some(entries) => entries,
+            None => {
+                let entries = enumerate(&ar, &central, charset);
 
-            self.size -= bin.len();
+                if let (Some(index), Some(stamp)) = (index, stamp) {
+                    if let Err(e) = index.put(archive_path, &stamp, charset.label(), &entries) {
+                        warn!("can not index {:?}: {:?}", archive_path, e);
+                    }
+                }
 
-            if self.size <= self.limit {
-                break;
+                entries
             }
-        }
+        };
+
+        let mut opened = OpenedArchive::with_entries(ar, entries, size);
+        opened.file = file;
+        Ok(opened)
+    }
 
-        for key in keys.iter() {
-            self.lru.pop(key);
+    //caller should hold ar lock. declared size is only a hint, budget
+    //stops reading as soon as a limit is crossed
+    fn read(
+        &self,
+        ex: &mut Extractor,
+        entry: &IndexedEntry,
+        budget: &mut Budget,
+    ) -> error::Result<Binary> {
+        //entry list said it is there, failing now means broken archive
+        let broken = |e| Error::Unsupported(format!("can not extract {:?}: {}", entry.name, e));
+
+        if ex.listed.is_none() {
+            let ar = &ex.ar;
+            ex.listed = Some(ar.iter().map(|e| (e.name().to_owned(), e)).collect());
         }
+
+        let located = ex
+            .listed
+            .as_ref()
+            .and_then(|listed| listed.get(&entry.key))
+            .ok_or_else(|| broken("not listed by archive library".to_owned()))?;
+        let mut reader = ex
+            .ar
+            .reader_for(located)
+            .map_err(|e| broken(e.to_string()))?;
+
+        budget.read(&mut reader, &entry.name, entry.size, self.size)
     }
 }
 
+struct CachedArchive {
+    opened: Arc<OpenedArchive>,
+    virtual_path: PathU8,
+    //nested archive bytes in memory, entries and dir tree nodes
+    cost: usize,
+}
+
+//lock order is per archive lock first, then cache lock. cache lock
+//is never held while reading archive. cached dirs and blobs are
+//looked up under read lock, so hits do not wait for each other
+//
+//file blobs, opened archives and their dir trees share mem_limit.
+//blobs are evicted first since they are cheap to extract again, then
+//whole archives (with dir tree and nested archives under them)
 pub struct ArchiveCache {
-    file_cache: SizedLru,
+    //limit is given by us, shared with opened archives
+    file_cache: WeightedCache<NodeId, Arc<Binary>>,
     dir_tree: HashMap<NodeId, HashMap<String, NodeId>>,
-    //archive , all name=>ArEntry map, is_nested
-    archive_cache: LruCache<NodeId, (ArArchive, HashMap<String, ArEntry>, bool)>,
+    archive_cache: LruCache<NodeId, CachedArchive>,
+    //every node in dir_tree (children included) has an id here
+    paths: PathTable,
+    //id of VIRTUAL_ROOT_PATH, never released
+    root: NodeId,
+    //sum of cost in archive_cache
+    archive_size: usize,
+    mem_limit: usize,
+    archive_limit: usize,
+    index: Option<Arc<ArchiveIndex>>,
+    charset: Charset,
+    limits: Limits,
+    spool: Option<Arc<Spool>>,
+    //blobs hit by quick_try, touched in file_cache on next eviction
+    touched: Mutex<Vec<NodeId>>,
 }
 
 impl Display for ArchiveCache {
@@ -218,7 +376,8 @@ impl Display for ArchiveCache {
         #[cfg(debug_assertions)]
         {
             for (key, value) in self.dir_tree.iter() {
-                writeln!(f, "{}:", key.display())?;
+                let path = self.paths.path_of(*key).unwrap();
+                writeln!(f, "{}:", path.display())?;
 
                 for name in value.keys() {
                     writeln!(f, "-{}", to_display_name(name))?;
@@ -226,11 +385,8 @@ impl Display for ArchiveCache {
             }
         }
 
-        let virtual_root_path = PathU8::from(VIRTUAL_ROOT_PATH);
-        let virtual_root_id = path_to_id(&virtual_root_path);
-
         let root = String::from(VIRTUAL_ROOT_PATH);
-        let walked = vec![(&root, &virtual_root_id, true)];
+        let walked = vec![(&root, &self.root, true)];
 
         self.recurisve_walk(&walked, true, f);
 
@@ -238,9 +394,14 @@ impl Display for ArchiveCache {
     }
 }
 
-pub enum NodeContents<'a> {
-    File(&'a Vec<u8>),
-    Dir(Vec<&'a String>),
+//owned, so cache lock can be released before using it
+pub enum NodeContents {
+    File(Arc<Binary>),
+    Dir(Vec<String>),
+}
+
+fn listing_of(children: &HashMap<String, NodeId>) -> NodeContents {
+    NodeContents::Dir(sorted_names(children.keys()).into_iter().cloned().collect())
 }
 
 fn to_display_name(v: &str) -> String {
@@ -316,289 +477,441 @@ impl ArchiveCache {
         }
     }
 
-    pub fn new(binary_limit: usize, archive_limit: usize) -> ArchiveCache {
+    pub fn new(mem_limit: usize, archive_limit: usize) -> ArchiveCache {
+        let mut paths = PathTable::new();
+        let root = paths.intern(&PathU8::from(VIRTUAL_ROOT_PATH));
+
         let mut ret = ArchiveCache {
-            file_cache: SizedLru::new(binary_limit),
+            file_cache: WeightedCache::new(Eviction::default()),
             dir_tree: HashMap::new(),
-            archive_cache: LruCache::new(archive_limit),
+            //evicted by us, so dir_tree stays consistent
+            archive_cache: LruCache::unbounded(),
+            paths,
+            root,
+            archive_size: 0,
+            mem_limit,
+            archive_limit,
+            index: None,
+            charset: Charset::default(),
+            limits: Limits::default(),
+            spool: None,
+            touched: Mutex::new(Vec::new()),
         };
 
-        let virtual_root_path = &PathU8::from(VIRTUAL_ROOT_PATH);
-        let virtual_root_id = path_to_id(virtual_root_path);
-
-        ret.dir_tree.insert(virtual_root_id, HashMap::new());
+        ret.dir_tree.insert(root, HashMap::new());
 
         ret
     }
 
-    pub fn invalid_path(&mut self, virtual_path: &PathU8) {
-        let virtual_root_id = path_to_id(&PathU8::from(VIRTUAL_ROOT_PATH));
+    //policy choosing which file blob goes first, archives are always lru
+    pub fn with_eviction(mut self, eviction: Eviction) -> ArchiveCache {
+        debug_assert_eq!(self.file_cache.len(), 0);
+        self.file_cache = WeightedCache::new(eviction);
+        self
+    }
 
-        let children = self.dir_tree.get(&virtual_root_id).unwrap().clone();
+    //entry lists of archive files are kept in index
+    pub fn with_index(mut self, index: Arc<ArchiveIndex>) -> ArchiveCache {
+        self.index = Some(index);
+        self
+    }
+
+    //how entry names which are not utf8 are decoded
+    pub fn with_charset(mut self, charset: Charset) -> ArchiveCache {
+        self.charset = charset;
+        self
+    }
 
-        for key in children.keys() {
-            let prefix: bool = key.starts_with(virtual_path.to_str().unwrap());
-            if prefix {
-                self.remove_by_id(&path_to_id(&PathU8::from(key)));
+    //nesting depth and sizes allowed when extracting
+    pub fn with_limits(mut self, limits: Limits) -> ArchiveCache {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    //compressed tarball files are unpacked into spool instead of memory
+    pub fn with_spool(mut self, spool: Arc<Spool>) -> ArchiveCache {
+        self.spool = Some(spool);
+        self
+    }
+
+    //plain archive file entries of opened archive can be read from,
+    //kept in spool as long as returned one is held
+    pub fn file_of(&self, virtual_path: &PathU8) -> Option<PlainFile> {
+        self.id_of(virtual_path)
+            .and_then(|id| self.archive_cache.peek(&id))
+            .and_then(|cached| cached.opened.file.clone())
+    }
+
+    //drop every opened archive at or under virtual_path, nested
+    //archives inside them included. empty path drops all
+    pub fn invalid_path(&mut self, virtual_path: &PathU8) {
+        let stale: Vec<(String, NodeId)> = self
+            .dir_tree
+            .get(&self.root)
+            .unwrap()
+            .iter()
+            //compare by components, a/b should not match a/bc.zip
+            .filter(|(key, _)| PathU8::from(key).starts_with(virtual_path))
+            .map(|(key, id)| (key.clone(), *id))
+            .collect();
+
+        //no path is interned meanwhile, so id of archive already
+        //dropped under an outer one is not reused by others
+        for (key, node_id) in stale {
+            debug!("invalid cached archive {:?}", key);
+            self.remove_by_id(node_id);
+            self.dir_tree.get_mut(&self.root).unwrap().remove(&key);
+
+            //nested archive is still an entry of its parent
+            if !self.is_child(&PathU8::from(&key), node_id) {
+                self.paths.release(node_id);
             }
         }
     }
 
-    fn remove_by_id(&mut self, node_id: &NodeId) {
-        let mut ids = std::vec::Vec::new();
+    //whether node_id is listed under parent dir of path
+    fn is_child(&self, path: &PathU8, node_id: NodeId) -> bool {
+        let (parent, name) = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+            (Some(parent), Some(name)) => (parent.to_path_buf(), name),
+            _ => return false,
+        };
 
-        {
-            let entry = self.dir_tree.get(node_id);
+        self.dir_of(&parent)
+            .and_then(|children| children.get(name))
+            .map_or(false, |id| *id == node_id)
+    }
 
-            if entry.is_none() {
+    //ids of nodes under node_id are released, node_id itself is kept
+    //since its parent may still list it
+    fn remove_by_id(&mut self, node_id: NodeId) {
+        let children = match self.dir_tree.remove(&node_id) {
+            Some(children) => children,
+            None => {
                 //this is not a dir object
                 //maybe not loaded dir, or a file node
 
-                self.file_cache.remove(node_id);
+                self.file_cache.remove(&node_id);
                 return;
             }
+        };
 
-            for (_, node_id) in entry.unwrap().iter() {
-                ids.push(node_id.clone());
-            }
+        for (_, id) in children {
+            self.remove_by_id(id);
+            self.paths.release(id);
         }
 
-        for id in ids.iter() {
-            self.remove_by_id(&id.clone());
+        if let Some(cached) = self.archive_cache.pop(&node_id) {
+            self.archive_size -= cached.cost;
+        }
+    }
+
+    //id of virtual path if it is a cached node (or a child of one)
+    pub fn id_of(&self, path: &PathU8) -> Option<NodeId> {
+        self.paths.id_of(path)
+    }
+
+    //virtual path of node, None once node is dropped from cache
+    pub fn path_of(&self, node_id: NodeId) -> Option<&PathU8> {
+        self.paths.path_of(node_id)
+    }
+
+    fn dir_of(&self, path: &PathU8) -> Option<&HashMap<String, NodeId>> {
+        self.dir_tree.get(&self.paths.id_of(path)?)
+    }
+
+    //bytes held by blobs and opened archives
+    pub fn mem_size(&self) -> usize {
+        self.file_cache.size() + self.archive_size
+    }
+
+    //evict until under budget, keep whatever path being served needs
+    fn recycle(&mut self, preserved: &PathU8) {
+        for id in self.touched.get_mut().unwrap().drain(..) {
+            self.file_cache.get(&id);
         }
 
-        self.dir_tree.remove(node_id);
-        self.archive_cache.pop(node_id);
+        let blob_limit = self.mem_limit.saturating_sub(self.archive_size);
+        //root is never a blob, so nothing preserved if path has no id
+        let preserved_id = self.paths.id_of(preserved).unwrap_or(self.root);
+        self.file_cache.recycle(blob_limit, &preserved_id);
+
+        while self.mem_size() > self.mem_limit || self.archive_cache.len() > self.archive_limit {
+            //least recently used first
+            let victim = self
+                .archive_cache
+                .iter()
+                .rev()
+                .map(|(_, cached)| &cached.virtual_path)
+                .find(|virtual_path| !preserved.starts_with(virtual_path))
+                .cloned();
+
+            match victim {
+                None => return,
+                Some(victim) => {
+                    debug!("evict archive {:?}", victim);
+                    self.invalid_path(&victim);
+                }
+            }
+        }
     }
 
-    fn quick_try(&mut self, full_path: &PathU8) -> Option<NodeContents> {
+    //cached dir or file, never touches archive. needs read lock only
+    pub fn quick_try(&self, full_path: &PathU8) -> Option<NodeContents> {
         trace!("lookup in cache by {:?}", full_path);
-        let node_id = path_to_id(full_path);
+        let node_id = match self.paths.id_of(full_path) {
+            Some(node_id) => node_id,
+            None => {
+                trace!("no cache for {:?}", full_path);
+                return None;
+            }
+        };
 
         //dir cache take higher than file
         //because we may save archive
         if let Some(children) = self.dir_tree.get(&node_id) {
             trace!("cache hit dir {:?}", full_path);
-            let ret = Vec::from_iter(children.keys());
-            return Some(NodeContents::Dir(ret));
+            return Some(listing_of(children));
         }
 
-        if let Some(binary) = self.file_cache.get(&node_id) {
+        if let Some(binary) = self.file_cache.peek(&node_id) {
             trace!("cache hit file {:?}", full_path);
-            return Some(NodeContents::File(binary));
+
+            let mut touched = self.touched.lock().unwrap();
+            if touched.len() < TOUCH_BACKLOG {
+                touched.push(node_id);
+            }
+
+            return Some(NodeContents::File(binary.clone()));
         }
 
         trace!("no cache for {:?}", full_path);
         None
     }
 
-    fn recursive_try(&mut self, virtual_path: &PathU8, rel: &PathU8) -> std::io::Result<bool> {
-        trace!("slow try archive_root {:?} , rel {:?}", virtual_path, rel);
+    //decompress path from opened archives, nested archives on the way
+    //are opened and cached too. only the archive being read is locked
+    //while decompressing, so other archives can be served meanwhile
+    pub fn load(cache: &RwLock<ArchiveCache>, path: &PathU8) -> error::Result<NodeContents> {
+        let limits = cache.read().unwrap().limits;
+        ArchiveCache::load_with(cache, path, &mut Budget::new(limits))
+    }
 
-        debug_assert!(
-            !self
-                .dir_tree
-                .contains_key(&path_to_id(&join_may_empty(virtual_path, rel))),
-            "slow try codepath should not be called if {:?} already in dir cache",
-            join_may_empty(&virtual_path, &rel)
-        );
-        debug_assert!(
-            !self
-                .file_cache
-                .contains_key(&path_to_id(&join_may_empty(virtual_path, rel))),
-            "slow try codepath should not be called if {:?} already in file cache",
-            join_may_empty(&virtual_path, &rel)
-        );
+    //load, with bytes already extracted for the same request taken
+    //from budget
+    pub fn load_with(
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        budget: &mut Budget,
+    ) -> error::Result<NodeContents> {
+        //each round opens one nested archive or returns
+        loop {
+            if let Some(hit) = cache.read().unwrap().quick_try(path) {
+                return Ok(hit);
+            }
+
+            let (archive_path, opened) = {
+                let mut lock = cache.write().unwrap();
 
-        let ar_and_entries = self
-            .archive_cache
-            .get_mut(&path_to_id(virtual_path))
-            .unwrap();
-
-        for (partical_try, left_path) in RevPathWalker::new(&rel) {
-            let full_virtual = join_may_empty(virtual_path, &partical_try);
-            if self.dir_tree.contains_key(&path_to_id(&full_virtual)) {
-                {
-                    //parent already is a dir,so we don't need to try more
-                    //this can't be a nested archive (already a dir)
-
-                    Error::new(
-                        ErrorKind::NotFound,
-                        format!("{:?} is already dir, can't be nested archive", full_virtual),
-                    );
+                if let Some(hit) = lock.quick_try(path) {
+                    return Ok(hit);
                 }
+
+                //due to we support nested archive, longest first
+                let archive_path = lock
+                    .archive_root_of(path)
+                    .ok_or_else(|| Error::NotFound(format!("{:?} not found", path)))?;
+
+                let opened = lock
+                    .id_of(&archive_path)
+                    .and_then(|id| lock.archive_cache.get(&id))
+                    .map(|cached| cached.opened.clone())
+                    .ok_or_else(|| {
+                        Error::NotFound(format!("archive {:?} evicted", archive_path))
+                    })?;
+
+                (archive_path, opened)
+            };
+
+            let rel = PathU8::from(path.strip_prefix(&archive_path).unwrap());
+
+            trace!("load archive_root {:?}, rel {:?}", archive_path, rel);
+
+            let mut ar = opened.ar.lock().unwrap();
+
+            //other request may have done it while we wait
+            if let Some(hit) = cache.read().unwrap().quick_try(path) {
+                return Ok(hit);
             }
 
+            let matched = RevPathWalker::new(&rel).find_map(|(partical_try, left_path)| {
+                opened
+                    .entries
+                    .get(&*partical_try.to_string_lossy())
+                    .map(|entry| (partical_try, left_path, entry))
+            });
+
+            let (partical_try, left_path, entry) = matched.ok_or_else(|| {
+                Error::NotFound(format!(
+                    "no entry matched {:?} under {:?}",
+                    rel, archive_path
+                ))
+            })?;
+
             debug!(
                 "under {:?} partical_try {:?}, left_path {:?}",
-                virtual_path, partical_try, left_path
+                archive_path, partical_try, left_path
             );
 
-            let entry = ar_and_entries
-                .1
-                .entry(partical_try.to_str().unwrap().to_string());
-            let mut binary;
-            match entry {
-                std::collections::hash_map::Entry::Vacant(_) => {
-                    continue;
-                }
-                std::collections::hash_map::Entry::Occupied(kv) => {
-                    let ar_entry = kv.get();
-
-                    let mut reader = ar_and_entries.0.reader_for(ar_entry)?;
-
-                    binary = Vec::with_capacity(ar_entry.size());
+            let binary = opened.read(&mut ar, entry, budget)?;
 
-                    if let Err(e) = reader.read_to_end(&mut binary) {
-                        return Err(e);
-                    }
-                }
-            }
+            let full_virtual = join_may_empty(&archive_path, &partical_try);
 
             //hit a entry in existing archive
-            let is_archive = is_archive(&partical_try, FileOrMem::Mem(&binary));
+            if !is_archive(&partical_try, FileOrMem::Mem(&binary)) {
+                if !left_path.as_os_str().is_empty() {
+                    return Err(Error::NotFound(format!(
+                        "{:?} is not an archive",
+                        partical_try
+                    )));
+                }
 
-            if !is_archive {
-                if left_path.to_str().unwrap().is_empty() {
-                    //normal file ,load into cache
-                    self.file_cache.put(
-                        &path_to_id(&join_may_empty(virtual_path, &partical_try)),
-                        binary,
-                    );
+                let mut lock = cache.write().unwrap();
 
-                    return Ok(true);
+                if !lock.is_opened(&archive_path) {
+                    //evicted while reading, invalid_path could not reach
+                    //the blob if cached now
+                    return Ok(NodeContents::File(Arc::new(binary)));
                 }
 
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    partical_try.to_str().unwrap().to_owned() + "can not be decoded as archive",
-                ));
+                //normal file ,load into cache
+                return Ok(NodeContents::File(lock.put_file(&full_virtual, binary)));
             }
 
             // this is archive ,look into it
+            budget.limits().check_depth(&entry.name, opened.depth + 1)?;
 
-            let ar = ArArchive::new(ArStream::from_memory(binary), None);
-
-            if ar.is_err() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    partical_try.to_str().unwrap().to_owned() + "can not be decoded as archive",
-                ));
-            }
+            //nested tarball is unpacked as a whole, counted against budget
+            let binary = match tarball::compression_of(&binary) {
+                Some(compression) => {
+                    tarball::unpack(compression, &binary[..], binary.len(), &entry.name, budget)?
+                }
+                None => binary,
+            };
+
+            let in_memory = binary.len();
+            let central = central_in(Cursor::new(&binary));
+            let nested = ArArchive::new(ArStream::from_memory(binary), None).map_err(|e| {
+                Error::Unsupported(format!(
+                    "{:?} can not be decoded as archive: {}",
+                    partical_try, e
+                ))
+            })?;
 
-            let nested_path = join_may_empty(virtual_path, &partical_try);
             trace!(
                 "set nested archive from {:?} as {:?} as archive",
-                virtual_path,
-                nested_path
+                archive_path,
+                full_virtual
             );
 
-            // have left path, but partical_path is not archive
-            self.set_archive_internal(&nested_path, ar.unwrap(), true);
+            let charset = cache.read().unwrap().charset;
+            let mut nested = OpenedArchive::new(nested, &central, charset, in_memory);
+            nested.depth = opened.depth + 1;
 
-            if left_path.to_str().unwrap().is_empty() {
-                return Ok(true);
-            }
+            let listing = cache
+                .write()
+                .unwrap()
+                .set_archive_internal(&full_virtual, nested);
 
-            if self.quick_try(&join_may_empty(virtual_path, rel)).is_some() {
-                return Ok(true);
+            if left_path.as_os_str().is_empty() {
+                return Ok(listing);
             }
-
-            return self.recursive_try(&nested_path, &left_path);
         }
-
-        //all path tried, but no matched archive
-
-        Err(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "no entry matched ".to_owned()
-                + rel.to_str().unwrap()
-                + " under "
-                + virtual_path.to_str().unwrap(),
-        ))
     }
 
-    pub fn get(&mut self, path: &PathU8) -> std::io::Result<NodeContents> {
-        let quick_tried = self.quick_try(path);
+    //virtual path of opened archive which holds path, nested one first
+    fn archive_root_of(&self, path: &PathU8) -> Option<PathU8> {
+        let mut longest_match: Option<&String> = None;
+
+        for virtual_path in self.dir_tree.get(&self.root).unwrap().keys() {
+            trace!("key is {:?}", virtual_path);
+            if !path.starts_with(virtual_path) {
+                continue;
+            }
 
-        if let Some(hit) = quick_tried {
-            return Ok(hit);
+            if longest_match.map_or(true, |m| m.len() < virtual_path.len()) {
+                longest_match = Some(virtual_path);
+            }
         }
 
-        self.slow_try(path)
+        longest_match.map(PathU8::from)
     }
 
-    pub fn slow_try(&mut self, path: &PathU8) -> std::io::Result<NodeContents> {
-        trace!("slow try {:?}", path);
+    //children of a dir inside archive, None if path is not a loaded dir
+    pub fn describe_dir(&mut self, path: &PathU8) -> Option<Vec<EntryInfo>> {
+        let children: Vec<(String, NodeId)> = self
+            .dir_of(path)?
+            .iter()
+            .map(|(name, id)| (name.clone(), *id))
+            .collect();
 
-        {
-            //should quick_try() first, and expose this function only
-            //but rust has bug that early return still extends lifetime
-            //to function scope so we expose quick_try too
-            debug_assert!(
-                !self.dir_tree.contains_key(&path_to_id(path))
-                    && !self.file_cache.contains_key(&path_to_id(path)),
-                "{:?} already in cache before get, caller should check quick_try result first",
-                path
-            );
-        }
+        let archive_root = self.archive_root_of(path);
 
-        //try to find in archive
-        //first we need to find matched archive root
-        //due to we support nested archive, we should longest first
+        let mut ret = Vec::with_capacity(children.len());
 
-        let virtual_root_id = path_to_id(&PathU8::from(VIRTUAL_ROOT_PATH));
+        for (name, id) in children {
+            let name_path = PathU8::from(&name);
 
-        let mut longest_match: Option<PathU8> = None;
+            let kind = if self.archive_cache.contains(&id)
+                || is_archive(&name_path, FileOrMem::Mem(&[]))
+            {
+                EntryKind::Archive
+            } else if self.dir_tree.contains_key(&id) {
+                EntryKind::Dir
+            } else {
+                EntryKind::File
+            };
 
-        let workaround_rust_nll = self.dir_tree.clone();
+            let mut info = EntryInfo::new(&name, kind);
 
-        for virtual_path in workaround_rust_nll.get(&virtual_root_id).unwrap().keys() {
-            trace!("key is {:?}", virtual_path);
-            if !path.starts_with(virtual_path) {
+            if kind == EntryKind::Dir {
+                ret.push(info);
                 continue;
             }
 
-            if longest_match.is_none() {
-                longest_match = Some(PathU8::from(virtual_path));
-                continue;
+            if let Some(root) = archive_root.as_ref() {
+                let child = join_may_empty(path, &name_path);
+                let rel = child.strip_prefix(root).ok().map(PathU8::from);
+                info.size = rel
+                    .and_then(|rel| self.entry_size(root, &rel))
+                    .map(|size| size as u64);
             }
 
-            if longest_match.as_ref().unwrap().to_str().unwrap().len() < virtual_path.len() {
-                longest_match = Some(PathU8::from(virtual_path));
+            if let Some(bin) = self.file_cache.get(&id) {
+                info.size = Some(bin.len() as u64);
             }
-        }
+            info.mime = mime_from_name(&name).map(String::from);
 
-        if longest_match.is_none() {
-            return Err(Error::new(
-                ErrorKind::NotFound,
-                path.to_str().unwrap().to_owned() + "not found",
-            ));
+            ret.push(info);
         }
 
-        //longest path first
-
-        let matched = longest_match.unwrap();
-        trace!("longest_match {:?}", matched);
-        let rel = path.strip_prefix(matched.clone()).unwrap();
-
-        self.recursive_try(&matched, &PathU8::from(rel))?;
-
-        match self.quick_try(path) {
-            Some(result) => Ok(result),
-            None => Err(Error::new(ErrorKind::NotFound, "")),
-        }
+        Some(ret)
     }
 
-    fn grow_under(&mut self, this_root: &PathU8, path: &PathU8) {
+    //bytes of added nodes returned
+    fn grow_under(&mut self, this_root: &PathU8, path: &PathU8) -> usize {
         trace!("grow under {:?} {:?}", this_root, path);
 
         let mut parent = this_root.clone();
+        let mut added = 0;
 
         for comp in path.iter() {
             let full_path = parent.join(comp);
 
-            let parent_id = path_to_id(&parent);
+            let parent_id = self.paths.intern(&parent);
 
             let entry = self.dir_tree.entry(parent_id).or_insert_with(HashMap::new);
 
@@ -609,89 +922,250 @@ impl ArchiveCache {
                 continue;
             }
 
-            let node_id = path_to_id(&parent);
+            let node_id = self.paths.intern(&parent);
 
+            added += utf8.len() + NODE_COST;
             entry.insert(utf8, node_id);
         }
+
+        added
     }
 
     fn set_archive_internal(
         &mut self,
         virtual_path: &PathU8,
-        ar: ArArchive,
-        is_nested: bool,
+        opened: OpenedArchive,
     ) -> NodeContents {
-        let virtual_root_path = &PathU8::from(VIRTUAL_ROOT_PATH);
-        let virtual_root_id: NodeId = path_to_id(virtual_root_path);
-
         debug_assert!(
-            virtual_path != virtual_root_path,
+            virtual_path != &PathU8::from(VIRTUAL_ROOT_PATH),
             "archive virtual path can not be same to virtual root {}",
             VIRTUAL_ROOT_PATH
         );
 
-        let vroot_children = self.dir_tree.get_mut(&virtual_root_id).unwrap();
-
-        debug_assert!(!vroot_children.contains_key(virtual_path.to_str().unwrap()));
+        let node_id = self.paths.intern(virtual_path);
 
-        vroot_children.insert(
-            virtual_path.to_str().unwrap().to_owned(),
-            path_to_id(virtual_path),
-        );
+        if let Some(children) = self.dir_tree.get(&node_id) {
+            //opened by other request while we were reading
+            return listing_of(children);
+        }
 
-        let mut entries = HashMap::new();
+        let mut cost = opened.held() + opened.entries_cost();
 
-        for f in ar.iter() {
-            self.grow_under(virtual_path, &PathU8::from(f.name()));
-            entries.insert(f.name().to_owned(), f);
+        for name in opened.entries.keys() {
+            cost += self.grow_under(virtual_path, &PathU8::from(name));
         }
 
-        self.archive_cache
-            .put(path_to_id(virtual_path), (ar, entries, is_nested));
+        //archive without entries is still an (empty) dir
+        self.dir_tree.entry(node_id).or_insert_with(HashMap::new);
 
-        self.dir_tree.get_mut(&virtual_root_id).unwrap().insert(
-            virtual_path.clone().to_str().unwrap().to_owned(),
-            path_to_id(&virtual_path),
-        );
+        trace!("archive {:?} costs {} bytes", virtual_path, cost);
 
-        let ret = Vec::from_iter(
-            self.dir_tree
-                .get(&path_to_id(&virtual_path))
-                .unwrap()
-                .keys(),
+        self.archive_cache.put(
+            node_id,
+            CachedArchive {
+                opened: Arc::new(opened),
+                virtual_path: virtual_path.clone(),
+                cost,
+            },
         );
-        NodeContents::Dir(ret)
+        self.archive_size += cost;
+
+        self.dir_tree
+            .get_mut(&self.root)
+            .unwrap()
+            .insert(virtual_path.to_str().unwrap().to_owned(), node_id);
+
+        let ret = listing_of(self.dir_tree.get(&node_id).unwrap());
+
+        self.recycle(virtual_path);
+
+        ret
     }
 
-    pub fn set_archive(
-        &mut self,
-        virtual_path: &PathU8,
-        archive_path: &PathU8,
-    ) -> std::io::Result<NodeContents> {
-        let node_id = path_to_id(virtual_path);
+    fn put_file(&mut self, path: &PathU8, binary: Binary) -> Arc<Binary> {
+        trace!("set file cache {:?}", path);
+
+        let ret = Arc::new(binary);
+        let node_id = self.paths.intern(path);
+        self.file_cache.put(node_id, ret.clone(), ret.len());
+        self.recycle(path);
+        ret
+    }
+
+    //entry directly under opened archive
+    pub fn entry_of(&self, virtual_path: &PathU8, name: &PathU8) -> Option<IndexedEntry> {
+        let name = name.to_str()?;
+        self.id_of(virtual_path)
+            .and_then(|id| self.archive_cache.peek(&id))
+            .and_then(|cached| cached.opened.entries.get(name))
+            .cloned()
+    }
+
+    //crc of archive entry at path, None if it is not a cached zip entry
+    pub fn crc_of(&self, path: &PathU8) -> Option<u32> {
+        let root = self.archive_root_of(path)?;
+        let name = PathU8::from(path.strip_prefix(&root).ok()?);
+        self.entry_of(&root, &name)?.crc
+    }
+
+    //declared size of entry directly under opened archive
+    pub fn entry_size(&self, virtual_path: &PathU8, name: &PathU8) -> Option<usize> {
+        self.entry_of(virtual_path, name).map(|entry| entry.size)
+    }
+
+    //files after path in its dir by natural order, with declared size.
+    //only uncached entries directly under an opened archive, nested
+    //archives are skipped
+    pub fn following_entries(&self, path: &PathU8, count: usize) -> Vec<(PathU8, usize)> {
+        let (parent, name) = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+            (Some(parent), Some(name)) => (parent.to_path_buf(), name),
+            _ => return Vec::new(),
+        };
+
+        let root = match self.archive_root_of(&parent) {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+
+        let children = match self.dir_of(&parent) {
+            Some(children) => children,
+            None => return Vec::new(),
+        };
+
+        sorted_names(children.keys())
+            .into_iter()
+            .skip_while(|child| child.as_str() != name)
+            .skip(1)
+            .map(|child| parent.join(child))
+            .filter(|child| {
+                self.id_of(child).map_or(true, |id| {
+                    !self.dir_tree.contains_key(&id) && !self.file_cache.contains_key(&id)
+                })
+            })
+            .filter(|child| !is_archive(child, FileOrMem::Mem(&[])))
+            .filter_map(|child| {
+                let rel = PathU8::from(child.strip_prefix(&root).ok()?);
+                let size = self.entry_size(&root, &rel)?;
+                Some((child, size))
+            })
+            .take(count)
+            .collect()
+    }
 
-        debug_assert!(!self.file_cache.contains_key(&node_id));
+    pub fn mem_limit(&self) -> usize {
+        self.mem_limit
+    }
 
+    //open archive file as virtual_path. file is read without cache lock
+    pub fn open_archive(
+        cache: &RwLock<ArchiveCache>,
+        virtual_path: &PathU8,
+        archive_path: &PathU8,
+    ) -> error::Result<NodeContents> {
         //all virtual path must be relative to 'virtual root'
         debug_assert!(virtual_path.is_relative());
 
-        if self.dir_tree.contains_key(&node_id) {
-            //already read. ignore
-            let ret = Vec::from_iter(
-                self.dir_tree
-                    .get(&path_to_id(&virtual_path))
-                    .unwrap()
-                    .keys(),
-            );
-            return Ok(NodeContents::Dir(ret));
+        let (index, charset, spool, limits, mem_limit) = {
+            let lock = cache.read().unwrap();
+
+            if let Some(children) = lock.dir_of(virtual_path) {
+                //already read. ignore
+                return Ok(listing_of(children));
+            }
+
+            (
+                lock.index.clone(),
+                lock.charset,
+                lock.spool.clone(),
+                lock.limits,
+                lock.mem_limit,
+            )
+        };
+
+        let opened = OpenedArchive::open(
+            archive_path,
+            index.as_ref().map(|i| &**i),
+            charset,
+            spool.as_ref().map(|s| &**s),
+            &limits,
+            mem_limit,
+        )?;
+
+        trace!("added archive {:?} as {:?}", archive_path, virtual_path);
+        Ok(cache
+            .write()
+            .unwrap()
+            .set_archive_internal(virtual_path, opened))
+    }
+
+    //load left inside archive file, reopen it if evicted by other
+    //requests meanwhile
+    pub fn load_in(
+        cache: &RwLock<ArchiveCache>,
+        virtual_path: &PathU8,
+        archive_path: &PathU8,
+        left: &PathU8,
+    ) -> error::Result<NodeContents> {
+        let path = join_may_empty(virtual_path, left);
+
+        //retries are still one request
+        let limits = cache.read().unwrap().limits;
+        let mut budget = Budget::new(limits);
+
+        for _ in 0..LOAD_RETRY {
+            ArchiveCache::open_archive(cache, virtual_path, archive_path)?;
+            let on_disk = cache.read().unwrap().file_of(virtual_path).is_some();
+
+            match ArchiveCache::load_with(cache, &path, &mut budget) {
+                Err(ref e) if !cache.read().unwrap().is_opened(virtual_path) => {
+                    debug!("{:?} evicted while loading {:?}: {}", virtual_path, path, e);
+
+                    //tarball unpacked in memory would be unpacked whole
+                    //again, only to be evicted the same way
+                    if !on_disk {
+                        break;
+                    }
+                }
+                res => return res,
+            }
         }
 
-        debug!("try to open {:?} as archive", archive_path);
+        Err(Error::Unavailable(format!(
+            "{:?} keeps being evicted, mem_limit too small?",
+            virtual_path
+        )))
+    }
+
+    pub fn is_opened(&self, virtual_path: &PathU8) -> bool {
+        self.id_of(virtual_path)
+            .map_or(false, |id| self.archive_cache.contains(&id))
+    }
 
-        let ar = ArArchive::new(ArStream::from_file(archive_path)?, None)?;
+    pub fn set_archive(
+        &mut self,
+        virtual_path: &PathU8,
+        archive_path: &PathU8,
+    ) -> error::Result<NodeContents> {
+        debug_assert!(self
+            .id_of(virtual_path)
+            .map_or(true, |id| !self.file_cache.contains_key(&id)));
+
+        if let Some(children) = self.dir_of(virtual_path) {
+            //already read. ignore
+            return Ok(listing_of(children));
+        }
+
+        let opened = OpenedArchive::open(
+            archive_path,
+            self.index.as_ref().map(|i| &**i),
+            self.charset,
+            self.spool.as_ref().map(|s| &**s),
+            &self.limits,
+            self.mem_limit,
+        )?;
 
         trace!("added archive {:?} as {:?}", archive_path, virtual_path);
-        Ok(self.set_archive_internal(virtual_path, ar, false))
+        Ok(self.set_archive_internal(virtual_path, opened))
     }
 }
 
@@ -702,7 +1176,7 @@ mod tests {
 
     #[test]
     fn test_only_archive_under_root() {
-        simple_logger::init();
+        let _ = simple_logger::init();
 
         let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
 
@@ -718,16 +1192,14 @@ mod tests {
             .set_archive(&PathU8::from(virtual_path), &d.clone())
             .is_ok());
 
-        let vroot_id = path_to_id(&PathU8::from(VIRTUAL_ROOT_PATH));
-
-        let children = ac.dir_tree.get(&vroot_id).unwrap();
+        let children = ac.dir_tree.get(&ac.root).unwrap();
 
         assert_eq!(children.len(), 1);
     }
 
     #[test]
     fn test_nested() {
-        simple_logger::init();
+        let _ = simple_logger::init();
 
         let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
 
@@ -747,24 +1219,172 @@ mod tests {
                 assert!(false);
             }
             NodeContents::Dir(dir) => {
-                assert!(dir.contains(&&("test.zip".to_owned())));
+                assert!(dir.contains(&("test.zip".to_owned())));
             }
         }
 
-        ac.recursive_try(&PathU8::from(virtual_path), &PathU8::from("test.zip"))
-            .unwrap();
+        let ac = RwLock::new(ac);
 
-        ac.recursive_try(
-            &PathU8::from(virtual_path).join("test.zip"),
-            &PathU8::from("under_root"),
-        )
-        .unwrap();
+        ArchiveCache::load(&ac, &PathU8::from(virtual_path).join("test.zip")).unwrap();
+
+        ArchiveCache::load(&ac, &PathU8::from(virtual_path).join("test.zip/under_root")).unwrap();
         assert!(ac
-            .get(&PathU8::from(virtual_path).join(PathU8::from("test.zip")))
-            .is_ok());
+            .read()
+            .unwrap()
+            .quick_try(&PathU8::from(virtual_path).join(PathU8::from("test.zip")))
+            .is_some());
         assert!(ac
-            .get(&PathU8::from(virtual_path).join(PathU8::from("test.zip/under_root")))
-            .is_ok());
+            .read()
+            .unwrap()
+            .quick_try(&PathU8::from(virtual_path).join(PathU8::from("test.zip/under_root")))
+            .is_some());
+    }
+
+    #[test]
+    fn test_concurrent_load() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("tests/nested.zip");
+
+        let cache = Arc::new(RwLock::new(ArchiveCache::new(1000, 10)));
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let cache = cache.clone();
+                let d = d.clone();
+                std::thread::spawn(move || {
+                    let virtual_path = PathU8::from(if i % 2 == 0 { "a" } else { "b" });
+                    ArchiveCache::load_in(
+                        &cache,
+                        &virtual_path,
+                        &d,
+                        &PathU8::from("test.zip/under_root"),
+                    )
+                    .map(|_| ())
+                })
+            })
+            .collect();
+
+        //budget is too small for both. racing threads may keep evicting
+        //each other, which is only reported as busy
+        for t in threads {
+            match t.join().unwrap() {
+                Ok(()) | Err(Error::Unavailable(_)) => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_consistent(&cache.read().unwrap());
+
+        //alone it is served
+        ArchiveCache::load_in(
+            &cache,
+            &PathU8::from("a"),
+            &d,
+            &PathU8::from("test.zip/under_root"),
+        )
+        .unwrap();
+        assert_consistent(&cache.read().unwrap());
+    }
+
+    fn assert_consistent(ac: &ArchiveCache) {
+        let vroot = ac.dir_tree.get(&ac.root).unwrap();
+
+        assert_eq!(vroot.len(), ac.archive_cache.len());
+
+        for (key, id) in vroot.iter() {
+            assert!(ac.dir_tree.contains_key(id), "{} has no dir tree", key);
+            assert!(ac.archive_cache.contains(id), "{} not opened", key);
+        }
+
+        let cost: usize = ac.archive_cache.iter().map(|(_, c)| c.cost).sum();
+        assert_eq!(cost, ac.archive_size);
+
+        //every id in tree resolves to its path, no id is leaked
+        let mut ids: Vec<NodeId> = ac.dir_tree.keys().cloned().collect();
+        for children in ac.dir_tree.values() {
+            ids.extend(children.values().cloned());
+        }
+        ids.sort();
+        ids.dedup();
+
+        for id in ids.iter() {
+            let path = ac.path_of(*id).unwrap();
+            assert_eq!(ac.id_of(path), Some(*id));
+        }
+        assert_eq!(ac.paths.len(), ids.len());
+    }
+
+    #[test]
+    fn test_node_id() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("tests/nested.zip");
+
+        let cache = RwLock::new(ArchiveCache::new(1 << 20, 10));
+        let file = PathU8::from("a/test.zip/dir/under_dir");
+
+        ArchiveCache::open_archive(&cache, &PathU8::from("a"), &d).unwrap();
+        ArchiveCache::load(&cache, &file).unwrap();
+
+        let mut lock = cache.write().unwrap();
+        let id = lock.id_of(&file).unwrap();
+        assert_eq!(lock.path_of(id), Some(&file));
+        assert_ne!(lock.id_of(&PathU8::from("a/test.zip/dir")), Some(id));
+        assert!(lock.id_of(&PathU8::from("a/test.zip/not_exists")).is_none());
+
+        //nested archive goes, its parent still lists it
+        lock.invalid_path(&PathU8::from("a/test.zip"));
+        assert_consistent(&lock);
+        assert!(lock.path_of(id).is_none());
+        assert!(lock.id_of(&PathU8::from("a/test.zip")).is_some());
+
+        lock.invalid_path(&PathU8::from(""));
+        assert_consistent(&lock);
+        assert_eq!(lock.paths.len(), 1);
+    }
+
+    #[test]
+    fn test_mem_budget() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("tests/nested.zip");
+
+        //count limit, older archive goes with its nested ones
+        let cache = RwLock::new(ArchiveCache::new(1 << 20, 2));
+
+        ArchiveCache::open_archive(&cache, &PathU8::from("a"), &d).unwrap();
+        ArchiveCache::load(&cache, &PathU8::from("a/test.zip/under_root")).unwrap();
+        assert_eq!(cache.read().unwrap().archive_cache.len(), 2);
+
+        ArchiveCache::open_archive(&cache, &PathU8::from("b"), &d).unwrap();
+        {
+            let lock = cache.read().unwrap();
+            assert_consistent(&lock);
+            assert!(lock.quick_try(&PathU8::from("a")).is_none());
+            assert!(lock
+                .quick_try(&PathU8::from("a/test.zip/under_root"))
+                .is_none());
+            assert!(lock.quick_try(&PathU8::from("b")).is_some());
+        }
+
+        //byte limit, only room for what is being served
+        let cache = RwLock::new(ArchiveCache::new(1, 10));
+
+        for name in ["a", "b", "c"].iter() {
+            let file = PathU8::from(name).join("test.zip/dir/under_dir");
+            ArchiveCache::open_archive(&cache, &PathU8::from(name), &d).unwrap();
+            ArchiveCache::load(&cache, &file).unwrap();
+
+            let lock = cache.read().unwrap();
+            assert_consistent(&lock);
+            assert!(lock.quick_try(&file).is_some());
+            //outer and nested archive of current file
+            assert_eq!(lock.archive_cache.len(), 2);
+            assert_eq!(lock.file_cache.len(), 1);
+            assert_eq!(lock.file_cache.size(), 9);
+        }
+
+        let mut lock = cache.write().unwrap();
+        lock.invalid_path(&PathU8::from(""));
+        assert_consistent(&lock);
+        assert_eq!(lock.mem_size(), 0);
     }
 
     #[test]
@@ -781,6 +1401,23 @@ mod tests {
             println!("iter key {}, v {}", i, v);
         }
     }
+    #[test]
+    fn test_read_hit_counts() {
+        let cache = RwLock::new(ArchiveCache::new(20, 10));
+        let (a, b, c) = (PathU8::from("a"), PathU8::from("b"), PathU8::from("c"));
+
+        cache.write().unwrap().put_file(&a, vec![0; 8]);
+        cache.write().unwrap().put_file(&b, vec![0; 8]);
+
+        //hit under read lock still makes a most recently used
+        assert!(cache.read().unwrap().quick_try(&a).is_some());
+
+        let mut lock = cache.write().unwrap();
+        lock.put_file(&c, vec![0; 8]);
+        assert!(lock.quick_try(&a).is_some());
+        assert!(lock.quick_try(&b).is_none());
+    }
+
     #[test]
     fn test_rev_path_walk() {
         let walker = RevPathWalker::new(&PathU8::from(""));
@@ -810,9 +1447,37 @@ mod tests {
         assert_eq!(counter, 2);
     }
 
+    #[test]
+    fn test_index() {
+        let d = PathU8::from(env!("CARGO_MANIFEST_DIR")).join("tests/test.zip");
+        let dir = std::env::temp_dir().join(format!("cache-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let index = Arc::new(ArchiveIndex::open(&dir).unwrap());
+        let file = PathU8::from("test.zip/dir/under_dir");
+
+        //second round reads entries from index
+        for _ in 0..2 {
+            let cache = RwLock::new(ArchiveCache::new(1000, 10).with_index(index.clone()));
+            ArchiveCache::open_archive(&cache, &PathU8::from("test.zip"), &d).unwrap();
+
+            match ArchiveCache::load(&cache, &file).unwrap() {
+                NodeContents::File(bin) => assert_eq!(bin.len(), 9),
+                NodeContents::Dir(_) => panic!("{:?} is file", file),
+            }
+
+            assert_consistent(&cache.read().unwrap());
+        }
+
+        let entries = index.get(&d, &Stamp::of(&d).unwrap(), "detect").unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn test_read() {
-        simple_logger::init();
+        let _ = simple_logger::init();
 
         let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
 
@@ -830,37 +1495,169 @@ mod tests {
                     assert!(false);
                 }
                 NodeContents::Dir(dir) => {
-                    assert!(dir.contains(&&("dir".to_owned())));
-                    assert!(dir.contains(&&("under_root".to_owned())));
+                    assert!(dir.contains(&("dir".to_owned())));
+                    assert!(dir.contains(&("under_root".to_owned())));
                 }
             }
 
-            match tree.get(&PathU8::from(p)).unwrap() {
+            let tree = RwLock::new(tree);
+
+            match ArchiveCache::load(&tree, &PathU8::from(p)).unwrap() {
                 NodeContents::File(_) => {
                     assert!(false);
                 }
                 NodeContents::Dir(dir) => {
-                    assert!(dir.contains(&&("dir".to_owned())));
-                    assert!(dir.contains(&&("under_root".to_owned())));
+                    assert!(dir.contains(&("dir".to_owned())));
+                    assert!(dir.contains(&("under_root".to_owned())));
                 }
             }
 
-            tree.get(&PathU8::from(p).join(PathU8::from("under_root")))
-                .unwrap();
+            ArchiveCache::load(&tree, &PathU8::from(p).join(PathU8::from("under_root"))).unwrap();
 
-            tree.get(&PathU8::from(p).join(PathU8::from("dir/under_dir")))
+            ArchiveCache::load(&tree, &PathU8::from(p).join(PathU8::from("dir/under_dir")))
                 .unwrap();
 
-            assert!(tree
-                .get(&PathU8::from(p).join(PathU8::from("not_exists")))
-                .is_err());
-            assert!(tree
-                .get(&PathU8::from(p).join(PathU8::from("dir/not_exists")))
-                .is_err());
-            assert!(tree
-                .get(&PathU8::from(p).join(PathU8::from("no_exists_dir/not_exists")))
-                .is_err());
+            assert!(
+                ArchiveCache::load(&tree, &PathU8::from(p).join(PathU8::from("not_exists")))
+                    .is_err()
+            );
+            assert!(ArchiveCache::load(
+                &tree,
+                &PathU8::from(p).join(PathU8::from("dir/not_exists"))
+            )
+            .is_err());
+            assert!(ArchiveCache::load(
+                &tree,
+                &PathU8::from(p).join(PathU8::from("no_exists_dir/not_exists"))
+            )
+            .is_err());
         }
     }
 
+    #[test]
+    fn test_limits() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+
+        let too_large = |limits: Limits, archive: &str, left: &str| {
+            let cache = RwLock::new(ArchiveCache::new(4 * 1024 * 1024, 10).with_limits(limits));
+            match ArchiveCache::load_in(
+                &cache,
+                &PathU8::from("a"),
+                &d.join(archive),
+                &PathU8::from(left),
+            ) {
+                Err(Error::TooLarge(_)) => true,
+                Err(e) => panic!("{}", e),
+                Ok(_) => false,
+            }
+        };
+
+        //nested.zip holds test.zip
+        let flat = Limits {
+            max_depth: 0,
+            ..Limits::default()
+        };
+        assert!(too_large(flat, "tests/nested.zip", "test.zip/under_root"));
+        assert!(!too_large(
+            Limits::default(),
+            "tests/nested.zip",
+            "test.zip/under_root"
+        ));
+
+        //1M of zeros in about 1K
+        let loose = Limits {
+            max_ratio: 1000,
+            ..Limits::default()
+        };
+        assert!(!too_large(loose, "tests/bomb.zip", "zeros.txt"));
+        assert!(too_large(Limits::default(), "tests/bomb.zip", "zeros.txt"));
+
+        let small_entry = Limits {
+            max_entry_size: 1024 * 1024 - 1,
+            ..loose
+        };
+        assert!(too_large(small_entry, "tests/bomb.zip", "zeros.txt"));
+
+        let small_request = Limits {
+            max_extract: 4096,
+            ..loose
+        };
+        assert!(too_large(small_request, "tests/bomb.zip", "zeros.txt"));
+    }
+
+    #[test]
+    fn test_tarball() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+
+        let page =
+            |cache: &RwLock<ArchiveCache>, archive: &str, left: &str| match ArchiveCache::load_in(
+                cache,
+                &PathU8::from("a"),
+                &d.join(archive),
+                &PathU8::from(left),
+            )
+            .unwrap()
+            {
+                NodeContents::File(bin) => bin,
+                NodeContents::Dir(dir) => panic!("{:?}", dir),
+            };
+
+        let spool_dir =
+            std::env::temp_dir().join(format!("archive-cache-tarball-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&spool_dir);
+        let spool = Arc::new(Spool::open(&spool_dir, 1024 * 1024).unwrap());
+
+        for name in &[
+            "tarball/book.cbt",
+            "tarball/book.tar.gz",
+            "tarball/book.tar.bz2",
+            "tarball/book.tar.xz",
+            "tarball/book.tar.zst",
+        ] {
+            assert!(is_archive(
+                &PathU8::from(name),
+                FileOrMem::Path(&d.join(name))
+            ));
+
+            let in_memory = RwLock::new(ArchiveCache::new(1024 * 1024, 10));
+            let spooled = RwLock::new(ArchiveCache::new(1024 * 1024, 10).with_spool(spool.clone()));
+
+            for cache in &[&in_memory, &spooled] {
+                assert_eq!(&**page(cache, name, "cover.jpg"), b"cover image");
+                assert_eq!(page(cache, name, "pages/01.jpg").len(), 9 * 50);
+            }
+
+            //plain tar is read in place, compressed one from spool only
+            let file = spooled
+                .read()
+                .unwrap()
+                .file_of(&PathU8::from("a"))
+                .unwrap()
+                .path;
+            if name.ends_with(".cbt") {
+                assert_eq!(file, d.join(name));
+                assert!(in_memory
+                    .read()
+                    .unwrap()
+                    .file_of(&PathU8::from("a"))
+                    .is_some());
+            } else {
+                assert!(file.starts_with(&spool_dir));
+                assert!(in_memory
+                    .read()
+                    .unwrap()
+                    .file_of(&PathU8::from("a"))
+                    .is_none());
+            }
+        }
+
+        //tarball.zip stores book.tgz
+        let cache = RwLock::new(ArchiveCache::new(1024 * 1024, 10));
+        assert_eq!(
+            &**page(&cache, "tarball.zip", "book.tgz/cover.jpg"),
+            b"cover image"
+        );
+
+        let _ = std::fs::remove_dir_all(&spool_dir);
+    }
 }
@@ -0,0 +1,437 @@
+extern crate rusqlite;
+extern crate serde_json;
+
+use super::cache::PathU8;
+use super::listing::EntryKind;
+use super::store::{db_error, decode};
+
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_SEARCH_LIMIT: usize = 100;
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS items (
+        path TEXT PRIMARY KEY NOT NULL,
+        parent TEXT NOT NULL,
+        name TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        pages INTEGER,
+        size INTEGER,
+        mtime INTEGER,
+        added INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS items_parent ON items (parent);
+    CREATE INDEX IF NOT EXISTS items_added ON items (added);
+";
+
+//folder or archive known to catalogue. paths are virtual paths,
+//times are seconds since unix epoch
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Item {
+    pub path: String,
+    pub name: String,
+    pub kind: EntryKind,
+    //images inside archive, at any depth
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pages: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    //when scanner first saw it, kept across updates
+    pub added: u64,
+}
+
+fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::Dir => "dir",
+        EntryKind::Archive => "archive",
+        EntryKind::File => "file",
+    }
+}
+
+fn kind_of(name: &str) -> EntryKind {
+    match name {
+        "dir" => EntryKind::Dir,
+        "archive" => EntryKind::Archive,
+        _ => EntryKind::File,
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+//parent of virtual path, empty for top level
+fn parent_of(path: &str) -> String {
+    PathU8::from(path)
+        .parent()
+        .and_then(|p| p.to_str())
+        .unwrap_or("")
+        .to_owned()
+}
+
+//sql condition of path being under ?n, which must not be empty
+fn under(n: usize) -> String {
+    format!("substr(path, 1, length(?{0}) + 1) = ?{0} || '/'", n)
+}
+
+//days since epoch of civil date, see howardhinnant.github.io/date_algorithms
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+//unix seconds, or YYYY-MM-DD as its midnight in utc
+fn parse_time(key: &str, v: &str) -> Result<u64, String> {
+    if let Ok(secs) = v.parse::<u64>() {
+        return Ok(secs);
+    }
+
+    let invalid = || format!("{} should be YYYY-MM-DD or unix seconds, got {:?}", key, v);
+
+    let parts: Vec<i64> = v
+        .split('-')
+        .map(|p| p.parse::<i64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+
+    match parts.as_slice() {
+        [y, m, d] if *y >= 1970 && (1..=12).contains(m) && (1..=31).contains(d) => {
+            Ok(days_from_civil(*y, *m, *d) as u64 * 24 * 3600)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+//what search endpoint asked for
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    //substring of name, case insensitive for ascii. empty matches all
+    pub name: String,
+    //only items under it, empty for whole library
+    pub folder: String,
+    //added in [since, until)
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: usize,
+}
+
+impl Query {
+    //search, since, until and limit query keys, folder is request path
+    pub fn parse(folder: &PathU8, query: Option<&str>) -> Result<Query, String> {
+        let mut ret = Query {
+            name: String::new(),
+            folder: folder.to_str().unwrap_or("").to_owned(),
+            since: None,
+            until: None,
+            limit: DEFAULT_SEARCH_LIMIT,
+        };
+
+        for pair in query.unwrap_or("").split('&') {
+            let mut kv = pair.splitn(2, '=');
+            let (k, v) = (kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+
+            match k {
+                "search" => ret.name = decode(v)?,
+                "since" => ret.since = Some(parse_time(k, &decode(v)?)?),
+                "until" => ret.until = Some(parse_time(k, &decode(v)?)?),
+                "limit" => {
+                    let limit = v
+                        .parse::<usize>()
+                        .map_err(|_| format!("limit should be number, got {:?}", v))?;
+                    ret.limit = std::cmp::min(limit, MAX_SEARCH_LIMIT);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+pub fn to_json(items: &[Item]) -> Vec<u8> {
+    serde_json::to_vec(items).unwrap()
+}
+
+//folders and archives of the library with page counts, filled by
+//scanner and searched by clients
+pub struct Catalogue {
+    conn: Mutex<Connection>,
+}
+
+impl Catalogue {
+    pub fn open(path: &PathU8) -> std::io::Result<Catalogue> {
+        Catalogue::init(Connection::open(path).map_err(db_error)?)
+    }
+
+    fn init(conn: Connection) -> std::io::Result<Catalogue> {
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        Ok(Catalogue {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    //(size, mtime, pages) recorded for path
+    pub fn stamp(
+        &self,
+        path: &str,
+    ) -> std::io::Result<Option<(Option<u64>, Option<u64>, Option<u64>)>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT size, mtime, pages FROM items WHERE path = ?1",
+                params![path],
+                |row| {
+                    let get = |i: usize| row.get::<_, Option<i64>>(i).map(|v| v.map(|v| v as u64));
+                    Ok((get(0)?, get(1)?, get(2)?))
+                },
+            )
+            .optional()
+            .map_err(db_error)
+    }
+
+    //added time of existing item is kept
+    pub fn upsert(&self, item: &Item) -> std::io::Result<()> {
+        let opt = |v: Option<u64>| v.map(|v| v as i64);
+
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO items (path, parent, name, kind, pages, size, mtime, added)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (path) DO UPDATE SET
+                    kind = excluded.kind,
+                    pages = excluded.pages,
+                    size = excluded.size,
+                    mtime = excluded.mtime",
+                params![
+                    item.path,
+                    parent_of(&item.path),
+                    item.name,
+                    kind_name(item.kind),
+                    opt(item.pages),
+                    opt(item.size),
+                    opt(item.mtime),
+                    item.added as i64,
+                ],
+            )
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    //path and everything under it, empty path clears all
+    pub fn remove(&self, path: &str) -> std::io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+
+        let res = if path.is_empty() {
+            conn.execute("DELETE FROM items", NO_PARAMS)
+        } else {
+            conn.execute(
+                &format!("DELETE FROM items WHERE path = ?1 OR {}", under(1)),
+                params![path],
+            )
+        };
+
+        res.map(|_| ()).map_err(db_error)
+    }
+
+    //drop children of parent whose name is not in names
+    pub fn retain_children(&self, parent: &str, names: &[String]) -> std::io::Result<()> {
+        let children: Vec<(String, String)> = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare("SELECT path, name FROM items WHERE parent = ?1")
+                .map_err(db_error)?;
+
+            let rows = stmt
+                .query_map(params![parent], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(db_error)?;
+
+            rows.collect::<Result<_, _>>().map_err(db_error)?
+        };
+
+        for (path, name) in children {
+            if !names.contains(&name) {
+                debug!("{:?} is gone from catalogue", path);
+                self.remove(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //newest first
+    pub fn search(&self, query: &Query) -> std::io::Result<Vec<Item>> {
+        let escaped = query
+            .name
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let pattern = format!("%{}%", escaped);
+        let since = query.since.map(|v| v as i64);
+        let until = query.until.map(|v| v as i64);
+        let limit = query.limit as i64;
+
+        let mut sql = String::from(
+            "SELECT path, name, kind, pages, size, mtime, added FROM items
+             WHERE name LIKE ?1 ESCAPE '\\'",
+        );
+        let mut args: Vec<&dyn ToSql> = vec![&pattern];
+
+        if !query.folder.is_empty() {
+            args.push(&query.folder);
+            sql += &format!(" AND {}", under(args.len()));
+        }
+        if let Some(since) = since.as_ref() {
+            args.push(since);
+            sql += &format!(" AND added >= ?{}", args.len());
+        }
+        if let Some(until) = until.as_ref() {
+            args.push(until);
+            sql += &format!(" AND added < ?{}", args.len());
+        }
+
+        args.push(&limit);
+        sql += &format!(" ORDER BY added DESC, path LIMIT ?{}", args.len());
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql).map_err(db_error)?;
+
+        let rows = stmt
+            .query_map(&args, |row| {
+                let get = |i: usize| row.get::<_, Option<i64>>(i).map(|v| v.map(|v| v as u64));
+                Ok(Item {
+                    path: row.get(0)?,
+                    name: row.get(1)?,
+                    kind: kind_of(&row.get::<_, String>(2)?),
+                    pages: get(3)?,
+                    size: get(4)?,
+                    mtime: get(5)?,
+                    added: row.get::<_, i64>(6)? as u64,
+                })
+            })
+            .map_err(db_error)?;
+
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn item(path: &str, kind: EntryKind, added: u64) -> Item {
+        Item {
+            path: path.to_owned(),
+            name: PathU8::from(path)
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned(),
+            kind,
+            pages: None,
+            size: None,
+            mtime: None,
+            added,
+        }
+    }
+
+    fn paths(items: &[Item]) -> Vec<&str> {
+        items.iter().map(|i| i.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let query = Query::parse(
+            &PathU8::from("a/b"),
+            Some("search=One+Piece%21&since=2019-05-15&limit=5000"),
+        )
+        .unwrap();
+
+        assert_eq!(query.name, "One Piece!");
+        assert_eq!(query.folder, "a/b");
+        assert_eq!(query.since, Some(1_557_878_400));
+        assert_eq!(query.until, None);
+        assert_eq!(query.limit, MAX_SEARCH_LIMIT);
+
+        assert_eq!(
+            Query::parse(&PathU8::new(), Some("until=86400"))
+                .unwrap()
+                .until,
+            Some(86400)
+        );
+        assert!(Query::parse(&PathU8::new(), Some("since=yesterday")).is_err());
+        assert!(Query::parse(&PathU8::new(), Some("until=2019-13-01")).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let catalogue = Catalogue::open(&PathU8::from(":memory:")).unwrap();
+
+        catalogue
+            .upsert(&item("manga", EntryKind::Dir, 10))
+            .unwrap();
+        catalogue
+            .upsert(&item("manga/One Piece 01.cbz", EntryKind::Archive, 20))
+            .unwrap();
+        catalogue
+            .upsert(&item("manga/one piece 02.cbz", EntryKind::Archive, 30))
+            .unwrap();
+        catalogue
+            .upsert(&item("mangaka/100%.zip", EntryKind::Archive, 40))
+            .unwrap();
+
+        let search = |q: &str, folder: &str| {
+            catalogue
+                .search(&Query::parse(&PathU8::from(folder), Some(q)).unwrap())
+                .unwrap()
+        };
+
+        assert_eq!(
+            paths(&search("search=piece", "")),
+            vec!["manga/one piece 02.cbz", "manga/One Piece 01.cbz"]
+        );
+        //manga/ does not hold mangaka
+        assert_eq!(search("", "manga").len(), 2);
+        assert_eq!(paths(&search("search=%25", "")), vec!["mangaka/100%.zip"]);
+        assert_eq!(search("since=30&until=40", "").len(), 1);
+        assert_eq!(search("limit=1", "").len(), 1);
+
+        //update keeps added
+        let mut updated = item("manga/One Piece 01.cbz", EntryKind::Archive, 99);
+        updated.pages = Some(180);
+        catalogue.upsert(&updated).unwrap();
+        assert_eq!(
+            catalogue.stamp("manga/One Piece 01.cbz").unwrap(),
+            Some((None, None, Some(180)))
+        );
+        let found = search("search=01", "");
+        assert_eq!(found[0].added, 20);
+        assert_eq!(found[0].pages, Some(180));
+
+        catalogue
+            .retain_children("manga", &["one piece 02.cbz".to_owned()])
+            .unwrap();
+        assert_eq!(search("", "manga").len(), 1);
+
+        catalogue.remove("manga").unwrap();
+        assert_eq!(paths(&search("", "")), vec!["mangaka/100%.zip"]);
+        assert_eq!(catalogue.stamp("manga").unwrap(), None);
+    }
+}
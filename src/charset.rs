@@ -0,0 +1,295 @@
+extern crate chardet;
+extern crate encoding_rs;
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use encoding_rs::{Encoding, UTF_8};
+
+use super::cache::PathU8;
+
+//byte which can not be decoded is kept as char ESCAPE_BASE + byte (a
+//private use block), so escaped name still maps back to exact bytes
+const ESCAPE_BASE: u32 = 0xF700;
+
+//how entry names of archive are decoded when they are not all utf8
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charset {
+    //guess per archive from its names
+    Detect,
+    Fixed(&'static Encoding),
+}
+
+impl Default for Charset {
+    fn default() -> Charset {
+        Charset::Detect
+    }
+}
+
+impl FromStr for Charset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Charset, String> {
+        let s = s.trim();
+
+        if s.eq_ignore_ascii_case("detect") {
+            return Ok(Charset::Detect);
+        }
+
+        Encoding::for_label(s.as_bytes())
+            .map(Charset::Fixed)
+            .ok_or_else(|| {
+                format!(
+                    "unknown charset {:?}, expect detect or encoding like shift_jis",
+                    s
+                )
+            })
+    }
+}
+
+impl Charset {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Charset::Detect => "detect",
+            Charset::Fixed(encoding) => encoding.name(),
+        }
+    }
+}
+
+fn is_escape(c: char) -> bool {
+    (c as u32) & !0xFF == ESCAPE_BASE
+}
+
+fn escape_byte(b: u8) -> char {
+    std::char::from_u32(ESCAPE_BASE + u32::from(b)).unwrap()
+}
+
+fn push_valid(ret: &mut String, valid: &str) {
+    for c in valid.chars() {
+        if !is_escape(c) {
+            ret.push(c);
+            continue;
+        }
+
+        //escape char itself is escaped, or it would map to one byte
+        let mut buf = [0; 4];
+        for b in c.encode_utf8(&mut buf).bytes() {
+            ret.push(escape_byte(b));
+        }
+    }
+}
+
+//utf8 is kept as is, other bytes are escaped
+pub fn escape(bytes: &[u8]) -> String {
+    let mut ret = String::with_capacity(bytes.len());
+    let mut rest = bytes;
+
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                push_valid(&mut ret, valid);
+                return ret;
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                push_valid(&mut ret, std::str::from_utf8(valid).unwrap());
+
+                //None means truncated sequence at end
+                let len = e.error_len().unwrap_or_else(|| invalid.len());
+                for b in &invalid[..len] {
+                    ret.push(escape_byte(*b));
+                }
+                rest = &invalid[len..];
+            }
+        }
+    }
+}
+
+//bytes of name before escape, other chars as utf8
+pub fn unescape(name: &str) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(name.len());
+    let mut buf = [0; 4];
+
+    for c in name.chars() {
+        if is_escape(c) {
+            ret.push((c as u32 - ESCAPE_BASE) as u8);
+        } else {
+            ret.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+
+    ret
+}
+
+fn decode_with(raw: &[u8], encoding: &'static Encoding) -> String {
+    if encoding == UTF_8 {
+        return escape(raw);
+    }
+
+    match encoding.decode_without_bom_handling_and_without_replacement(raw) {
+        Some(decoded) if !decoded.chars().any(is_escape) => decoded.into_owned(),
+        _ => escape(raw),
+    }
+}
+
+//guess from names which are not utf8, utf8 (so they are escaped) if
+//chardet has no idea
+fn detect(raws: &[&[u8]]) -> &'static Encoding {
+    let mut foreign = Vec::new();
+
+    for raw in raws.iter().filter(|raw| std::str::from_utf8(raw).is_err()) {
+        foreign.extend_from_slice(raw);
+        foreign.push(b'\n');
+    }
+
+    let (charset, confidence, _) = chardet::detect(&foreign);
+
+    match Encoding::for_label(chardet::charset2encoding(&charset).as_bytes()) {
+        Some(encoding) => {
+            debug!("names look like {} ({})", encoding.name(), confidence);
+            encoding
+        }
+        None => {
+            debug!("unknown charset {:?} of names, escape them", charset);
+            UTF_8
+        }
+    }
+}
+
+//decoded names of one archive, in the same order. archive with only
+//utf8 names is never decoded by charset. undecodable or duplicated
+//names are escaped, so every entry is still reachable by its name
+pub fn decode_names(raws: &[&[u8]], charset: Charset) -> Vec<String> {
+    let encoding = if raws.iter().all(|raw| std::str::from_utf8(raw).is_ok()) {
+        UTF_8
+    } else {
+        match charset {
+            Charset::Fixed(encoding) => encoding,
+            Charset::Detect => detect(raws),
+        }
+    };
+
+    let mut seen = HashSet::new();
+
+    raws.iter()
+        .map(|raw| {
+            let name = decode_with(raw, encoding);
+            if seen.insert(name.clone()) {
+                return name;
+            }
+
+            let escaped = escape(raw);
+            seen.insert(escaped.clone());
+            escaped
+        })
+        .collect()
+}
+
+//name of file on disk as served. only unix has non unicode names
+#[cfg(unix)]
+pub fn from_os(name: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    escape(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn from_os(name: &OsStr) -> String {
+    name.to_string_lossy().into_owned()
+}
+
+#[cfg(unix)]
+pub fn to_os(name: &str) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(unescape(name))
+}
+
+#[cfg(not(unix))]
+pub fn to_os(name: &str) -> OsString {
+    OsString::from(name)
+}
+
+//virtual path relative to root, as path on disk
+pub fn to_os_path(path: &PathU8) -> PathBuf {
+    path.iter()
+        .map(|comp| match comp.to_str() {
+            Some(name) => to_os(name),
+            None => comp.to_os_string(),
+        })
+        .collect()
+}
+
+pub fn from_os_path(path: &Path) -> PathU8 {
+    path.iter().map(from_os).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        let cases: Vec<&[u8]> = vec![
+            b"plain.jpg",
+            "\u{8868}\u{7d19}.jpg".as_bytes(),
+            b"\x95\\\x8e\x86.jpg",
+            b"truncated\xe3\x81",
+            "\u{f741}".as_bytes(),
+            b"\xff\xfe",
+        ];
+
+        let mut seen = HashSet::new();
+
+        for raw in cases {
+            let name = escape(raw);
+            assert_eq!(unescape(&name), raw);
+            assert!(seen.insert(name));
+        }
+
+        assert_eq!(escape(b"plain.jpg"), "plain.jpg");
+        //escape char in input does not look like escaped byte
+        assert_ne!(escape("\u{f741}".as_bytes()), escape(b"\x41"));
+    }
+
+    #[test]
+    fn test_decode_names() {
+        //"表紙.jpg" and "本文/01.jpg" in shift_jis
+        let sjis: Vec<&[u8]> = vec![b"\x95\\\x8e\x86.jpg", b"\x96{\x95\xb6/01.jpg", b"a.txt"];
+
+        let charset: Charset = "shift_jis".parse().unwrap();
+        let names = decode_names(&sjis, charset);
+        assert_eq!(
+            names,
+            vec!["\u{8868}\u{7d19}.jpg", "\u{672c}\u{6587}/01.jpg", "a.txt"]
+        );
+
+        //utf8 archive is never decoded by charset
+        let utf8: Vec<&[u8]> = vec!["\u{8868}\u{7d19}.jpg".as_bytes()];
+        assert_eq!(decode_names(&utf8, charset), vec!["\u{8868}\u{7d19}.jpg"]);
+
+        //not valid in given charset, still distinct and reversible
+        let broken: Vec<&[u8]> = vec![b"\x81.jpg", b"\x82.jpg"];
+        let names = decode_names(&broken, charset);
+        assert_ne!(names[0], names[1]);
+        assert_eq!(unescape(&names[0]), broken[0]);
+
+        assert_eq!("Detect".parse::<Charset>().unwrap(), Charset::Detect);
+        assert_eq!(Charset::default().label(), "detect");
+        assert_eq!(charset.label(), "Shift_JIS");
+        assert!("klingon".parse::<Charset>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_os_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let disk = Path::new(OsStr::from_bytes(b"dir/\x95\\\x8e\x86.jpg"));
+
+        let virtual_path = from_os_path(disk);
+        assert!(virtual_path.to_str().is_some());
+        assert_eq!(to_os_path(&virtual_path), disk);
+    }
+}
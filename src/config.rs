@@ -0,0 +1,1199 @@
+extern crate clap;
+extern crate toml;
+
+use super::cache::PathU8;
+use super::charset::Charset;
+use super::eviction::Eviction;
+use super::fs::{DEFAULT_ARCHIVE_LIMIT, DEFAULT_MEM_LIMIT};
+use super::limits::Limits;
+use super::prefetch::{DEFAULT_PREFETCH, DEFAULT_PREFETCH_BUDGET};
+use super::sort::SortOrder;
+use super::tarball::DEFAULT_SPOOL_LIMIT;
+
+use clap::{App, Arg, ArgMatches};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+
+pub const DEFAULT_PORT: u16 = 31257;
+
+pub const ENV_PREFIX: &str = "COMIX_";
+
+pub const DEFAULT_DISK_CACHE_LIMIT: usize = 1024 * 1024 * 1024;
+
+//settings used by convert_if_needed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageConfig {
+    pub convert: bool,
+    //images smaller than this are sent as is
+    pub convert_threshold: usize,
+    //quality used when re-encoding a jpeg source
+    pub jpeg_quality: u8,
+    //quality used when re-encoding a png source
+    pub png_quality: u8,
+    //thumbnails kept in memory
+    pub thumb_cache: usize,
+    //largest thumbnail edge client can ask for
+    pub thumb_max: u32,
+    pub thumb_quality: u8,
+    //largest width or height client can ask for when transcoding
+    pub transcode_max: u32,
+}
+
+impl Default for ImageConfig {
+    fn default() -> ImageConfig {
+        ImageConfig {
+            convert: true,
+            convert_threshold: 300 * 1024,
+            jpeg_quality: 60,
+            png_quality: 80,
+            thumb_cache: 1024,
+            thumb_max: 1024,
+            thumb_quality: 80,
+            transcode_max: 4096,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub bind: SocketAddr,
+    //more than one root is served as virtual top level dirs,
+    //named by last component of each root
+    pub roots: Vec<PathU8>,
+    //shared by cached files, opened archives and their dir trees
+    pub mem_limit: usize,
+    pub archive_limit: usize,
+    //which cached file goes first when over mem_limit
+    pub eviction: Eviction,
+    //decodes archive entry names which are not utf8, detect by default
+    pub archive_charset: Charset,
+    //nesting depth and sizes allowed when extracting archives
+    pub limits: Limits,
+    //entries after the served one extracted ahead, 0 disables
+    pub prefetch: usize,
+    //bytes extracted ahead per request
+    pub prefetch_budget: usize,
+    pub log_level: log::Level,
+    pub image: ImageConfig,
+    //converted images and thumbnails kept on disk, disabled if None
+    pub disk_cache_dir: Option<PathU8>,
+    pub disk_cache_limit: usize,
+    //entry lists of archives kept on disk, disabled if None
+    pub index_dir: Option<PathU8>,
+    //plain tars unpacked from compressed tarballs kept on disk,
+    //unpacked into memory if None
+    pub tar_spool_dir: Option<PathU8>,
+    pub tar_spool_limit: usize,
+    //database of scanned library for search, disabled if None
+    pub catalogue: Option<PathU8>,
+    //database of reading progress and bookmarks, disabled if None
+    pub progress: Option<PathU8>,
+    //user file, anyone can access if None
+    pub users: Option<PathU8>,
+    //passwords of encrypted archives by path pattern
+    pub passwords: Option<PathU8>,
+    //PEM certificate chain and private key, plain http if None
+    pub tls_cert: Option<PathU8>,
+    pub tls_key: Option<PathU8>,
+    //plain http listener redirecting to https, needs tls
+    pub http_redirect: Option<SocketAddr>,
+    //default order of listings, client can override per request
+    pub sort: SortOrder,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: ([0, 0, 0, 0], DEFAULT_PORT).into(),
+            roots: vec![PathU8::from(".")],
+            mem_limit: DEFAULT_MEM_LIMIT,
+            archive_limit: DEFAULT_ARCHIVE_LIMIT,
+            eviction: Eviction::default(),
+            archive_charset: Charset::default(),
+            limits: Limits::default(),
+            prefetch: DEFAULT_PREFETCH,
+            prefetch_budget: DEFAULT_PREFETCH_BUDGET,
+            log_level: log::Level::Info,
+            image: ImageConfig::default(),
+            disk_cache_dir: None,
+            disk_cache_limit: DEFAULT_DISK_CACHE_LIMIT,
+            index_dir: None,
+            tar_spool_dir: None,
+            tar_spool_limit: DEFAULT_SPOOL_LIMIT,
+            catalogue: None,
+            progress: None,
+            users: None,
+            passwords: None,
+            tls_cert: None,
+            tls_key: None,
+            http_redirect: None,
+            sort: SortOrder::default(),
+        }
+    }
+}
+
+//everything is optional in config file, missing one keeps default
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    roots: Option<Vec<String>>,
+    mem_limit: Option<toml::Value>,
+    archive_limit: Option<usize>,
+    eviction: Option<String>,
+    archive_charset: Option<String>,
+    limits: Option<FileLimits>,
+    prefetch: Option<usize>,
+    prefetch_budget: Option<toml::Value>,
+    log_level: Option<String>,
+    image: Option<FileImageConfig>,
+    disk_cache_dir: Option<String>,
+    disk_cache_limit: Option<toml::Value>,
+    index_dir: Option<String>,
+    tar_spool_dir: Option<String>,
+    tar_spool_limit: Option<toml::Value>,
+    catalogue: Option<String>,
+    progress: Option<String>,
+    users: Option<String>,
+    passwords: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    http_redirect: Option<String>,
+    sort_by: Option<String>,
+    dirs_first: Option<bool>,
+    sort_desc: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileImageConfig {
+    convert: Option<bool>,
+    convert_threshold: Option<toml::Value>,
+    jpeg_quality: Option<u8>,
+    png_quality: Option<u8>,
+    thumb_cache: Option<usize>,
+    thumb_max: Option<u32>,
+    thumb_quality: Option<u8>,
+    transcode_max: Option<u32>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileLimits {
+    max_depth: Option<usize>,
+    max_entry_size: Option<toml::Value>,
+    max_ratio: Option<usize>,
+    max_extract: Option<toml::Value>,
+}
+
+//why settings could not be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    //from clap, including help or version request. exit() prints it
+    //to right stream with right code
+    Args(clap::Error),
+    Invalid(Error),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::Args(e) => write!(f, "{}", e),
+            LoadError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<Error> for LoadError {
+    fn from(e: Error) -> LoadError {
+        LoadError::Invalid(e)
+    }
+}
+
+//bad setting in config, user file, certificate or password list
+pub fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+//accept plain bytes or K/M/G suffixed (1024 based) size
+pub fn parse_size(s: &str) -> std::io::Result<usize> {
+    let trimmed = s.trim();
+
+    let (num, scale) = match trimmed.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 1024),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 1024 * 1024),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 1024 * 1024 * 1024),
+        _ => (trimmed, 1),
+    };
+
+    num.trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| invalid(format!("{:?} is not a valid size", s)))
+}
+
+fn size_from_toml(key: &str, v: &toml::Value) -> std::io::Result<usize> {
+    match v {
+        toml::Value::Integer(i) if *i >= 0 => Ok(*i as usize),
+        toml::Value::String(s) => parse_size(s),
+        _ => Err(invalid(format!("{} must be a size, got {}", key, v))),
+    }
+}
+
+fn parse_bind(s: &str) -> std::io::Result<SocketAddr> {
+    if let Ok(port) = s.parse::<u16>() {
+        return Ok(([0, 0, 0, 0], port).into());
+    }
+
+    s.parse::<SocketAddr>()
+        .map_err(|e| invalid(format!("bind address {:?} invalid: {}", s, e)))
+}
+
+fn parse_level(s: &str) -> std::io::Result<log::Level> {
+    s.parse::<log::Level>()
+        .map_err(|_| invalid(format!("unknown log level {:?}", s)))
+}
+
+fn parse_num<T: std::str::FromStr>(key: &str, s: &str) -> std::io::Result<T> {
+    s.trim()
+        .parse::<T>()
+        .map_err(|_| invalid(format!("{} {:?} is not a valid number", key, s)))
+}
+
+fn parse_bool(key: &str, s: &str) -> std::io::Result<bool> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(invalid(format!("{} {:?} is not a boolean", key, s))),
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("comix-server")
+        .about("serve directories and archives for comic readers")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("toml config file"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .short("b")
+                .long("bind")
+                .value_name("ADDR")
+                .help("listen address, ip:port or port only"),
+        )
+        .arg(
+            Arg::with_name("root")
+                .short("r")
+                .long("root")
+                .value_name("DIR")
+                .multiple(true)
+                .number_of_values(1)
+                .help("library root, can be given more than once"),
+        )
+        .arg(
+            Arg::with_name("mem-limit")
+                .long("mem-limit")
+                .value_name("BYTES")
+                .help("memory for cached files and opened archives, accepts K/M/G suffix"),
+        )
+        .arg(
+            Arg::with_name("archive-limit")
+                .long("archive-limit")
+                .value_name("N")
+                .help("max opened archives kept in cache"),
+        )
+        .arg(
+            Arg::with_name("eviction")
+                .long("eviction")
+                .value_name("POLICY")
+                .help("cached file eviction policy: lru, lfu or gdsf"),
+        )
+        .arg(
+            Arg::with_name("archive-charset")
+                .long("archive-charset")
+                .value_name("CHARSET")
+                .help("charset of non utf8 entry names in archives, like shift_jis or gbk (default: detect)"),
+        )
+        .arg(
+            Arg::with_name("max-depth")
+                .long("max-depth")
+                .value_name("N")
+                .help("archives nested in archive to open, 0 refuses nested archives"),
+        )
+        .arg(
+            Arg::with_name("max-entry-size")
+                .long("max-entry-size")
+                .value_name("BYTES")
+                .help("largest archive entry to extract, accepts K/M/G suffix"),
+        )
+        .arg(
+            Arg::with_name("max-ratio")
+                .long("max-ratio")
+                .value_name("N")
+                .help("largest entry size as multiple of the archive holding it"),
+        )
+        .arg(
+            Arg::with_name("max-extract")
+                .long("max-extract")
+                .value_name("BYTES")
+                .help("bytes extracted per request, nested archives included, accepts K/M/G suffix"),
+        )
+        .arg(
+            Arg::with_name("prefetch")
+                .long("prefetch")
+                .value_name("N")
+                .help("archive entries to extract ahead of reader, 0 disables"),
+        )
+        .arg(
+            Arg::with_name("prefetch-budget")
+                .long("prefetch-budget")
+                .value_name("BYTES")
+                .help("bytes extracted ahead per request, accepts K/M/G suffix"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .short("l")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("error, warn, info, debug or trace"),
+        )
+        .arg(
+            Arg::with_name("disk-cache-dir")
+                .long("disk-cache-dir")
+                .value_name("DIR")
+                .help("keep converted images and thumbnails under DIR"),
+        )
+        .arg(
+            Arg::with_name("disk-cache-limit")
+                .long("disk-cache-limit")
+                .value_name("BYTES")
+                .help("disk cache size, accepts K/M/G suffix"),
+        )
+        .arg(
+            Arg::with_name("index-dir")
+                .long("index-dir")
+                .value_name("DIR")
+                .help("keep entry lists of archives under DIR"),
+        )
+        .arg(
+            Arg::with_name("tar-spool-dir")
+                .long("tar-spool-dir")
+                .value_name("DIR")
+                .help("unpack compressed tarballs under DIR instead of memory"),
+        )
+        .arg(
+            Arg::with_name("tar-spool-limit")
+                .long("tar-spool-limit")
+                .value_name("BYTES")
+                .help("tar spool size, accepts K/M/G suffix"),
+        )
+        .arg(
+            Arg::with_name("catalogue")
+                .long("catalogue")
+                .value_name("FILE")
+                .help("scan library into FILE and serve ?search= queries"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .value_name("FILE")
+                .help("keep reading progress and bookmarks in FILE"),
+        )
+        .arg(
+            Arg::with_name("users")
+                .long("users")
+                .value_name("FILE")
+                .help("require login of users in FILE (argon2 password hashes)"),
+        )
+        .arg(
+            Arg::with_name("passwords")
+                .long("passwords")
+                .value_name("FILE")
+                .help("passwords of encrypted archives in FILE, by path pattern"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("FILE")
+                .help("serve https with PEM certificate chain in FILE"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("FILE")
+                .help("PEM private key of --tls-cert"),
+        )
+        .arg(
+            Arg::with_name("http-redirect")
+                .long("http-redirect")
+                .value_name("ADDR")
+                .help("also listen for plain http on ADDR and redirect to https"),
+        )
+        .arg(
+            Arg::with_name("sort-by")
+                .long("sort-by")
+                .value_name("KEY")
+                .help("default listing order: name, mtime or size"),
+        )
+        .arg(
+            Arg::with_name("dirs-first")
+                .long("dirs-first")
+                .help("list dirs and archives before files"),
+        )
+        .arg(
+            Arg::with_name("sort-desc")
+                .long("sort-desc")
+                .help("list in descending order"),
+        )
+        .arg(
+            Arg::with_name("no-convert")
+                .long("no-convert")
+                .help("send images as is"),
+        )
+        .arg(
+            Arg::with_name("convert-threshold")
+                .long("convert-threshold")
+                .value_name("BYTES")
+                .help("only re-encode images larger than this"),
+        )
+        .arg(
+            Arg::with_name("jpeg-quality")
+                .long("jpeg-quality")
+                .value_name("Q")
+                .help("quality used to re-encode jpeg images"),
+        )
+        .arg(
+            Arg::with_name("png-quality")
+                .long("png-quality")
+                .value_name("Q")
+                .help("quality used to re-encode png images"),
+        )
+        .arg(
+            Arg::with_name("thumb-cache")
+                .long("thumb-cache")
+                .value_name("N")
+                .help("thumbnails kept in memory"),
+        )
+        .arg(
+            Arg::with_name("thumb-max")
+                .long("thumb-max")
+                .value_name("PIXELS")
+                .help("largest thumbnail width or height"),
+        )
+        .arg(
+            Arg::with_name("thumb-quality")
+                .long("thumb-quality")
+                .value_name("Q")
+                .help("jpeg quality of thumbnails"),
+        )
+        .arg(
+            Arg::with_name("transcode-max")
+                .long("transcode-max")
+                .value_name("PIXELS")
+                .help("largest width or height client can ask for"),
+        )
+}
+
+impl Config {
+    //convenient for tests and fuzz targets: default settings serving one root
+    pub fn with_root(root: &PathU8) -> Config {
+        Config {
+            roots: vec![root.clone()],
+            ..Config::default()
+        }
+    }
+
+    //read settings from process args and environment
+    pub fn load() -> Result<Config, LoadError> {
+        let env: HashMap<String, String> = std::env::vars()
+            .filter(|(k, _)| k.starts_with(ENV_PREFIX))
+            .collect();
+        Config::from_sources(std::env::args_os(), &env)
+    }
+
+    //priority: command line > environment > config file > default
+    pub fn from_sources<I, T>(args: I, env: &HashMap<String, String>) -> Result<Config, LoadError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = app().get_matches_from_safe(args).map_err(LoadError::Args)?;
+
+        let mut config = Config::default();
+
+        let config_file = matches
+            .value_of("config")
+            .map(String::from)
+            .or_else(|| env.get("COMIX_CONFIG").cloned());
+
+        if let Some(file) = config_file {
+            config.apply_file(&PathU8::from(file))?;
+        }
+
+        config.apply_env(env)?;
+        config.apply_args(&matches)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, path: &PathU8) -> std::io::Result<()> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("can not read config file {:?}: {}", path, e),
+            )
+        })?;
+
+        self.apply_toml(&content)
+            .map_err(|e| invalid(format!("config file {:?}: {}", path, e)))
+    }
+
+    fn apply_toml(&mut self, content: &str) -> std::io::Result<()> {
+        let file: FileConfig = toml::from_str(content).map_err(|e| invalid(e.to_string()))?;
+
+        if let Some(bind) = file.bind {
+            self.bind = parse_bind(&bind)?;
+        }
+        if let Some(roots) = file.roots {
+            self.roots = roots.into_iter().map(PathU8::from).collect();
+        }
+        if let Some(v) = file.mem_limit {
+            self.mem_limit = size_from_toml("mem_limit", &v)?;
+        }
+        if let Some(v) = file.archive_limit {
+            self.archive_limit = v;
+        }
+        if let Some(v) = file.eviction {
+            self.eviction = v.parse().map_err(invalid)?;
+        }
+        if let Some(v) = file.archive_charset {
+            self.archive_charset = v.parse().map_err(invalid)?;
+        }
+        if let Some(limits) = file.limits {
+            if let Some(v) = limits.max_depth {
+                self.limits.max_depth = v;
+            }
+            if let Some(v) = limits.max_entry_size {
+                self.limits.max_entry_size = size_from_toml("limits.max_entry_size", &v)?;
+            }
+            if let Some(v) = limits.max_ratio {
+                self.limits.max_ratio = v;
+            }
+            if let Some(v) = limits.max_extract {
+                self.limits.max_extract = size_from_toml("limits.max_extract", &v)?;
+            }
+        }
+        if let Some(v) = file.prefetch {
+            self.prefetch = v;
+        }
+        if let Some(v) = file.prefetch_budget {
+            self.prefetch_budget = size_from_toml("prefetch_budget", &v)?;
+        }
+        if let Some(level) = file.log_level {
+            self.log_level = parse_level(&level)?;
+        }
+        if let Some(dir) = file.disk_cache_dir {
+            self.disk_cache_dir = Some(PathU8::from(dir));
+        }
+        if let Some(v) = file.disk_cache_limit {
+            self.disk_cache_limit = size_from_toml("disk_cache_limit", &v)?;
+        }
+        if let Some(dir) = file.index_dir {
+            self.index_dir = Some(PathU8::from(dir));
+        }
+        if let Some(dir) = file.tar_spool_dir {
+            self.tar_spool_dir = Some(PathU8::from(dir));
+        }
+        if let Some(v) = file.tar_spool_limit {
+            self.tar_spool_limit = size_from_toml("tar_spool_limit", &v)?;
+        }
+        if let Some(path) = file.catalogue {
+            self.catalogue = Some(PathU8::from(path));
+        }
+        if let Some(path) = file.progress {
+            self.progress = Some(PathU8::from(path));
+        }
+        if let Some(path) = file.users {
+            self.users = Some(PathU8::from(path));
+        }
+        if let Some(path) = file.passwords {
+            self.passwords = Some(PathU8::from(path));
+        }
+        if let Some(path) = file.tls_cert {
+            self.tls_cert = Some(PathU8::from(path));
+        }
+        if let Some(path) = file.tls_key {
+            self.tls_key = Some(PathU8::from(path));
+        }
+        if let Some(v) = file.http_redirect {
+            self.http_redirect = Some(parse_bind(&v)?);
+        }
+        if let Some(v) = file.sort_by {
+            self.sort.key = v.parse().map_err(invalid)?;
+        }
+        if let Some(v) = file.dirs_first {
+            self.sort.dirs_first = v;
+        }
+        if let Some(v) = file.sort_desc {
+            self.sort.descending = v;
+        }
+        if let Some(image) = file.image {
+            if let Some(v) = image.convert {
+                self.image.convert = v;
+            }
+            if let Some(v) = image.convert_threshold {
+                self.image.convert_threshold = size_from_toml("image.convert_threshold", &v)?;
+            }
+            if let Some(v) = image.jpeg_quality {
+                self.image.jpeg_quality = v;
+            }
+            if let Some(v) = image.png_quality {
+                self.image.png_quality = v;
+            }
+            if let Some(v) = image.thumb_cache {
+                self.image.thumb_cache = v;
+            }
+            if let Some(v) = image.thumb_max {
+                self.image.thumb_max = v;
+            }
+            if let Some(v) = image.thumb_quality {
+                self.image.thumb_quality = v;
+            }
+            if let Some(v) = image.transcode_max {
+                self.image.transcode_max = v;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_env(&mut self, env: &HashMap<String, String>) -> std::io::Result<()> {
+        let get = |key: &str| env.get(&(ENV_PREFIX.to_owned() + key));
+
+        if let Some(v) = get("BIND") {
+            self.bind = parse_bind(v)?;
+        }
+        if let Some(v) = get("ROOTS") {
+            self.roots = std::env::split_paths(v).collect();
+        }
+        if let Some(v) = get("MEM_LIMIT") {
+            self.mem_limit = parse_size(v)?;
+        }
+        if let Some(v) = get("ARCHIVE_LIMIT") {
+            self.archive_limit = parse_num("COMIX_ARCHIVE_LIMIT", v)?;
+        }
+        if let Some(v) = get("EVICTION") {
+            self.eviction = v.parse().map_err(invalid)?;
+        }
+        if let Some(v) = get("ARCHIVE_CHARSET") {
+            self.archive_charset = v.parse().map_err(invalid)?;
+        }
+        if let Some(v) = get("MAX_DEPTH") {
+            self.limits.max_depth = parse_num("COMIX_MAX_DEPTH", v)?;
+        }
+        if let Some(v) = get("MAX_ENTRY_SIZE") {
+            self.limits.max_entry_size = parse_size(v)?;
+        }
+        if let Some(v) = get("MAX_RATIO") {
+            self.limits.max_ratio = parse_num("COMIX_MAX_RATIO", v)?;
+        }
+        if let Some(v) = get("MAX_EXTRACT") {
+            self.limits.max_extract = parse_size(v)?;
+        }
+        if let Some(v) = get("PREFETCH") {
+            self.prefetch = parse_num("COMIX_PREFETCH", v)?;
+        }
+        if let Some(v) = get("PREFETCH_BUDGET") {
+            self.prefetch_budget = parse_size(v)?;
+        }
+        if let Some(v) = get("LOG_LEVEL") {
+            self.log_level = parse_level(v)?;
+        }
+        if let Some(v) = get("DISK_CACHE_DIR") {
+            self.disk_cache_dir = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("DISK_CACHE_LIMIT") {
+            self.disk_cache_limit = parse_size(v)?;
+        }
+        if let Some(v) = get("INDEX_DIR") {
+            self.index_dir = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("TAR_SPOOL_DIR") {
+            self.tar_spool_dir = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("TAR_SPOOL_LIMIT") {
+            self.tar_spool_limit = parse_size(v)?;
+        }
+        if let Some(v) = get("CATALOGUE") {
+            self.catalogue = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("PROGRESS") {
+            self.progress = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("USERS") {
+            self.users = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("PASSWORDS") {
+            self.passwords = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("TLS_CERT") {
+            self.tls_cert = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("TLS_KEY") {
+            self.tls_key = Some(PathU8::from(v));
+        }
+        if let Some(v) = get("HTTP_REDIRECT") {
+            self.http_redirect = Some(parse_bind(v)?);
+        }
+        if let Some(v) = get("SORT_BY") {
+            self.sort.key = v.parse().map_err(invalid)?;
+        }
+        if let Some(v) = get("DIRS_FIRST") {
+            self.sort.dirs_first = parse_bool("COMIX_DIRS_FIRST", v)?;
+        }
+        if let Some(v) = get("SORT_DESC") {
+            self.sort.descending = parse_bool("COMIX_SORT_DESC", v)?;
+        }
+        if let Some(v) = get("CONVERT") {
+            self.image.convert = parse_bool("COMIX_CONVERT", v)?;
+        }
+        if let Some(v) = get("CONVERT_THRESHOLD") {
+            self.image.convert_threshold = parse_size(v)?;
+        }
+        if let Some(v) = get("JPEG_QUALITY") {
+            self.image.jpeg_quality = parse_num("COMIX_JPEG_QUALITY", v)?;
+        }
+        if let Some(v) = get("PNG_QUALITY") {
+            self.image.png_quality = parse_num("COMIX_PNG_QUALITY", v)?;
+        }
+        if let Some(v) = get("THUMB_CACHE") {
+            self.image.thumb_cache = parse_num("COMIX_THUMB_CACHE", v)?;
+        }
+        if let Some(v) = get("THUMB_MAX") {
+            self.image.thumb_max = parse_num("COMIX_THUMB_MAX", v)?;
+        }
+        if let Some(v) = get("THUMB_QUALITY") {
+            self.image.thumb_quality = parse_num("COMIX_THUMB_QUALITY", v)?;
+        }
+        if let Some(v) = get("TRANSCODE_MAX") {
+            self.image.transcode_max = parse_num("COMIX_TRANSCODE_MAX", v)?;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, matches: &ArgMatches) -> std::io::Result<()> {
+        if let Some(v) = matches.value_of("bind") {
+            self.bind = parse_bind(v)?;
+        }
+        if let Some(roots) = matches.values_of("root") {
+            self.roots = roots.map(PathU8::from).collect();
+        }
+        if let Some(v) = matches.value_of("mem-limit") {
+            self.mem_limit = parse_size(v)?;
+        }
+        if let Some(v) = matches.value_of("archive-limit") {
+            self.archive_limit = parse_num("--archive-limit", v)?;
+        }
+        if let Some(v) = matches.value_of("eviction") {
+            self.eviction = v.parse().map_err(invalid)?;
+        }
+        if let Some(v) = matches.value_of("archive-charset") {
+            self.archive_charset = v.parse().map_err(invalid)?;
+        }
+        if let Some(v) = matches.value_of("max-depth") {
+            self.limits.max_depth = parse_num("--max-depth", v)?;
+        }
+        if let Some(v) = matches.value_of("max-entry-size") {
+            self.limits.max_entry_size = parse_size(v)?;
+        }
+        if let Some(v) = matches.value_of("max-ratio") {
+            self.limits.max_ratio = parse_num("--max-ratio", v)?;
+        }
+        if let Some(v) = matches.value_of("max-extract") {
+            self.limits.max_extract = parse_size(v)?;
+        }
+        if let Some(v) = matches.value_of("prefetch") {
+            self.prefetch = parse_num("--prefetch", v)?;
+        }
+        if let Some(v) = matches.value_of("prefetch-budget") {
+            self.prefetch_budget = parse_size(v)?;
+        }
+        if let Some(v) = matches.value_of("log-level") {
+            self.log_level = parse_level(v)?;
+        }
+        if let Some(v) = matches.value_of("disk-cache-dir") {
+            self.disk_cache_dir = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("disk-cache-limit") {
+            self.disk_cache_limit = parse_size(v)?;
+        }
+        if let Some(v) = matches.value_of("index-dir") {
+            self.index_dir = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("tar-spool-dir") {
+            self.tar_spool_dir = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("tar-spool-limit") {
+            self.tar_spool_limit = parse_size(v)?;
+        }
+        if let Some(v) = matches.value_of("catalogue") {
+            self.catalogue = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("progress") {
+            self.progress = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("users") {
+            self.users = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("passwords") {
+            self.passwords = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("tls-cert") {
+            self.tls_cert = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("tls-key") {
+            self.tls_key = Some(PathU8::from(v));
+        }
+        if let Some(v) = matches.value_of("http-redirect") {
+            self.http_redirect = Some(parse_bind(v)?);
+        }
+        if let Some(v) = matches.value_of("sort-by") {
+            self.sort.key = v.parse().map_err(invalid)?;
+        }
+        if matches.is_present("dirs-first") {
+            self.sort.dirs_first = true;
+        }
+        if matches.is_present("sort-desc") {
+            self.sort.descending = true;
+        }
+        if matches.is_present("no-convert") {
+            self.image.convert = false;
+        }
+        if let Some(v) = matches.value_of("convert-threshold") {
+            self.image.convert_threshold = parse_size(v)?;
+        }
+        if let Some(v) = matches.value_of("jpeg-quality") {
+            self.image.jpeg_quality = parse_num("--jpeg-quality", v)?;
+        }
+        if let Some(v) = matches.value_of("png-quality") {
+            self.image.png_quality = parse_num("--png-quality", v)?;
+        }
+        if let Some(v) = matches.value_of("thumb-cache") {
+            self.image.thumb_cache = parse_num("--thumb-cache", v)?;
+        }
+        if let Some(v) = matches.value_of("thumb-max") {
+            self.image.thumb_max = parse_num("--thumb-max", v)?;
+        }
+        if let Some(v) = matches.value_of("thumb-quality") {
+            self.image.thumb_quality = parse_num("--thumb-quality", v)?;
+        }
+        if let Some(v) = matches.value_of("transcode-max") {
+            self.image.transcode_max = parse_num("--transcode-max", v)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> std::io::Result<()> {
+        if self.roots.is_empty() {
+            return Err(invalid("at least one root is required".to_owned()));
+        }
+
+        let mut names = std::collections::HashSet::new();
+
+        for root in self.roots.iter() {
+            let meta = root.metadata().map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("root {:?} is not accessible: {}", root, e),
+                )
+            })?;
+
+            if !meta.is_dir() {
+                return Err(invalid(format!("root {:?} is not a directory", root)));
+            }
+
+            if self.roots.len() > 1 {
+                let name = mount_name(root)?;
+                if !names.insert(name.clone()) {
+                    return Err(invalid(format!(
+                        "roots share same name {:?}, can not mount them together",
+                        name
+                    )));
+                }
+            }
+        }
+
+        if self.mem_limit == 0 {
+            return Err(invalid("mem_limit must be larger than 0".to_owned()));
+        }
+
+        if self.archive_limit == 0 {
+            return Err(invalid("archive_limit must be larger than 0".to_owned()));
+        }
+
+        if self.limits.max_entry_size == 0
+            || self.limits.max_ratio == 0
+            || self.limits.max_extract == 0
+        {
+            return Err(invalid(
+                "max_entry_size, max_ratio and max_extract must be larger than 0".to_owned(),
+            ));
+        }
+
+        if self.disk_cache_dir.is_some() && self.disk_cache_limit == 0 {
+            return Err(invalid("disk_cache_limit must be larger than 0".to_owned()));
+        }
+
+        if self.index_dir.is_some() && self.index_dir == self.disk_cache_dir {
+            return Err(invalid(
+                "index_dir and disk_cache_dir must be different".to_owned(),
+            ));
+        }
+
+        if self.tar_spool_dir.is_some() && self.tar_spool_limit == 0 {
+            return Err(invalid("tar_spool_limit must be larger than 0".to_owned()));
+        }
+
+        if self.tar_spool_dir.is_some()
+            && (self.tar_spool_dir == self.disk_cache_dir || self.tar_spool_dir == self.index_dir)
+        {
+            return Err(invalid(
+                "tar_spool_dir must differ from disk_cache_dir and index_dir".to_owned(),
+            ));
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(invalid(
+                "tls_cert and tls_key must be given together".to_owned(),
+            ));
+        }
+
+        if let Some(redirect) = self.http_redirect {
+            if self.tls_cert.is_none() {
+                return Err(invalid(
+                    "http_redirect needs tls_cert and tls_key".to_owned(),
+                ));
+            }
+            if redirect == self.bind {
+                return Err(invalid(
+                    "http_redirect and bind must be different".to_owned(),
+                ));
+            }
+        }
+
+        if self.image.thumb_cache == 0 || self.image.thumb_max == 0 || self.image.transcode_max == 0
+        {
+            return Err(invalid(
+                "thumb_cache, thumb_max and transcode_max must be larger than 0".to_owned(),
+            ));
+        }
+
+        for (key, q) in [
+            ("jpeg_quality", self.image.jpeg_quality),
+            ("png_quality", self.image.png_quality),
+            ("thumb_quality", self.image.thumb_quality),
+        ]
+        .iter()
+        {
+            if *q == 0 || *q > 100 {
+                return Err(invalid(format!("{} must be in 1..=100, got {}", key, q)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+//name of a root when served together with other roots
+pub fn mount_name(root: &PathU8) -> std::io::Result<String> {
+    let abs = std::fs::canonicalize(root)?;
+
+    abs.file_name()
+        .and_then(|n| n.to_str())
+        .map(String::from)
+        .ok_or_else(|| invalid(format!("root {:?} has no usable name", root)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::sort::SortKey;
+
+    fn args(v: &[&str]) -> Vec<String> {
+        std::iter::once("comix-server")
+            .chain(v.iter().cloned())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size("256M").unwrap(), 256 * 1024 * 1024);
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn test_default() {
+        let config = Config::from_sources(args(&[]), &HashMap::new()).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_help() {
+        for flag in &["--help", "--version"] {
+            match Config::from_sources(args(&[*flag]), &HashMap::new()) {
+                Err(LoadError::Args(e)) => assert!(
+                    e.kind == clap::ErrorKind::HelpDisplayed
+                        || e.kind == clap::ErrorKind::VersionDisplayed
+                ),
+                other => panic!("{} gives {:?}", flag, other),
+            }
+        }
+
+        match Config::from_sources(args(&["--no-such-flag"]), &HashMap::new()) {
+            Err(LoadError::Args(e)) => assert_eq!(e.kind, clap::ErrorKind::UnknownArgument),
+            other => panic!("unknown flag gives {:?}", other),
+        }
+        match Config::from_sources(args(&["--log-level", "loud"]), &HashMap::new()) {
+            Err(LoadError::Invalid(e)) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+            other => panic!("bad level gives {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut config = Config::default();
+
+        config
+            .apply_toml(
+                r#"
+                bind = "127.0.0.1:8000"
+                mem_limit = "1M"
+                prefetch_budget = "4M"
+                users = "users.toml"
+                passwords = "passwords.toml"
+                sort_by = "mtime"
+                tar_spool_limit = "8G"
+                [image]
+                jpeg_quality = 70
+                [limits]
+                max_depth = 1
+                max_entry_size = "64M"
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1:8000".parse().unwrap());
+        assert_eq!(config.mem_limit, 1024 * 1024);
+        assert_eq!(config.image.jpeg_quality, 70);
+        assert_eq!(config.sort.key, SortKey::Mtime);
+        assert_eq!(config.prefetch_budget, 4 * 1024 * 1024);
+        assert_eq!(config.users, Some(PathU8::from("users.toml")));
+        assert_eq!(config.limits.max_depth, 1);
+        assert_eq!(config.limits.max_entry_size, 64 * 1024 * 1024);
+        assert_eq!(config.tar_spool_limit, 8 * 1024 * 1024 * 1024);
+
+        let mut env = HashMap::new();
+        env.insert("COMIX_MEM_LIMIT".to_owned(), "2M".to_owned());
+        env.insert("COMIX_ARCHIVE_LIMIT".to_owned(), "7".to_owned());
+        env.insert("COMIX_EVICTION".to_owned(), "gdsf".to_owned());
+        env.insert("COMIX_ARCHIVE_CHARSET".to_owned(), "gbk".to_owned());
+        env.insert("COMIX_PASSWORDS".to_owned(), "secret.toml".to_owned());
+        env.insert("COMIX_PROGRESS".to_owned(), "progress.db".to_owned());
+        env.insert("COMIX_MAX_RATIO".to_owned(), "20".to_owned());
+        env.insert("COMIX_TLS_KEY".to_owned(), "key.pem".to_owned());
+        env.insert("COMIX_TAR_SPOOL_DIR".to_owned(), "spool".to_owned());
+        config.apply_env(&env).unwrap();
+
+        assert_eq!(config.mem_limit, 2 * 1024 * 1024);
+        assert_eq!(config.archive_limit, 7);
+        assert_eq!(config.eviction, Eviction::Gdsf);
+        assert_eq!(config.archive_charset.label(), "GBK");
+        assert_eq!(config.passwords, Some(PathU8::from("secret.toml")));
+        assert_eq!(config.progress, Some(PathU8::from("progress.db")));
+        assert_eq!(config.limits.max_ratio, 20);
+        assert_eq!(config.tls_key, Some(PathU8::from("key.pem")));
+        assert_eq!(config.tar_spool_dir, Some(PathU8::from("spool")));
+
+        let from_all = Config::from_sources(
+            args(&[
+                "--root",
+                env!("CARGO_MANIFEST_DIR"),
+                "--archive-limit",
+                "3",
+                "--prefetch",
+                "0",
+                "--catalogue",
+                "library.db",
+                "--tls-cert",
+                "cert.pem",
+                "--http-redirect",
+                "8080",
+                "--archive-charset",
+                "detect",
+                "--max-extract",
+                "1G",
+                "--tar-spool-limit",
+                "2G",
+            ]),
+            &env,
+        )
+        .unwrap();
+
+        assert_eq!(from_all.archive_limit, 3);
+        assert_eq!(from_all.prefetch, 0);
+        assert_eq!(from_all.archive_charset, Charset::Detect);
+        assert_eq!(from_all.limits.max_extract, 1024 * 1024 * 1024);
+        assert_eq!(from_all.limits.max_ratio, 20);
+        assert_eq!(from_all.tar_spool_dir, Some(PathU8::from("spool")));
+        assert_eq!(from_all.tar_spool_limit, 2 * 1024 * 1024 * 1024);
+        assert_eq!(from_all.catalogue, Some(PathU8::from("library.db")));
+        assert_eq!(from_all.tls_cert, Some(PathU8::from("cert.pem")));
+        assert_eq!(from_all.http_redirect, Some(([0, 0, 0, 0], 8080).into()));
+        assert_eq!(from_all.mem_limit, 2 * 1024 * 1024);
+        assert_eq!(
+            from_all.roots,
+            vec![PathU8::from(env!("CARGO_MANIFEST_DIR"))]
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut config = Config::default();
+        assert!(config.apply_toml("unknown_key = 1").is_err());
+        assert!(config.apply_toml("bind = \"not addr\"").is_err());
+
+        assert!(Config::from_sources(args(&["--jpeg-quality", "0"]), &HashMap::new()).is_err());
+        assert!(Config::from_sources(args(&["--root", "not exists"]), &HashMap::new()).is_err());
+        assert!(Config::from_sources(args(&["--log-level", "loud"]), &HashMap::new()).is_err());
+        assert!(config.apply_toml("sort_by = \"color\"").is_err());
+        assert!(config.apply_toml("eviction = \"fifo\"").is_err());
+        assert!(config.apply_toml("archive_charset = \"klingon\"").is_err());
+        assert!(config.apply_toml("[limits]\nmax_bombs = 1").is_err());
+        assert!(Config::from_sources(args(&["--max-ratio", "0"]), &HashMap::new()).is_err());
+        assert!(Config::from_sources(args(&["--tls-cert", "cert.pem"]), &HashMap::new()).is_err());
+        assert!(Config::from_sources(args(&["--http-redirect", "80"]), &HashMap::new()).is_err());
+        assert!(Config::from_sources(
+            args(&["--tar-spool-dir", "a", "--index-dir", "a"]),
+            &HashMap::new()
+        )
+        .is_err());
+
+        let manifest = env!("CARGO_MANIFEST_DIR");
+        assert!(
+            Config::from_sources(args(&["-r", manifest, "-r", manifest]), &HashMap::new()).is_err()
+        );
+    }
+}
@@ -0,0 +1,455 @@
+extern crate filetime;
+extern crate sha2;
+
+use filetime::FileTime;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+const TMP_SUFFIX: &str = ".tmp";
+
+//tells apart temp files of concurrent writes of same key
+static TMP_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+struct DiskIndex {
+    //key => (size, last access tick)
+    entries: HashMap<String, (u64, u64)>,
+    size: u64,
+    tick: u64,
+    //key => holders of Pin, never evicted while held
+    pinned: HashMap<String, usize>,
+}
+
+impl DiskIndex {
+    fn touch(&mut self, key: &str) -> bool {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            None => false,
+            Some(entry) => {
+                entry.1 = tick;
+                true
+            }
+        }
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        self.tick += 1;
+        if let Some((old, _)) = self.entries.insert(key, (size, self.tick)) {
+            self.size -= old;
+        }
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((size, _)) = self.entries.remove(key) {
+            self.size -= size;
+        }
+    }
+
+    fn least_used(&self, except: &str) -> Option<String> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| k.as_str() != except && !self.pinned.contains_key(k.as_str()))
+            .min_by_key(|(_, (_, tick))| *tick)
+            .map(|(k, _)| k.clone())
+    }
+}
+
+//keeps file of key from being evicted while file is in use. taken
+//before file is located or written, so it can not go in between
+pub struct Pin {
+    index: Arc<Mutex<DiskIndex>>,
+    key: String,
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut index = self.index.lock().unwrap();
+        let left = index.pinned.get_mut(&self.key).map(|holders| {
+            *holders -= 1;
+            *holders
+        });
+        if left == Some(0) {
+            index.pinned.remove(&self.key);
+        }
+    }
+}
+
+//content addressed blobs on disk (converted images, thumbnails), bounded
+//by total bytes with lru eviction. survives restart, access order is
+//recovered from file mtime
+pub struct DiskCache {
+    dir: PathBuf,
+    limit: u64,
+    index: Arc<Mutex<DiskIndex>>,
+}
+
+//stable across builds and rust versions, unlike DefaultHasher
+pub fn key_of(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.input(part.as_bytes());
+        //separator, so ["ab","c"] and ["a","bc"] differ
+        hasher.input(&[0u8]);
+    }
+
+    hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl DiskCache {
+    pub fn open(dir: &PathBuf, limit: u64) -> std::io::Result<DiskCache> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut found = Vec::new();
+
+        for shard in std::fs::read_dir(dir)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+
+            for file in std::fs::read_dir(shard.path())? {
+                let file = file?;
+                let name = file.file_name().to_string_lossy().into_owned();
+
+                if name.ends_with(TMP_SUFFIX) {
+                    //crashed while writing
+                    let _ = std::fs::remove_file(file.path());
+                    continue;
+                }
+
+                let attr = file.metadata()?;
+                let mtime = attr.modified().ok();
+                found.push((mtime, name, attr.len()));
+            }
+        }
+
+        //oldest first, so it gets lowest tick
+        found.sort();
+
+        let mut index = DiskIndex {
+            entries: HashMap::new(),
+            size: 0,
+            tick: 0,
+            pinned: HashMap::new(),
+        };
+
+        for (_, key, size) in found {
+            index.insert(key, size);
+        }
+
+        debug!(
+            "disk cache {:?} loaded {} entries, {} bytes",
+            dir,
+            index.entries.len(),
+            index.size
+        );
+
+        let ret = DiskCache {
+            dir: dir.clone(),
+            limit,
+            index: Arc::new(Mutex::new(index)),
+        };
+
+        //limit may be lowered since last run
+        ret.recycle(&mut ret.index.lock().unwrap(), "");
+
+        Ok(ret)
+    }
+
+    fn path_of(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(key)
+    }
+
+    //written then renamed to path of key, so reader never sees partial
+    //file. unique per write, same key may be written concurrently
+    fn tmp_of(path: &Path, key: &str) -> PathBuf {
+        let seq = TMP_SEQ.fetch_add(1, Ordering::SeqCst);
+        path.with_file_name(format!(
+            "{}.{}.{}{}",
+            key,
+            std::process::id(),
+            seq,
+            TMP_SUFFIX
+        ))
+    }
+
+    //hit is recorded in mtime too, so access order survives restart
+    fn bump(path: &Path) {
+        let now = FileTime::from_system_time(SystemTime::now());
+        if let Err(e) = filetime::set_file_mtime(path, now) {
+            debug!("can not touch {:?}: {:?}", path, e);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        if !self.index.lock().unwrap().touch(key) {
+            return None;
+        }
+
+        let path = self.path_of(key);
+        match std::fs::read(&path) {
+            Ok(bin) => {
+                trace!("disk cache hit {}", key);
+                DiskCache::bump(&path);
+                Some(bin)
+            }
+            Err(e) => {
+                //removed behind us
+                debug!("disk cache entry {} unreadable: {:?}", key, e);
+                self.index.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    //file of key for reading in place, too large to get as bytes
+    pub fn locate(&self, key: &str) -> Option<PathBuf> {
+        if !self.index.lock().unwrap().touch(key) {
+            return None;
+        }
+
+        let path = self.path_of(key);
+        if path.is_file() {
+            DiskCache::bump(&path);
+            return Some(path);
+        }
+
+        //removed behind us
+        self.index.lock().unwrap().remove(key);
+        None
+    }
+
+    pub fn pin(&self, key: &str) -> Pin {
+        *self
+            .index
+            .lock()
+            .unwrap()
+            .pinned
+            .entry(key.to_owned())
+            .or_insert(0) += 1;
+
+        Pin {
+            index: self.index.clone(),
+            key: key.to_owned(),
+        }
+    }
+
+    //copy reader into file of key. nothing is kept and None returned if
+    //it has more than max bytes (or more than limit)
+    pub fn put_from(
+        &self,
+        key: &str,
+        reader: &mut Read,
+        max: u64,
+    ) -> std::io::Result<Option<PathBuf>> {
+        let max = std::cmp::min(max, self.limit);
+
+        let path = self.path_of(key);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        let tmp = DiskCache::tmp_of(&path, key);
+        let copied = std::fs::File::create(&tmp)
+            .and_then(|mut file| std::io::copy(&mut reader.take(max + 1), &mut file));
+
+        let size = match copied {
+            Ok(size) if size <= max => size,
+            res => {
+                let _ = std::fs::remove_file(&tmp);
+                return res.map(|_| None);
+            }
+        };
+
+        std::fs::rename(&tmp, &path)?;
+
+        let mut index = self.index.lock().unwrap();
+        index.insert(key.to_owned(), size);
+        self.recycle(&mut index, key);
+
+        Ok(Some(path))
+    }
+
+    pub fn put(&self, key: &str, bin: &[u8]) -> std::io::Result<()> {
+        if bin.len() as u64 > self.limit {
+            return Ok(());
+        }
+
+        let path = self.path_of(key);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        let tmp = DiskCache::tmp_of(&path, key);
+        {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(bin)?;
+        }
+        std::fs::rename(&tmp, &path)?;
+
+        let mut index = self.index.lock().unwrap();
+        index.insert(key.to_owned(), bin.len() as u64);
+        self.recycle(&mut index, key);
+
+        Ok(())
+    }
+
+    fn recycle(&self, index: &mut DiskIndex, preserved_key: &str) {
+        while index.size > self.limit {
+            let victim = match index.least_used(preserved_key) {
+                None => return,
+                Some(victim) => victim,
+            };
+
+            trace!("disk cache evict {}", victim);
+
+            if let Err(e) = std::fs::remove_file(self.path_of(&victim)) {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("can not remove disk cache {}: {:?}", victim, e);
+                }
+            }
+
+            index.remove(&victim);
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("archive-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(key_of(&["a", "b"]), key_of(&["a", "b"]));
+        assert_ne!(key_of(&["ab", "c"]), key_of(&["a", "bc"]));
+        assert_eq!(key_of(&[]).len(), 64);
+    }
+
+    #[test]
+    fn test_evict_and_reload() {
+        let dir = temp_dir("disk");
+
+        {
+            let cache = DiskCache::open(&dir, 10).unwrap();
+
+            cache.put(&key_of(&["1"]), b"1234").unwrap();
+            cache.put(&key_of(&["2"]), b"1234").unwrap();
+
+            //1 becomes most recently used
+            assert_eq!(cache.get(&key_of(&["1"])).unwrap(), b"1234");
+
+            cache.put(&key_of(&["3"]), b"1234").unwrap();
+
+            assert_eq!(cache.size(), 8);
+            assert!(cache.get(&key_of(&["2"])).is_none());
+            assert!(cache.get(&key_of(&["1"])).is_some());
+            assert!(cache.get(&key_of(&["3"])).is_some());
+
+            //larger than whole cache, ignored
+            cache.put(&key_of(&["4"]), &[0; 11]).unwrap();
+            assert!(cache.get(&key_of(&["4"])).is_none());
+        }
+
+        let reopened = DiskCache::open(&dir, 10).unwrap();
+        assert_eq!(reopened.size(), 8);
+        assert_eq!(reopened.get(&key_of(&["3"])).unwrap(), b"1234");
+
+        let shrinked = DiskCache::open(&dir, 4).unwrap();
+        assert_eq!(shrinked.size(), 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reload_order() {
+        let dir = temp_dir("disk-order");
+
+        {
+            let cache = DiskCache::open(&dir, 8).unwrap();
+            cache.put(&key_of(&["1"]), b"1234").unwrap();
+            cache.put(&key_of(&["2"]), b"1234").unwrap();
+
+            //1 written long before 2
+            let ago = |secs| {
+                FileTime::from_system_time(SystemTime::now() - std::time::Duration::from_secs(secs))
+            };
+            filetime::set_file_mtime(cache.path_of(&key_of(&["1"])), ago(20)).unwrap();
+            filetime::set_file_mtime(cache.path_of(&key_of(&["2"])), ago(10)).unwrap();
+
+            //but used last
+            assert!(cache.get(&key_of(&["1"])).is_some());
+        }
+
+        let shrinked = DiskCache::open(&dir, 4).unwrap();
+        assert!(shrinked.get(&key_of(&["1"])).is_some());
+        assert!(shrinked.get(&key_of(&["2"])).is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_put_from() {
+        let dir = temp_dir("disk-from");
+        let cache = DiskCache::open(&dir, 10).unwrap();
+
+        let path = cache
+            .put_from(&key_of(&["1"]), &mut &b"1234"[..], 4)
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"1234");
+        assert_eq!(cache.locate(&key_of(&["1"])), Some(path));
+
+        //over max, or over limit of whole cache
+        assert!(cache
+            .put_from(&key_of(&["2"]), &mut &b"12345"[..], 4)
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .put_from(&key_of(&["3"]), &mut &[0; 11][..], 100)
+            .unwrap()
+            .is_none());
+        assert!(cache.locate(&key_of(&["2"])).is_none());
+        assert_eq!(cache.size(), 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pin() {
+        let dir = temp_dir("disk-pin");
+        let cache = DiskCache::open(&dir, 8).unwrap();
+
+        let pin = cache.pin(&key_of(&["1"]));
+        cache.put(&key_of(&["1"]), b"1234").unwrap();
+        cache.put(&key_of(&["2"]), b"1234").unwrap();
+
+        //least used one is pinned, next one goes
+        cache.put(&key_of(&["3"]), b"1234").unwrap();
+        assert!(cache.path_of(&key_of(&["1"])).is_file());
+        assert!(cache.locate(&key_of(&["2"])).is_none());
+
+        drop(pin);
+        cache.put(&key_of(&["4"]), b"1234").unwrap();
+        assert!(!cache.path_of(&key_of(&["1"])).is_file());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
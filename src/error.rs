@@ -0,0 +1,165 @@
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+
+//failures of cache and fs. server picks status code by variant, so
+//each one is a different answer to client
+#[derive(Debug)]
+pub enum Error {
+    //request itself is malformed, like absolute path
+    BadRequest(String),
+    //outside of allowed scope, or beyond root
+    Forbidden(String),
+    NotFound(String),
+    //file is there but can not be decoded, like corrupt archive
+    Unsupported(String),
+    //can not be served for now, like archive evicted on every retry
+    Unavailable(String),
+    //entry is encrypted and no known password opens it
+    PasswordRequired(String),
+    //extracting would cross configured limits, like zip bomb
+    TooLarge(String),
+    //other io failure, with what was being done
+    Io(String, std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(context: String, e: std::io::Error) -> Error {
+        match e.kind() {
+            ErrorKind::NotFound => Error::NotFound(format!("{}: {}", context, e)),
+            ErrorKind::PermissionDenied => Error::Forbidden(format!("{}: {}", context, e)),
+            _ => Error::Io(context, e),
+        }
+    }
+
+    //short text for client, cause is only logged
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::BadRequest(_) => "bad request",
+            Error::Forbidden(_) => "not allowed",
+            Error::NotFound(_) => "nothing",
+            Error::Unsupported(_) => "unsupported content",
+            Error::Unavailable(_) => "busy, try again later",
+            Error::PasswordRequired(_) => "password required",
+            Error::TooLarge(_) => "archive exceeds extraction limits",
+            Error::Io(..) => "internal error",
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::BadRequest(msg)
+            | Error::Forbidden(msg)
+            | Error::NotFound(msg)
+            | Error::Unsupported(msg)
+            | Error::Unavailable(msg)
+            | Error::PasswordRequired(msg)
+            | Error::TooLarge(msg) => write!(f, "{}", msg),
+            Error::Io(context, e) => write!(f, "{}: {}", context, e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+//io errors without context, kind decides variant. error converted
+//from this type comes back as it was
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        if e.get_ref().map_or(false, |inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+
+        let msg = e.to_string();
+        match e.kind() {
+            ErrorKind::NotFound => Error::NotFound(msg),
+            ErrorKind::PermissionDenied => Error::Forbidden(msg),
+            ErrorKind::InvalidInput => Error::BadRequest(msg),
+            ErrorKind::InvalidData => Error::Unsupported(msg),
+            _ => Error::Io(String::from("io"), e),
+        }
+    }
+}
+
+//for callers still on io::Result, kind is kept as close as possible
+//and variant is carried inside for the way back
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> std::io::Error {
+        let kind = match e {
+            Error::BadRequest(_) => ErrorKind::InvalidInput,
+            Error::Forbidden(_) => ErrorKind::PermissionDenied,
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::Unsupported(_) => ErrorKind::InvalidData,
+            Error::Unavailable(_) => ErrorKind::Other,
+            Error::PasswordRequired(_) => ErrorKind::PermissionDenied,
+            Error::TooLarge(_) => ErrorKind::InvalidData,
+            Error::Io(_, ref e) => e.kind(),
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_io_round_trip() {
+        let denied = std::io::Error::new(ErrorKind::PermissionDenied, "no");
+        match Error::from(denied) {
+            Error::Forbidden(_) => {}
+            other => panic!("{:?}", other),
+        }
+
+        let broken = Error::io(
+            "read a.zip".to_owned(),
+            std::io::Error::new(ErrorKind::UnexpectedEof, "eof"),
+        );
+        assert_eq!(broken.to_string(), "read a.zip: eof");
+        assert_eq!(broken.reason(), "internal error");
+
+        let back: std::io::Error = broken.into();
+        assert_eq!(back.kind(), ErrorKind::UnexpectedEof);
+
+        let missing = Error::io(
+            "open x".to_owned(),
+            std::io::Error::new(ErrorKind::NotFound, "gone"),
+        );
+        let back: std::io::Error = missing.into();
+        assert_eq!(back.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_variant_round_trip() {
+        let busy: std::io::Error = Error::Unavailable("evicted".to_owned()).into();
+        assert_eq!(busy.to_string(), "evicted");
+        match Error::from(busy) {
+            Error::Unavailable(msg) => assert_eq!(msg, "evicted"),
+            other => panic!("{:?}", other),
+        }
+
+        let big: std::io::Error = Error::TooLarge("bomb".to_owned()).into();
+        match Error::from(big) {
+            Error::TooLarge(_) => {}
+            other => panic!("{:?}", other),
+        }
+
+        let locked: std::io::Error = Error::PasswordRequired("a.zip".to_owned()).into();
+        assert_eq!(locked.kind(), ErrorKind::PermissionDenied);
+        match Error::from(locked) {
+            Error::PasswordRequired(_) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
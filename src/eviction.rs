@@ -0,0 +1,423 @@
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::str::FromStr;
+
+//gdsf priority is frequency * scale / size, so it stays integer
+const GDSF_SCALE: u64 = 1 << 32;
+
+//decides which entry of WeightedCache goes first. shared with readers
+//of cache, which only peek
+pub trait Policy<K>: Send + Sync {
+    fn insert(&mut self, key: &K, size: usize);
+    fn touch(&mut self, key: &K);
+    fn remove(&mut self, key: &K);
+    //next one to evict, never except
+    fn victim(&self, except: &K) -> Option<K>;
+
+    //victim actually dropped, policies with history may remember it
+    fn evict(&mut self, key: &K) {
+        self.remove(key);
+    }
+}
+
+//keys ordered by rank, lowest first
+struct Ranked<K: Ord, R: Ord> {
+    ranks: HashMap<K, R>,
+    order: BTreeSet<(R, K)>,
+}
+
+impl<K: Ord + Hash + Clone, R: Ord + Copy> Ranked<K, R> {
+    fn new() -> Ranked<K, R> {
+        Ranked {
+            ranks: HashMap::new(),
+            order: BTreeSet::new(),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<R> {
+        self.ranks.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &K, rank: R) {
+        if let Some(old) = self.ranks.insert(key.clone(), rank) {
+            self.order.remove(&(old, key.clone()));
+        }
+        self.order.insert((rank, key.clone()));
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(old) = self.ranks.remove(key) {
+            self.order.remove(&(old, key.clone()));
+        }
+    }
+
+    fn first_except(&self, except: &K) -> Option<K> {
+        self.order
+            .iter()
+            .map(|(_, k)| k)
+            .find(|k| *k != except)
+            .cloned()
+    }
+}
+
+//least recently used
+pub struct Lru<K: Ord> {
+    tick: u64,
+    ranked: Ranked<K, u64>,
+}
+
+impl<K: Ord + Hash + Clone> Lru<K> {
+    pub fn new() -> Lru<K> {
+        Lru {
+            tick: 0,
+            ranked: Ranked::new(),
+        }
+    }
+}
+
+impl<K: Ord + Hash + Clone + Send + Sync> Policy<K> for Lru<K> {
+    fn insert(&mut self, key: &K, _size: usize) {
+        self.tick += 1;
+        self.ranked.set(key, self.tick);
+    }
+
+    fn touch(&mut self, key: &K) {
+        if self.ranked.get(key).is_some() {
+            self.insert(key, 0);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.ranked.remove(key);
+    }
+
+    fn victim(&self, except: &K) -> Option<K> {
+        self.ranked.first_except(except)
+    }
+}
+
+//least frequently used, older one first when used equally
+pub struct Lfu<K: Ord> {
+    tick: u64,
+    ranked: Ranked<K, (u64, u64)>,
+}
+
+impl<K: Ord + Hash + Clone> Lfu<K> {
+    pub fn new() -> Lfu<K> {
+        Lfu {
+            tick: 0,
+            ranked: Ranked::new(),
+        }
+    }
+}
+
+impl<K: Ord + Hash + Clone + Send + Sync> Policy<K> for Lfu<K> {
+    fn insert(&mut self, key: &K, _size: usize) {
+        self.tick += 1;
+        self.ranked.set(key, (1, self.tick));
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some((freq, _)) = self.ranked.get(key) {
+            self.tick += 1;
+            self.ranked.set(key, (freq + 1, self.tick));
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.ranked.remove(key);
+    }
+
+    fn victim(&self, except: &K) -> Option<K> {
+        self.ranked.first_except(except)
+    }
+}
+
+//greedy dual size frequency: small and often read entries stay,
+//priority of evicted one is inherited (clock) so old entries age out
+pub struct Gdsf<K: Ord> {
+    clock: u64,
+    tick: u64,
+    //frequency and size
+    stats: HashMap<K, (u64, usize)>,
+    ranked: Ranked<K, (u64, u64)>,
+}
+
+impl<K: Ord + Hash + Clone> Gdsf<K> {
+    pub fn new() -> Gdsf<K> {
+        Gdsf {
+            clock: 0,
+            tick: 0,
+            stats: HashMap::new(),
+            ranked: Ranked::new(),
+        }
+    }
+
+    fn rank(&mut self, key: &K) {
+        let (freq, size) = self.stats[key];
+        let priority = self
+            .clock
+            .saturating_add(freq.saturating_mul(GDSF_SCALE) / std::cmp::max(size, 1) as u64);
+
+        self.tick += 1;
+        self.ranked.set(key, (priority, self.tick));
+    }
+}
+
+impl<K: Ord + Hash + Clone + Send + Sync> Policy<K> for Gdsf<K> {
+    fn insert(&mut self, key: &K, size: usize) {
+        self.stats.insert(key.clone(), (1, size));
+        self.rank(key);
+    }
+
+    fn touch(&mut self, key: &K) {
+        match self.stats.get_mut(key) {
+            None => return,
+            Some(stat) => stat.0 += 1,
+        }
+        self.rank(key);
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.stats.remove(key);
+        self.ranked.remove(key);
+    }
+
+    fn victim(&self, except: &K) -> Option<K> {
+        self.ranked.first_except(except)
+    }
+
+    fn evict(&mut self, key: &K) {
+        if let Some((priority, _)) = self.ranked.get(key) {
+            self.clock = std::cmp::max(self.clock, priority);
+        }
+        self.remove(key);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eviction {
+    Lru,
+    Lfu,
+    Gdsf,
+}
+
+impl Default for Eviction {
+    fn default() -> Eviction {
+        Eviction::Lru
+    }
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Eviction, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "lru" => Ok(Eviction::Lru),
+            "lfu" => Ok(Eviction::Lfu),
+            "gdsf" => Ok(Eviction::Gdsf),
+            other => Err(format!(
+                "unknown eviction policy {:?}, expect lru, lfu or gdsf",
+                other
+            )),
+        }
+    }
+}
+
+impl Eviction {
+    pub fn build<K: Ord + Hash + Clone + Send + Sync + 'static>(self) -> Box<Policy<K>> {
+        match self {
+            Eviction::Lru => Box::new(Lru::new()),
+            Eviction::Lfu => Box::new(Lfu::new()),
+            Eviction::Gdsf => Box::new(Gdsf::new()),
+        }
+    }
+}
+
+//byte weighted cache, limit is checked by caller through recycle so
+//it can be shared with other users of memory
+pub struct WeightedCache<K, V> {
+    //value and its weight in bytes
+    entries: HashMap<K, (V, usize)>,
+    size: usize,
+    policy: Box<Policy<K>>,
+}
+
+impl<K: Ord + Hash + Clone + Send + Sync + 'static, V> WeightedCache<K, V> {
+    pub fn new(eviction: Eviction) -> WeightedCache<K, V> {
+        WeightedCache {
+            entries: HashMap::new(),
+            size: 0,
+            policy: eviction.build(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    //replaces existing value
+    pub fn put(&mut self, key: K, value: V, weight: usize) {
+        self.remove(&key);
+
+        self.policy.insert(&key, weight);
+        self.size += weight;
+        self.entries.insert(key, (value, weight));
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let ret = self.entries.get(key);
+        if ret.is_some() {
+            self.policy.touch(key);
+        }
+        ret.map(|(v, _)| v)
+    }
+
+    //without counting as access
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(v, _)| v)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, weight) = self.entries.remove(key)?;
+
+        self.policy.remove(key);
+
+        debug_assert!(self.size >= weight);
+        self.size -= weight;
+
+        Some(value)
+    }
+
+    //evict until size fits limit, preserved key is never evicted
+    pub fn recycle(&mut self, limit: usize, preserved: &K) {
+        while self.size > limit {
+            let victim = match self.policy.victim(preserved) {
+                None => return,
+                Some(victim) => victim,
+            };
+
+            self.policy.evict(&victim);
+
+            let (_, weight) = self
+                .entries
+                .remove(&victim)
+                .expect("policy tracks key not in cache");
+            self.size -= weight;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const POLICIES: [Eviction; 3] = [Eviction::Lru, Eviction::Lfu, Eviction::Gdsf];
+
+    //xorshift, tests must not depend on extra crates
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    fn check(cache: &WeightedCache<u64, Vec<u8>>) {
+        let sum: usize = cache.entries.values().map(|(v, _)| v.len()).sum();
+        assert_eq!(cache.size(), sum);
+
+        //policy never picks key not cached, and has one if any cached
+        match cache.policy.victim(&u64::max_value()) {
+            None => assert_eq!(cache.len(), 0),
+            Some(victim) => assert!(cache.contains_key(&victim)),
+        }
+    }
+
+    #[test]
+    fn test_size_matches_property() {
+        for eviction in POLICIES.iter() {
+            for seed in 1..50 {
+                let mut rng = Rng(seed * 7919);
+                let mut cache = WeightedCache::new(*eviction);
+
+                for _ in 0..500 {
+                    let key = rng.next(32);
+
+                    match rng.next(5) {
+                        0 | 1 => {
+                            let value = vec![0; rng.next(100) as usize];
+                            let weight = value.len();
+                            cache.put(key, value, weight);
+                        }
+                        2 => {
+                            cache.get(&key);
+                        }
+                        3 => {
+                            cache.remove(&key);
+                        }
+                        _ => {
+                            let limit = rng.next(1000) as usize;
+                            cache.recycle(limit, &key);
+
+                            let preserved = cache.peek(&key).map_or(0, |v| v.len());
+                            assert!(cache.size() <= std::cmp::max(limit, preserved));
+                        }
+                    }
+
+                    check(&cache);
+                }
+
+                //drain through policy, only empty ones may stay
+                cache.recycle(0, &u64::max_value());
+                assert_eq!(cache.size(), 0);
+                assert!(cache.entries.values().all(|(v, _)| v.is_empty()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_policies() {
+        let fill = |eviction: Eviction| {
+            let mut cache = WeightedCache::new(eviction);
+            cache.put(1u64, (), 10);
+            cache.put(2, (), 1000);
+            cache.put(3, (), 10);
+            cache.get(&1);
+            cache.get(&1);
+            cache.get(&2);
+            cache
+        };
+
+        let mut lru = fill(Eviction::Lru);
+        lru.recycle(1010, &0);
+        assert!(!lru.contains_key(&3));
+
+        let mut lfu = fill(Eviction::Lfu);
+        lfu.recycle(1010, &0);
+        assert!(!lfu.contains_key(&3));
+        lfu.recycle(20, &0);
+        assert!(lfu.contains_key(&1));
+
+        //large one goes first even if read more than 3
+        let mut gdsf = fill(Eviction::Gdsf);
+        gdsf.recycle(1010, &0);
+        assert!(!gdsf.contains_key(&2));
+        assert_eq!(gdsf.size(), 20);
+
+        assert_eq!("GDSF".parse::<Eviction>().unwrap(), Eviction::Gdsf);
+        assert!("fifo".parse::<Eviction>().is_err());
+    }
+}
@@ -1,13 +1,30 @@
 extern crate relative_path;
 extern crate tree_magic;
+use super::archive_index::IndexedEntry;
+use super::auth::{normalize, Scope, Visibility};
 use super::cache::{
     is_archive, join_may_empty, ArchiveCache, FileOrMem, NodeContents, PathU8, RevPathWalker,
 };
+use super::charset::{from_os, from_os_path, to_os_path};
+use super::config::mount_name;
+use super::error;
+use super::limits::{Budget, Capped, Limits};
+use super::listing::{image_dimensions, mime_from_name, EntryInfo, EntryKind};
+use super::password::Keyring;
+use super::sort::{natural_cmp, sorted_names};
+use super::tarball::PlainFile;
+use super::zipcrypto;
 use relative_path::RelativePathBuf;
-
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
-use std::sync::{mpsc::channel, Mutex};
-use std::time::Duration;
+use unarr::{ArArchive, ArStream};
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{
+    mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender},
+    Arc, RwLock,
+};
+use std::time::{Duration, SystemTime};
 use std::{fs::File, io::BufReader};
 
 use std::io::{Error, ErrorKind, Read, Write};
@@ -15,16 +32,90 @@ use std::io::{Error, ErrorKind, Read, Write};
 pub const DEFAULT_MEM_LIMIT: usize = 256 * 1024 * 1024;
 pub const DEFAULT_ARCHIVE_LIMIT: usize = 20;
 
+//archive entries at least this large are streamed instead of cached
+pub const STREAM_ENTRY_MIN: usize = 16 * 1024 * 1024;
+pub const STREAM_CHUNK: usize = 64 * 1024;
+//chunks buffered ahead of slow client
+pub const STREAM_QUEUE: usize = 4;
+
+//notify merges events of same file within this delay
+const WATCH_DELAY: Duration = Duration::from_secs(2);
+//how often watch thread checks for shutdown
+const WATCH_POLL: Duration = Duration::from_millis(200);
+
 pub struct Fs {
-    root: std::path::PathBuf,
+    //(mount name, absolute root). mount name is unused for single root
+    roots: Vec<(String, std::path::PathBuf)>,
 }
 
 unsafe impl Send for Fs {}
 unsafe impl Sync for Fs {}
 
-const MIME_TEXT: &str = "Content-Type: text/plain; charset=utf-8";
+const MIME_TEXT: &str = "text/plain; charset=utf-8";
+
+//what was written by Fs::read
+pub struct Meta {
+    pub mime: String,
+    pub is_dir: bool,
+    //mtime of the file, or of the archive file on disk holding the entry
+    pub mtime: Option<SystemTime>,
+    //of zip entry, tells its content apart without archive mtime
+    pub crc: Option<u32>,
+}
+
+//body of a read. large contents are not buffered as a whole
+pub enum Content {
+    Mem(Vec<u8>),
+    File(File, u64),
+    //entry streamed out of archive, with declared size
+    Entry(Box<Read + Send>, u64),
+}
+
+impl Content {
+    pub fn len(&self) -> u64 {
+        match self {
+            Content::Mem(bin) => bin.len() as u64,
+            Content::File(_, len) => *len,
+            Content::Entry(_, len) => *len,
+        }
+    }
+}
+
+//contents behind path, before it is described by Meta
+struct Found {
+    content: Content,
+    mime: String,
+    is_dir: bool,
+}
+
+impl Found {
+    fn file(content: Content, mime: String) -> Found {
+        Found {
+            content,
+            mime,
+            is_dir: false,
+        }
+    }
+
+    fn listing(names: Vec<u8>) -> Found {
+        Found {
+            content: Content::Mem(names),
+            mime: MIME_TEXT.to_owned(),
+            is_dir: true,
+        }
+    }
 
-impl<'a> NodeContents<'a> {
+    fn from_node(node: &NodeContents) -> error::Result<Found> {
+        let mut bin = Vec::new();
+        let mime = node.write_to(&mut bin)?;
+        Ok(match node {
+            NodeContents::File(_) => Found::file(Content::Mem(bin), mime),
+            NodeContents::Dir(_) => Found::listing(bin),
+        })
+    }
+}
+
+impl NodeContents {
     pub fn write_to(&self, w: &mut Write) -> std::io::Result<String> {
         match self {
             NodeContents::File(bin) => {
@@ -42,71 +133,334 @@ impl<'a> NodeContents<'a> {
     }
 }
 
-impl Fs {
-    pub fn start_watch(&self, cache: &Mutex<ArchiveCache>) {
-        // Create a channel to receive the events.
-        let (tx, rx) = channel();
+//reject path which may escape from roots
+fn check_path(path: &PathU8) -> error::Result<PathU8> {
+    if path.is_absolute() {
+        trace!("access abs path {:?} forbidden", path);
+        return Err(error::Error::BadRequest(format!(
+            "can not access absolute path {:?}",
+            path
+        )));
+    }
+
+    let canonicalized = RelativePathBuf::new().to_path(path);
+
+    trace!("access {:?}", canonicalized);
+
+    //. and .. are resolved before anything is opened, so scope is
+    //checked on the path accessed and nothing on the way is touched
+    normalize(&canonicalized)
+        .ok_or_else(|| error::Error::Forbidden(format!("cannot access beyond root {:?}", path)))
+}
 
-        // Automatically select the best implementation for your platform.
-        // You can also access each implementation directly e.g. INotifyWatcher.
-        let watcher: Result<RecommendedWatcher, notify::Error> =
-            Watcher::new(tx, Duration::from_secs(2));
+//receives chunks produced by another thread
+struct ChannelReader {
+    rx: Receiver<std::io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
 
-        if watcher.is_err() {
-            return;
+impl ChannelReader {
+    //make sure some data buffered, empty slice means eof
+    fn fill(&mut self) -> std::io::Result<&[u8]> {
+        while self.pos == self.buf.len() {
+            match self.rx.recv() {
+                //sender dropped, all sent
+                Err(_) => return Ok(&[]),
+                Ok(chunk) => {
+                    self.buf = chunk?;
+                    self.pos = 0;
+                }
+            }
         }
+        Ok(&self.buf[self.pos..])
+    }
+}
 
-        // Add a path to be watched. All files and directories at that path and
-        // below will be monitored for changes.
-        let res = watcher
-            .unwrap()
-            .watch("/home/comicfans/Downloads", RecursiveMode::Recursive);
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let n = {
+            let avail = self.fill()?;
+            let n = std::cmp::min(avail.len(), out.len());
+            out[..n].copy_from_slice(&avail[..n]);
+            n
+        };
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+//most bytes entry of archive on disk may take
+fn entry_cap(
+    archive_path: &PathU8,
+    name: &str,
+    declared: usize,
+    limits: Limits,
+) -> error::Result<usize> {
+    let archive_size = std::fs::metadata(archive_path)
+        .map_err(|e| error::Error::io(format!("stat {:?}", archive_path), e))?
+        .len() as usize;
+
+    Budget::new(limits).allow(name, declared, archive_size)
+}
 
-        if res.is_err() {
-            return;
+//read entry of archive on disk in a separated thread, so caller
+//gets bounded memory usage no matter how large the entry is. thread
+//holds archive, so spooled one stays until it is done
+fn stream_entry(archive: PlainFile, entry: IndexedEntry, limits: Limits) -> error::Result<Found> {
+    let archive_path = archive.path.clone();
+    trace!("stream {:?} in {:?}", entry.name, archive_path);
+
+    let cap = entry_cap(&archive_path, &entry.name, entry.size, limits)?;
+
+    let (tx, rx) = sync_channel(STREAM_QUEUE);
+
+    let context = format!("stream {:?} in {:?}", entry.name, archive_path);
+    let size = entry.size;
+    let name = entry.name.clone();
+
+    std::thread::spawn(move || {
+        let res = (|| -> std::io::Result<()> {
+            let mut ar = ArArchive::new(ArStream::from_file(&archive.path)?, None)?;
+
+            //located by key, decoded name may differ from the one
+            //archive library gives
+            let entry = ar.iter().find(|e| e.name() == entry.key).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} disappeared from {:?}", entry.name, archive_path),
+                )
+            })?;
+
+            //declared size may lie, stop at what limits allow
+            let mut reader = Capped::new(ar.reader_for(&entry)?, cap, &name);
+
+            loop {
+                let mut chunk = vec![0; STREAM_CHUNK];
+                let n = reader.read(&mut chunk)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                chunk.truncate(n);
+                if tx.send(Ok(chunk)).is_err() {
+                    //reader gone
+                    return Ok(());
+                }
+            }
+        })();
+
+        if let Err(e) = res {
+            let _ = tx.send(Err(e));
         }
+    });
+
+    let mut reader = ChannelReader {
+        rx,
+        buf: Vec::new(),
+        pos: 0,
+    };
 
-        // This is a simple loop, but you may want to use more complex logic here,
-        // for example to handle I/O.
-        loop {
-            match rx.recv() {
-                Ok(event) => {
-                    let path = PathU8::new();
+    let mime = tree_magic::from_u8(reader.fill().map_err(|e| error::Error::io(context, e))?);
 
-                    let res = path.strip_prefix(self.root.clone());
+    Ok(Found::file(
+        Content::Entry(Box::new(reader), size as u64),
+        mime,
+    ))
+}
 
-                    if let Ok(rel) = res {
-                        let lock = cache.lock();
-                        lock.unwrap().invalid_path(&PathU8::from(rel));
+//stops watching and joins handler thread on drop
+pub struct Watch {
+    _watcher: RecommendedWatcher,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+//virtual path of file on disk, None if not under any root
+fn to_virtual(roots: &[(String, PathBuf)], physical: &std::path::Path) -> Option<PathU8> {
+    for (name, root) in roots {
+        if let Ok(rel) = physical.strip_prefix(root) {
+            //single root has empty name
+            return Some(PathU8::from(name).join(from_os_path(rel)));
+        }
+    }
+    None
+}
+
+//virtual paths whose cache should be dropped for event
+fn changed_paths(roots: &[(String, PathBuf)], event: DebouncedEvent) -> Vec<PathU8> {
+    let physical = match event {
+        DebouncedEvent::Create(p)
+        | DebouncedEvent::Write(p)
+        | DebouncedEvent::Remove(p)
+        | DebouncedEvent::Chmod(p)
+        | DebouncedEvent::NoticeWrite(p)
+        | DebouncedEvent::NoticeRemove(p) => vec![p],
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        //events may be lost, nothing can be trusted
+        DebouncedEvent::Rescan => return vec![PathU8::new()],
+        DebouncedEvent::Error(e, p) => {
+            warn!("watch error {:?} on {:?}", e, p);
+            p.into_iter().collect()
+        }
+    };
+
+    physical
+        .iter()
+        .filter_map(|p| to_virtual(roots, p))
+        .collect()
+}
+
+impl Fs {
+    //invalid cached archives when files under roots change, until
+    //returned Watch is dropped. changed paths are also sent to rescan
+    pub fn start_watch(
+        &self,
+        cache: Arc<RwLock<ArchiveCache>>,
+        rescan: Option<Sender<PathU8>>,
+    ) -> std::io::Result<Watch> {
+        let (tx, rx) = channel();
+
+        let mut watcher: RecommendedWatcher = Watcher::new(tx, WATCH_DELAY)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("can not watch: {:?}", e)))?;
+
+        for (_, root) in self.roots.iter() {
+            watcher.watch(root, RecursiveMode::Recursive).map_err(|e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("can not watch {:?}: {:?}", root, e),
+                )
+            })?;
+            info!("watching {:?}", root);
+        }
+
+        let roots = self.roots.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_t = stop.clone();
+
+        let thread = std::thread::spawn(move || {
+            while !stop_t.load(Ordering::SeqCst) {
+                let event = match rx.recv_timeout(WATCH_POLL) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+
+                trace!("watch event {:?}", event);
+
+                let changed = changed_paths(&roots, event);
+
+                if changed.is_empty() {
+                    continue;
+                }
+
+                {
+                    let mut lock = cache.write().unwrap();
+                    for path in changed.iter() {
+                        lock.invalid_path(path);
+                    }
+                }
+
+                if let Some(rescan) = rescan.as_ref() {
+                    for path in changed {
+                        let _ = rescan.send(path);
                     }
                 }
-                Err(e) => println!("watch error: {:?}", e),
             }
-        }
+
+            debug!("watch thread exit");
+        });
+
+        Ok(Watch {
+            _watcher: watcher,
+            stop,
+            thread: Some(thread),
+        })
     }
 
     pub fn new(path: &PathU8) -> std::io::Result<Fs> {
-        debug!("path {:?}", path);
-        let abs_root = std::fs::canonicalize(path)?;
+        Fs::with_roots(std::slice::from_ref(path))
+    }
+
+    //single root is served as is, multiple roots are served as
+    //virtual top level dirs named by their last component
+    pub fn with_roots(paths: &[PathU8]) -> std::io::Result<Fs> {
+        if paths.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "no root given"));
+        }
 
-        debug!("fs normalized root {:?}", abs_root);
+        let mut roots = Vec::new();
 
-        let ret = Fs { root: abs_root };
+        for path in paths {
+            debug!("path {:?}", path);
+            let abs_root = std::fs::canonicalize(path)?;
+
+            debug!("fs normalized root {:?}", abs_root);
+
+            let name = if paths.len() == 1 {
+                String::new()
+            } else {
+                mount_name(path)?
+            };
+
+            if roots.iter().any(|(n, _)| *n == name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("root name {:?} used more than once", name),
+                ));
+            }
+
+            roots.push((name, abs_root));
+        }
 
         //access root . root is special since it can be virtual root of all
         //partition driver under windows
 
-        Ok(ret)
+        Ok(Fs { roots })
+    }
+
+    //split virtual path into (absolute root, path relative to that root)
+    //returns None when path is the virtual dir holding multiple roots
+    fn resolve(&self, path: &PathU8) -> error::Result<Option<(&std::path::PathBuf, PathU8)>> {
+        if self.roots.len() == 1 {
+            return Ok(Some((&self.roots[0].1, path.clone())));
+        }
+
+        let mut comps = path.iter();
+
+        let first = match comps.next() {
+            None => return Ok(None),
+            Some(first) => first,
+        };
+
+        for (name, root) in self.roots.iter() {
+            if first == name.as_str() {
+                return Ok(Some((root, comps.collect())));
+            }
+        }
+
+        Err(error::Error::NotFound(format!(
+            "{:?} is not a served root",
+            first
+        )))
     }
 
     fn try_in_archive(
         &self,
-        cache: &Mutex<ArchiveCache>,
+        cache: &RwLock<ArchiveCache>,
         virtual_path: &PathU8,
         archive_path: &PathU8,
         left: &PathU8,
-        w: &mut Write,
-    ) -> std::io::Result<String> {
+        keys: Keyring,
+    ) -> error::Result<Found> {
         trace!(
             "try in archive {:?}, as virtual_path {:?}, left {:?}",
             archive_path,
@@ -114,42 +468,143 @@ impl Fs {
             left
         );
 
-        let mut lock = cache.lock().unwrap();
-
-        let res = lock.set_archive(virtual_path, &archive_path)?;
+        let res = ArchiveCache::open_archive(cache, virtual_path, &archive_path)?;
 
-        if left.to_str().unwrap().is_empty() {
+        if left.as_os_str().is_empty() {
             trace!("no left, use archive {:?} result", virtual_path);
-            return res.write_to(w);
+            return Found::from_node(&res);
         }
 
-        let result = lock.get(&join_may_empty(virtual_path, left))?;
+        let (entry, limit, limits, file) = {
+            let lock = cache.read().unwrap();
+            (
+                lock.entry_of(virtual_path, left),
+                lock.mem_limit(),
+                lock.limits(),
+                lock.file_of(virtual_path),
+            )
+        };
 
-        result.write_to(w)
+        let read = match (&entry, file) {
+            //too large to keep in cache, read it again from disk. tarball
+            //unpacked in memory has no plain file to read
+            (Some(entry), Some(file)) if entry.size >= STREAM_ENTRY_MIN || entry.size > limit => {
+                stream_entry(file, entry.clone(), limits)
+            }
+            _ => ArchiveCache::load_in(cache, virtual_path, archive_path, left)
+                .and_then(|result| Found::from_node(&result)),
+        };
+
+        //only look for encryption once archive library failed
+        match (read, entry) {
+            (Err(e), Some(entry)) => match entry.offset {
+                Some(offset) if zipcrypto::is_encrypted(archive_path, offset).unwrap_or(false) => {
+                    Fs::decrypt_entry(virtual_path, archive_path, &entry, offset, keys, limits)
+                }
+                _ => Err(e),
+            },
+            (read, _) => read,
+        }
     }
 
-    fn direct_file_access(&self, path: &PathU8, w: &mut Write) -> std::io::Result<String> {
-        trace!("access {:?} as direct file", path);
-        // is image file from filesystem, no need to cache
-        let file = File::open(path)?;
+    //encrypted zip entry, never cached since other users may not know
+    //the password
+    fn decrypt_entry(
+        virtual_path: &PathU8,
+        archive_path: &PathU8,
+        entry: &IndexedEntry,
+        offset: i64,
+        keys: Keyring,
+        limits: Limits,
+    ) -> error::Result<Found> {
+        let cap = entry_cap(archive_path, &entry.name, entry.size, limits)?;
+
+        for password in keys.candidates(virtual_path) {
+            if let Some(plain) = zipcrypto::read(archive_path, offset, password.as_bytes(), cap)? {
+                let mime = tree_magic::from_u8(&plain);
+                return Ok(Found::file(Content::Mem(plain), mime));
+            }
+        }
+
+        Err(error::Error::PasswordRequired(format!(
+            "{:?} in {:?} is encrypted",
+            entry.name, virtual_path
+        )))
+    }
+
+    //Ok(false) if password does not open encrypted archive behind path.
+    //only zips on disk can be unlocked, entries of a zip nested in
+    //another archive are read by archive library, which can not decrypt
+    pub fn unlock(
+        &self,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        password: &str,
+    ) -> error::Result<bool> {
+        let file = match self.physical_file(path) {
+            Some(file) => file,
+            None if path
+                .ancestors()
+                .skip(1)
+                .any(|a| self.physical_file(&a.to_owned()).is_some()) =>
+            {
+                return Err(error::Error::Unsupported(format!(
+                    "{:?} is inside an archive, only archives on disk can be unlocked",
+                    path
+                )))
+            }
+            None => return Err(error::Error::NotFound(format!("{:?} is not a file", path))),
+        };
+
+        let offset = zipcrypto::first_encrypted(&file)?.ok_or_else(|| {
+            error::Error::BadRequest(format!("{:?} is not an encrypted zip", path))
+        })?;
 
-        let mut buf: Vec<u8> = Vec::new();
-        BufReader::new(file).read_to_end(&mut buf)?;
+        //declared size is not known here, limits still bound reading
+        let limits = cache.read().unwrap().limits();
+        let cap = entry_cap(&file, &path.to_string_lossy(), 0, limits)?;
 
-        w.write_all(&buf)?;
-        Ok(tree_magic::from_filepath(path))
+        Ok(zipcrypto::read(&file, offset, password.as_bytes(), cap)?.is_some())
+    }
+
+    fn direct_file_access(&self, path: &PathU8) -> error::Result<Found> {
+        trace!("access {:?} as direct file", path);
+        // is image file from filesystem, no need to cache
+        let file = File::open(path).map_err(|e| error::Error::io(format!("open {:?}", path), e))?;
+        let len = file
+            .metadata()
+            .map_err(|e| error::Error::io(format!("stat {:?}", path), e))?
+            .len();
+
+        Ok(Found::file(
+            Content::File(file, len),
+            tree_magic::from_filepath(path),
+        ))
     }
 
     fn try_access(
         &self,
-        cache: &Mutex<ArchiveCache>,
+        cache: &RwLock<ArchiveCache>,
         path: &PathU8,
-        w: &mut Write,
-    ) -> std::io::Result<String> {
+        keys: Keyring,
+    ) -> error::Result<Found> {
         trace!("try access {:?}", path);
 
-        for (i, (this_root, left)) in RevPathWalker::new(path).enumerate() {
-            let try_path = self.root.join(this_root);
+        let mut w = Vec::new();
+
+        let (root, rel) = match self.resolve(path)? {
+            None => {
+                for name in sorted_names(self.roots.iter().map(|(name, _)| name)) {
+                    w.write_all(name.as_bytes())?;
+                    w.write_all(b"\n")?;
+                }
+                return Ok(Found::listing(w));
+            }
+            Some(resolved) => resolved,
+        };
+
+        for (i, (this_root, left)) in RevPathWalker::new(&rel).enumerate() {
+            let try_path = root.join(to_os_path(&this_root));
 
             trace!("try {:?}, left {:?}", try_path, left);
 
@@ -171,18 +626,26 @@ impl Fs {
                     //path has comp left, but parent is a dir (not archive)
                     //so this path can not be a file inside archive
                     //(we already tried fullpath as file and it is not readable)
-                    return Err(Error::new(ErrorKind::NotFound, "path is not dir"));
+                    return Err(error::Error::NotFound(format!(
+                        "{:?} is under a file",
+                        path
+                    )));
                 }
                 //only first try to test if target is dir
                 //otherwise it must be archive + inner path
                 trace!("read {:?} as dir", try_path);
-                for entry in try_path.read_dir()? {
-                    let entry = entry?;
+                let list_failed = |e| error::Error::io(format!("list {:?}", try_path), e);
+
+                let mut names = Vec::new();
+                for entry in try_path.read_dir().map_err(list_failed)? {
+                    names.push(from_os(&entry.map_err(list_failed)?.file_name()));
+                }
 
-                    w.write_all(entry.file_name().into_string().unwrap().as_bytes())?;
+                for name in sorted_names(names.iter()) {
+                    w.write_all(name.as_bytes())?;
                     w.write_all(b"\n")?;
                 }
-                return Ok(MIME_TEXT.to_string());
+                return Ok(Found::listing(w));
             }
 
             // is a file (at previous time)
@@ -190,7 +653,7 @@ impl Fs {
             let is_archive = is_archive(&try_path, FileOrMem::Path(&try_path));
 
             if !is_archive {
-                return self.direct_file_access(&try_path, w);
+                return self.direct_file_access(&try_path);
             }
 
             let mut rel_to_archive = path.clone();
@@ -199,58 +662,308 @@ impl Fs {
                 rel_to_archive.pop();
             }
 
-            return self.try_in_archive(cache, &rel_to_archive, &try_path, &left, w);
+            return self.try_in_archive(cache, &rel_to_archive, &try_path, &left, keys);
         }
 
-        Err(Error::new(
-            ErrorKind::NotFound,
-            path.to_str().unwrap().to_owned() + "not matched in filesystem",
-        ))
+        Err(error::Error::NotFound(format!(
+            "{:?} not matched in filesystem",
+            path
+        )))
+    }
+
+    //first existing file or dir on disk along the path. for entries inside
+    //archive, this is the (outermost) archive file
+    fn physical_mtime(&self, path: &PathU8) -> Option<SystemTime> {
+        let (root, rel) = self.resolve(path).ok()??;
+
+        for (this_root, _) in RevPathWalker::new(&rel) {
+            if let Ok(attr) = root.join(to_os_path(&this_root)).metadata() {
+                return attr.modified().ok();
+            }
+        }
+
+        None
     }
 
+    //archive or other file on disk behind path, None for dirs and
+    //entries inside archive
+    pub fn physical_file(&self, path: &PathU8) -> Option<PathBuf> {
+        let path = check_path(path).ok()?;
+        let (root, rel) = self.resolve(&path).ok()??;
+
+        let file = root.join(to_os_path(&rel));
+        if file.is_file() {
+            Some(file)
+        } else {
+            None
+        }
+    }
+
+    fn describe_file(path: &std::path::Path, name: &str) -> std::io::Result<EntryInfo> {
+        let attr = path.metadata()?;
+
+        if attr.is_dir() {
+            let mut info = EntryInfo::new(name, EntryKind::Dir);
+            info.set_mtime(attr.modified().ok());
+            return Ok(info);
+        }
+
+        let as_path = PathU8::from(path);
+
+        let kind = if is_archive(&as_path, FileOrMem::Path(&as_path)) {
+            EntryKind::Archive
+        } else {
+            EntryKind::File
+        };
+
+        let mut info = EntryInfo::new(name, kind);
+        info.size = Some(attr.len());
+        info.set_mtime(attr.modified().ok());
+        //by name, listing must not read every file
+        info.mime = mime_from_name(name).map(String::from);
+
+        Ok(info)
+    }
+
+    //width and height of listed images, from their headers. only done
+    //when client asks. inside archive only entries already in memory
+    //are measured, nothing is extracted for it
+    pub fn add_dimensions(
+        &self,
+        cache: &RwLock<ArchiveCache>,
+        dir: &PathU8,
+        entries: &mut [EntryInfo],
+    ) {
+        //virtual top level only lists roots
+        let on_disk = match self.resolve(dir) {
+            Ok(Some((root, rel))) => Some(root.join(to_os_path(&rel))).filter(|p| p.is_dir()),
+            _ => return,
+        };
+
+        for entry in entries.iter_mut() {
+            let is_image = match entry.mime {
+                Some(ref mime) => mime.starts_with("image"),
+                None => false,
+            };
+            if entry.kind != EntryKind::File || !is_image {
+                continue;
+            }
+
+            let name = PathU8::from(entry.name.as_str());
+
+            let dims = match on_disk.as_ref() {
+                Some(dir) => File::open(dir.join(to_os_path(&name)))
+                    .ok()
+                    .and_then(|file| image_dimensions(BufReader::new(file))),
+                None => match cache.read().unwrap().quick_try(&join_may_empty(dir, &name)) {
+                    Some(NodeContents::File(bin)) => {
+                        image_dimensions(std::io::Cursor::new(&bin[..]))
+                    }
+                    _ => None,
+                },
+            };
+
+            entry.set_dimensions(dims);
+        }
+    }
+
+    //children of dir (on disk or inside archive) with metadata,
+    //in natural name order
+    pub fn list(
+        &self,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        scope: &Scope,
+    ) -> error::Result<Vec<EntryInfo>> {
+        let canonicalized = check_path(path)?;
+        let visibility = scope.check(&canonicalized)?;
+
+        let mut entries = self.list_unsorted(cache, &canonicalized)?;
+
+        if visibility == Visibility::Ancestor {
+            entries.retain(|e| scope.shows(&canonicalized, &e.name));
+        }
+
+        entries.sort_by(|a, b| natural_cmp(&a.name, &b.name));
+        Ok(entries)
+    }
+
+    fn list_unsorted(
+        &self,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+    ) -> error::Result<Vec<EntryInfo>> {
+        let resolved = match self.resolve(path)? {
+            None => {
+                let mut ret = Vec::new();
+                for (name, root) in self.roots.iter() {
+                    ret.push(Fs::describe_file(root, name)?);
+                }
+                return Ok(ret);
+            }
+            Some((root, rel)) => root.join(to_os_path(&rel)),
+        };
+
+        if resolved.is_dir() {
+            let list_failed = |e| error::Error::io(format!("list {:?}", resolved), e);
+
+            let mut ret = Vec::new();
+
+            for entry in resolved.read_dir().map_err(list_failed)? {
+                let entry = entry.map_err(list_failed)?;
+
+                let name = from_os(&entry.file_name());
+
+                match Fs::describe_file(&entry.path(), &name) {
+                    Ok(info) => ret.push(info),
+                    //broken link or no permission, still list it
+                    Err(_) => ret.push(EntryInfo::new(&name, EntryKind::File)),
+                }
+            }
+
+            return Ok(ret);
+        }
+
+        //inside archive, make sure it is loaded
+        let described = cache.write().unwrap().describe_dir(path);
+
+        let mut entries = match described {
+            Some(entries) => entries,
+            None => {
+                //scope is checked by list
+                let (_, meta) = self.open(cache, path, &Scope::All)?;
+
+                if !meta.is_dir {
+                    return Err(error::Error::BadRequest(format!("{:?} is not dir", path)));
+                }
+
+                cache
+                    .write()
+                    .unwrap()
+                    .describe_dir(path)
+                    .ok_or_else(|| error::Error::Unavailable(format!("{:?} evicted", path)))?
+            }
+        };
+
+        let mtime = self.physical_mtime(path);
+
+        for entry in entries.iter_mut() {
+            entry.set_mtime(mtime);
+        }
+
+        Ok(entries)
+    }
+
+    //mtime of path, see physical_mtime
+    pub fn mtime(&self, path: &PathU8) -> error::Result<Option<SystemTime>> {
+        let path = check_path(path)?;
+        Ok(self.physical_mtime(&path))
+    }
+
+    //read whole contents into writer, for in memory users.
+    //server should use open() to avoid buffering large files
     pub fn read<W: std::io::Write>(
         &self,
-        cache: &Mutex<ArchiveCache>,
+        cache: &RwLock<ArchiveCache>,
         path: &PathU8,
         writer: &mut W,
-    ) -> std::io::Result<String> {
-        if path.is_absolute() {
-            trace!("access abs path {:?} forbidden", path);
-            return Err(Error::new(
-                ErrorKind::NotFound,
-                "can not access absolute path".to_owned() + path.to_str().unwrap(),
-            ));
-        }
+        scope: &Scope,
+    ) -> error::Result<Meta> {
+        let (content, meta) = self.open(cache, path, scope)?;
+
+        let copied = match content {
+            Content::Mem(bin) => writer.write_all(&bin),
+            Content::File(file, _) => std::io::copy(&mut BufReader::new(file), writer).map(|_| ()),
+            Content::Entry(mut reader, _) => std::io::copy(&mut reader, writer).map(|_| ()),
+        };
 
-        let canonicalized = RelativePathBuf::new().to_path(path);
+        copied.map_err(|e| error::Error::io(format!("read {:?}", path), e))?;
 
-        trace!("access {:?}", canonicalized);
+        Ok(meta)
+    }
+
+    //Forbidden for path outside scope, before anything is read.
+    //listing of dir holding allowed subtrees only shows the way to them
+    pub fn open(
+        &self,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        scope: &Scope,
+    ) -> error::Result<(Content, Meta)> {
+        self.open_with_keys(cache, path, scope, Keyring::empty())
+    }
 
-        if canonicalized.starts_with("..") {
-            return Err(Error::new(
-                ErrorKind::PermissionDenied,
-                std::format!("cannot access beyond root {:?}", self.root),
-            ));
+    //as open, encrypted entries are tried with passwords in keys
+    pub fn open_with_keys(
+        &self,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        scope: &Scope,
+        keys: Keyring,
+    ) -> error::Result<(Content, Meta)> {
+        let canonicalized = check_path(path)?;
+        let visibility = scope.check(&canonicalized)?;
+
+        let found = self.open_contents(cache, &canonicalized, keys)?;
+        let mut content = found.content;
+
+        let meta = Meta {
+            mime: found.mime,
+            is_dir: found.is_dir,
+            mtime: self.physical_mtime(&canonicalized),
+            crc: cache.read().unwrap().crc_of(&canonicalized),
+        };
+
+        if visibility == Visibility::Ancestor {
+            let listing = match (meta.is_dir, &content) {
+                (true, Content::Mem(listing)) => String::from_utf8_lossy(listing).into_owned(),
+                _ => {
+                    return Err(error::Error::Forbidden(format!(
+                        "{:?} is not allowed",
+                        path
+                    )))
+                }
+            };
+
+            let mut shown = Vec::new();
+            for name in listing
+                .lines()
+                .filter(|name| scope.shows(&canonicalized, name))
+            {
+                shown.extend_from_slice(name.as_bytes());
+                shown.push(b'\n');
+            }
+            content = Content::Mem(shown);
         }
 
+        Ok((content, meta))
+    }
+
+    fn open_contents(
+        &self,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        keys: Keyring,
+    ) -> error::Result<Found> {
+        let canonicalized = check_path(path)?;
+
         //test cache first
 
-        {
-            let mut lock = cache.lock().unwrap();
-            let res = lock.get(&canonicalized);
+        //only lookup here, decompressing is done by try_in_archive
+        //which may decide to stream instead
+        let hit = cache.read().unwrap().quick_try(&canonicalized);
 
-            if let Ok(node_contents) = res {
-                trace!("cache hit for {:?}", path);
-                return node_contents.write_to(writer);
-            }
-            trace!("cache no hit for {:?}", path);
+        if let Some(node_contents) = hit {
+            trace!("cache hit for {:?}", path);
+            return Found::from_node(&node_contents);
         }
+        trace!("cache no hit for {:?}", path);
 
         //no such entry, read in fs. file or dir?
         //
         //
 
-        self.try_access(cache, path, writer)
+        self.try_access(cache, &canonicalized, keys)
     }
 }
 
@@ -258,14 +971,16 @@ impl Fs {
 mod tests {
 
     use super::*;
+    use crate::password::Passwords;
 
+    #[test]
     fn run2() {
         let f = Fs::new(&PathU8::from(std::path::PathBuf::from(env!(
             "CARGO_MANIFEST_DIR"
         ))))
         .unwrap();
 
-        let mut cache = Mutex::new(ArchiveCache::new(100, 100));
+        let cache = RwLock::new(ArchiveCache::new(100, 100));
 
         let mut c1 = std::io::Cursor::new(Vec::new());
 
@@ -273,11 +988,445 @@ mod tests {
             .read(
                 &cache,
                 &PathU8::from("tests/nested.zip/test.zip/dir/under_dir"),
-                &mut c1
+                &mut c1,
+                &Scope::All
             )
             .is_ok());
     }
 
+    #[test]
+    fn test_open_streams_file() {
+        let f = Fs::new(&PathU8::from(env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(100, 100));
+
+        let expected =
+            std::fs::read(PathU8::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")).unwrap();
+
+        match f
+            .open(&cache, &PathU8::from("Cargo.toml"), &Scope::All)
+            .unwrap()
+            .0
+        {
+            Content::File(_, len) => assert_eq!(len, expected.len() as u64),
+            _ => panic!("plain file should not be buffered"),
+        }
+
+        let mut c1 = std::io::Cursor::new(Vec::new());
+        let meta = f
+            .read(&cache, &PathU8::from("Cargo.toml"), &mut c1, &Scope::All)
+            .unwrap();
+        assert!(!meta.is_dir);
+        assert!(meta.mtime.is_some());
+        assert!(meta.crc.is_none());
+        assert_eq!(c1.into_inner(), expected);
+    }
+
+    #[test]
+    fn test_entry_meta() {
+        let f = Fs::new(&PathU8::from(env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(1 << 20, 10));
+
+        let (_, meta) = f
+            .open(&cache, &PathU8::from("tests/test.zip"), &Scope::All)
+            .unwrap();
+        assert!(meta.is_dir);
+
+        //crc from central directory, cached or not
+        for _ in 0..2 {
+            let (_, meta) = f
+                .open(
+                    &cache,
+                    &PathU8::from("tests/test.zip/under_root"),
+                    &Scope::All,
+                )
+                .unwrap();
+            assert!(!meta.is_dir);
+            assert!(meta.crc.is_some());
+        }
+    }
+
+    #[test]
+    fn test_scope() {
+        let f = Fs::new(&PathU8::from(env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(1 << 20, 10));
+        let scope = Scope::Subtrees(vec![PathU8::from("tests/test.zip")]);
+
+        let mut top = Vec::new();
+        f.read(&cache, &PathU8::from(""), &mut top, &scope).unwrap();
+        assert_eq!(top, b"tests\n");
+
+        let names: Vec<String> = f
+            .list(&cache, &PathU8::from("tests"), &scope)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["test.zip"]);
+
+        let mut bin = Vec::new();
+        assert!(f
+            .read(
+                &cache,
+                &PathU8::from("tests/test.zip/under_root"),
+                &mut bin,
+                &scope
+            )
+            .is_ok());
+
+        let before = cache.read().unwrap().mem_size();
+
+        for denied in &[
+            "Cargo.toml",
+            "tests/nested.zip",
+            "tests/test.zip/../nested.zip",
+        ] {
+            match f.open(&cache, &PathU8::from(denied), &scope) {
+                Err(error::Error::Forbidden(_)) => {}
+                Err(e) => panic!("{:?} failed as {:?}", denied, e),
+                Ok(_) => panic!("{:?} is opened", denied),
+            }
+        }
+
+        //denied before archive is opened
+        assert_eq!(cache.read().unwrap().mem_size(), before);
+
+        //allowed once resolved, archive on the way is not opened
+        let mut bin = Vec::new();
+        let through = PathU8::from("tests/nested.zip/../test.zip/under_root");
+        f.read(&cache, &through, &mut bin, &scope).unwrap();
+        assert!(!bin.is_empty());
+
+        let dir = PathU8::from("tests/nested.zip/../test.zip");
+        let listed = f.list(&cache, &dir, &scope).unwrap();
+        assert!(listed.iter().any(|e| e.name == "under_root"));
+
+        let lock = cache.read().unwrap();
+        assert!(!lock.is_opened(&PathU8::from("tests/nested.zip")));
+        assert!(lock.id_of(&PathU8::from("tests/nested.zip")).is_none());
+    }
+
+    #[test]
+    fn test_channel_reader() {
+        let (tx, rx) = sync_channel(STREAM_QUEUE);
+
+        std::thread::spawn(move || {
+            for i in 0..3u8 {
+                tx.send(Ok(vec![i; 3])).unwrap();
+            }
+        });
+
+        let mut reader = ChannelReader {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+        };
+
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, vec![0, 0, 0, 1, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_list() {
+        let f = Fs::new(&PathU8::from(env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(100, 100));
+
+        let entries = f.list(&cache, &PathU8::from(""), &Scope::All).unwrap();
+
+        let src = entries.iter().find(|e| e.name == "src").unwrap();
+        assert_eq!(src.kind, EntryKind::Dir);
+
+        let manifest = entries.iter().find(|e| e.name == "Cargo.toml").unwrap();
+        assert_eq!(manifest.kind, EntryKind::File);
+        assert!(manifest.size.unwrap() > 0);
+        assert!(manifest.mtime.is_some());
+
+        let archives = f.list(&cache, &PathU8::from("tests"), &Scope::All).unwrap();
+        let zip = archives.iter().find(|e| e.name == "test.zip").unwrap();
+        assert_eq!(zip.kind, EntryKind::Archive);
+
+        let inner = f
+            .list(&cache, &PathU8::from("tests/test.zip"), &Scope::All)
+            .unwrap();
+        let dir = inner.iter().find(|e| e.name == "dir").unwrap();
+        assert_eq!(dir.kind, EntryKind::Dir);
+        let file = inner.iter().find(|e| e.name == "under_root").unwrap();
+        assert_eq!(file.kind, EntryKind::File);
+    }
+
+    #[test]
+    fn test_dimensions() {
+        let root = std::env::temp_dir().join(format!("fs-dimensions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        image::DynamicImage::new_rgb8(7, 5)
+            .save(root.join("page.png"))
+            .unwrap();
+        std::fs::write(root.join("notes.txt"), b"not image").unwrap();
+
+        let f = Fs::new(&root).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(100, 100));
+
+        let mut entries = f.list(&cache, &PathU8::from(""), &Scope::All).unwrap();
+        let page = entries.iter().find(|e| e.name == "page.png").unwrap();
+        assert_eq!(page.mime.as_ref().unwrap(), "image/png");
+        assert_eq!(page.width, None);
+
+        f.add_dimensions(&cache, &PathU8::from(""), &mut entries);
+        let page = entries.iter().find(|e| e.name == "page.png").unwrap();
+        assert_eq!((page.width, page.height), (Some(7), Some(5)));
+        let notes = entries.iter().find(|e| e.name == "notes.txt").unwrap();
+        assert_eq!(notes.mime.as_ref().unwrap(), "text/plain");
+        assert_eq!(notes.width, None);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_changed_paths() {
+        let single = vec![(String::new(), PathBuf::from("/lib"))];
+
+        assert_eq!(
+            changed_paths(
+                &single,
+                DebouncedEvent::Write(PathBuf::from("/lib/a/b.zip"))
+            ),
+            vec![PathU8::from("a/b.zip")]
+        );
+        assert_eq!(
+            changed_paths(
+                &single,
+                DebouncedEvent::Rename(PathBuf::from("/lib/x.zip"), PathBuf::from("/other/y.zip"))
+            ),
+            vec![PathU8::from("x.zip")]
+        );
+        assert_eq!(
+            changed_paths(&single, DebouncedEvent::Rescan),
+            vec![PathU8::new()]
+        );
+
+        let multi = vec![
+            ("comics".to_owned(), PathBuf::from("/mnt/comics")),
+            ("manga".to_owned(), PathBuf::from("/mnt/manga")),
+        ];
+
+        assert_eq!(
+            changed_paths(
+                &multi,
+                DebouncedEvent::Remove(PathBuf::from("/mnt/manga/v1.cbz"))
+            ),
+            vec![PathU8::from("manga/v1.cbz")]
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        let root = std::env::temp_dir().join(format!("archive-cache-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        let zip = root.join("test.zip");
+        std::fs::copy(
+            PathU8::from(env!("CARGO_MANIFEST_DIR")).join("tests/test.zip"),
+            &zip,
+        )
+        .unwrap();
+
+        let f = Fs::new(&root).unwrap();
+        let cache = Arc::new(RwLock::new(ArchiveCache::new(100, 100)));
+        let (tx, rx) = channel();
+        let watch = f.start_watch(cache.clone(), Some(tx)).unwrap();
+
+        let mut bin = Vec::new();
+        f.read(
+            &cache,
+            &PathU8::from("test.zip/under_root"),
+            &mut bin,
+            &Scope::All,
+        )
+        .unwrap();
+
+        let opened = |cache: &RwLock<ArchiveCache>| {
+            cache
+                .read()
+                .unwrap()
+                .entry_size(&PathU8::from("test.zip"), &PathU8::from("under_root"))
+                .is_some()
+        };
+        assert!(opened(&cache));
+
+        std::fs::copy(
+            PathU8::from(env!("CARGO_MANIFEST_DIR")).join("tests/nested.zip"),
+            &zip,
+        )
+        .unwrap();
+
+        let mut waited = Duration::from_secs(0);
+        while opened(&cache) && waited < Duration::from_secs(10) {
+            std::thread::sleep(WATCH_POLL);
+            waited += WATCH_POLL;
+        }
+        assert!(!opened(&cache));
+        assert!(rx.try_iter().any(|p| p == PathU8::from("test.zip")));
+
+        //joins watch thread
+        drop(watch);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_errors() {
+        let root =
+            std::env::temp_dir().join(format!("archive-cache-errors-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        std::fs::write(root.join("broken.zip"), b"PK\x03\x04 not really").unwrap();
+        std::fs::copy(
+            PathU8::from(env!("CARGO_MANIFEST_DIR")).join("tests/test.zip"),
+            root.join("test.zip"),
+        )
+        .unwrap();
+
+        let f = Fs::new(&root).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(DEFAULT_MEM_LIMIT, 10));
+
+        let open = |path: &str| f.open(&cache, &PathU8::from(path), &Scope::All).err();
+
+        match open("../etc") {
+            Some(error::Error::Forbidden(_)) => {}
+            e => panic!("{:?}", e),
+        }
+        match open("/etc") {
+            Some(error::Error::BadRequest(_)) => {}
+            e => panic!("{:?}", e),
+        }
+        match open("missing") {
+            Some(error::Error::NotFound(_)) => {}
+            e => panic!("{:?}", e),
+        }
+        match open("test.zip/missing") {
+            Some(error::Error::NotFound(_)) => {}
+            e => panic!("{:?}", e),
+        }
+        match open("broken.zip/page.jpg") {
+            Some(error::Error::Unsupported(_)) => {}
+            e => panic!("{:?}", e),
+        }
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let f = Fs::new(&PathU8::from(env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(DEFAULT_MEM_LIMIT, 10));
+
+        let archive = PathU8::from("tests/encrypted.zip");
+        let cover = archive.join("cover.jpg");
+
+        //names are not encrypted
+        let listed = f.list(&cache, &archive, &Scope::All).unwrap();
+        assert_eq!(listed.len(), 2);
+
+        match f.open(&cache, &cover, &Scope::All) {
+            Err(error::Error::PasswordRequired(_)) => {}
+            other => panic!("{:?}", other.map(|_| ()).err()),
+        }
+
+        assert!(!f.unlock(&cache, &archive, "wrong").unwrap());
+        assert!(f.unlock(&cache, &archive, "secret").unwrap());
+        assert!(f
+            .unlock(&cache, &PathU8::from("tests/test.zip"), "secret")
+            .is_err());
+
+        let passwords = Passwords::new();
+        passwords.remember("kid", &archive, "wrong");
+        match f.open_with_keys(&cache, &cover, &Scope::All, Keyring::new(&passwords, "kid")) {
+            Err(error::Error::PasswordRequired(_)) => {}
+            other => panic!("{:?}", other.map(|_| ()).err()),
+        }
+
+        passwords.remember("kid", &archive, "secret");
+        let keys = Keyring::new(&passwords, "kid");
+        for _ in 0..2 {
+            match f.open_with_keys(&cache, &cover, &Scope::All, keys).unwrap() {
+                (Content::Mem(bin), _) => assert_eq!(bin, b"cover image"),
+                _ => panic!("decrypted entry is in memory"),
+            }
+        }
+
+        //never cached for others
+        match f.open_with_keys(
+            &cache,
+            &cover,
+            &Scope::All,
+            Keyring::new(&passwords, "other"),
+        ) {
+            Err(error::Error::PasswordRequired(_)) => {}
+            other => panic!("{:?}", other.map(|_| ()).err()),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_legacy_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = std::env::temp_dir().join(format!("archive-cache-names-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        //latin1 name on disk, shift_jis names in archive
+        let zip = root.join(std::ffi::OsStr::from_bytes(b"caf\xe9.zip"));
+        std::fs::copy(
+            PathU8::from(env!("CARGO_MANIFEST_DIR")).join("tests/sjis.zip"),
+            &zip,
+        )
+        .unwrap();
+
+        let f = Fs::new(&root).unwrap();
+        let cache = RwLock::new(
+            ArchiveCache::new(DEFAULT_MEM_LIMIT, 10).with_charset("shift_jis".parse().unwrap()),
+        );
+
+        let listed = f.list(&cache, &PathU8::from(""), &Scope::All).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].kind, EntryKind::Archive);
+
+        //listed name is valid in url and leads back to the file
+        let name = PathU8::from(&listed[0].name);
+        assert_eq!(f.physical_file(&name), Some(zip.clone()));
+
+        let inner = f.list(&cache, &name, &Scope::All).unwrap();
+        let names: Vec<&str> = inner.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["\u{672c}\u{6587}", "\u{8868}\u{7d19}.jpg"]);
+
+        let mut cover = Vec::new();
+        f.read(
+            &cache,
+            &name.join("\u{8868}\u{7d19}.jpg"),
+            &mut cover,
+            &Scope::All,
+        )
+        .unwrap();
+        assert_eq!(cover, b"cover");
+
+        let mut page = Vec::new();
+        f.read(
+            &cache,
+            &name.join("\u{672c}\u{6587}/01.jpg"),
+            &mut page,
+            &Scope::All,
+        )
+        .unwrap();
+        assert_eq!(page, b"page 1");
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
     #[test]
     fn test_can() {
         let canonicalized = RelativePathBuf::new().to_path("../../");
@@ -291,31 +1440,45 @@ mod tests {
         ))))
         .unwrap();
 
-        let mut cache = Mutex::new(ArchiveCache::new(100, 100));
+        let cache = RwLock::new(ArchiveCache::new(100, 100));
 
         let mut c1 = std::io::Cursor::new(Vec::new());
 
-        assert!(f.read(&cache, &PathU8::from(""), &mut c1).is_ok());
+        assert!(f
+            .read(&cache, &PathU8::from(""), &mut c1, &Scope::All)
+            .is_ok());
 
         let mut c2 = std::io::Cursor::new(Vec::new());
 
-        assert!(f.read(&cache, &PathU8::from("tests"), &mut c2).is_ok());
+        assert!(f
+            .read(&cache, &PathU8::from("tests"), &mut c2, &Scope::All)
+            .is_ok());
 
         let mut c3 = std::io::Cursor::new(Vec::new());
 
         assert!(f
-            .read(&cache, &PathU8::from("not exists"), &mut c3)
+            .read(&cache, &PathU8::from("not exists"), &mut c3, &Scope::All)
             .is_err());
 
         let mut c4 = std::io::Cursor::new(Vec::new());
 
         assert!(f
-            .read(&cache, &PathU8::from("tests/test.zip"), &mut c4)
+            .read(
+                &cache,
+                &PathU8::from("tests/test.zip"),
+                &mut c4,
+                &Scope::All
+            )
             .is_ok());
 
         let mut c5 = std::io::Cursor::new(Vec::new());
         assert!(f
-            .read(&cache, &PathU8::from("tests/test.zip/under_root"), &mut c5)
+            .read(
+                &cache,
+                &PathU8::from("tests/test.zip/under_root"),
+                &mut c5,
+                &Scope::All
+            )
             .is_ok());
 
         let mut c6 = std::io::Cursor::new(Vec::new());
@@ -323,9 +1486,9 @@ mod tests {
             .read(
                 &cache,
                 &PathU8::from("tests/test.zip/dir/under_dir"),
-                &mut c6
+                &mut c6,
+                &Scope::All
             )
             .is_ok());
     }
-
 }
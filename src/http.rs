@@ -0,0 +1,237 @@
+extern crate httpdate;
+
+use hyper::header::{HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//result of matching Range header against body length
+#[derive(Debug, PartialEq)]
+pub enum RangeSpec {
+    //no (usable) range, send whole body
+    Full,
+    //inclusive start and end
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+//http dates have second resolution, drop the rest so comparison
+//against If-Modified-Since is stable
+pub fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+//archive entry with crc is told apart by it, anything else by mtime
+//of file, or of archive file holding it
+pub fn etag(mtime: Option<SystemTime>, crc: Option<u32>, len: u64) -> String {
+    if let Some(crc) = crc {
+        return format!("\"c{:x}-{:x}\"", crc, len);
+    }
+
+    let secs = mtime
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    format!("\"{:x}-{:x}\"", secs, len)
+}
+
+pub fn fmt_date(time: SystemTime) -> String {
+    httpdate::fmt_http_date(time)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: hyper::header::HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn etag_matches(list: &str, etag: &str) -> bool {
+    list.split(',').map(str::trim).any(|candidate| {
+        //weak comparison is enough for GET/HEAD
+        candidate == "*" || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+//true if client copy is still fresh and 304 should be sent
+pub fn not_modified(headers: &HeaderMap, etag: &str, mtime: Option<SystemTime>) -> bool {
+    //If-None-Match takes precedence, If-Modified-Since is ignored when present
+    if let Some(list) = header_str(headers, IF_NONE_MATCH) {
+        return etag_matches(list, etag);
+    }
+
+    let since =
+        header_str(headers, IF_MODIFIED_SINCE).and_then(|v| httpdate::parse_http_date(v).ok());
+
+    match (since, mtime) {
+        (Some(since), Some(mtime)) => truncate_to_secs(mtime) <= since,
+        _ => false,
+    }
+}
+
+//If-Range must match current representation, otherwise range is ignored
+fn if_range_matches(headers: &HeaderMap, etag: &str, mtime: Option<SystemTime>) -> bool {
+    let value = match header_str(headers, IF_RANGE) {
+        None => return true,
+        Some(v) => v.trim(),
+    };
+
+    if value.starts_with('"') {
+        return value == etag;
+    }
+
+    match (httpdate::parse_http_date(value), mtime) {
+        (Ok(date), Some(mtime)) => truncate_to_secs(mtime) == date,
+        _ => false,
+    }
+}
+
+//only single byte range is supported, multiple ranges fall back to full body
+pub fn parse_range(value: &str, len: u64) -> RangeSpec {
+    let value = value.trim();
+
+    if !value.starts_with("bytes=") {
+        return RangeSpec::Full;
+    }
+
+    let spec = value["bytes=".len()..].trim();
+
+    if spec.contains(',') {
+        return RangeSpec::Full;
+    }
+
+    let mut parts = spec.splitn(2, '-');
+
+    let (start, end) = match (parts.next(), parts.next()) {
+        (Some(start), Some(end)) => (start.trim(), end.trim()),
+        _ => return RangeSpec::Full,
+    };
+
+    if start.is_empty() {
+        //suffix range, last n bytes
+        let n = match end.parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => return RangeSpec::Full,
+        };
+
+        if n == 0 || len == 0 {
+            return RangeSpec::Unsatisfiable;
+        }
+
+        let n = std::cmp::min(n, len);
+        return RangeSpec::Partial(len - n, len - 1);
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(s) => s,
+        Err(_) => return RangeSpec::Full,
+    };
+
+    let end = if end.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match end.parse::<u64>() {
+            Ok(e) if e >= start => std::cmp::min(e, len.saturating_sub(1)),
+            _ => return RangeSpec::Full,
+        }
+    };
+
+    if start >= len {
+        return RangeSpec::Unsatisfiable;
+    }
+
+    RangeSpec::Partial(start, end)
+}
+
+pub fn range_for(
+    headers: &HeaderMap,
+    len: u64,
+    etag: &str,
+    mtime: Option<SystemTime>,
+) -> RangeSpec {
+    match header_str(headers, RANGE) {
+        None => RangeSpec::Full,
+        Some(value) => {
+            if !if_range_matches(headers, etag, mtime) {
+                return RangeSpec::Full;
+            }
+            parse_range(value, len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), RangeSpec::Partial(0, 9));
+        assert_eq!(parse_range("bytes=90-", 100), RangeSpec::Partial(90, 99));
+        assert_eq!(parse_range("bytes=90-200", 100), RangeSpec::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-10", 100), RangeSpec::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-200", 100), RangeSpec::Partial(0, 99));
+        assert_eq!(parse_range("bytes=100-", 100), RangeSpec::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), RangeSpec::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,4-5", 100), RangeSpec::Full);
+        assert_eq!(parse_range("bytes=5-1", 100), RangeSpec::Full);
+        assert_eq!(parse_range("items=0-1", 100), RangeSpec::Full);
+        assert_eq!(parse_range("bytes=x-", 100), RangeSpec::Full);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let mtime = UNIX_EPOCH + Duration::from_millis(1_500_000_000_500);
+        let tag = etag(Some(mtime), None, 10);
+        //entry with crc does not change with archive holding it
+        assert_eq!(etag(Some(mtime), Some(7), 10), etag(None, Some(7), 10));
+        assert_ne!(etag(None, Some(7), 10), etag(None, Some(8), 10));
+
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified(&headers, &tag, Some(mtime)));
+
+        headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_str(&fmt_date(mtime)).unwrap(),
+        );
+        assert!(not_modified(&headers, &tag, Some(mtime)));
+        assert!(!not_modified(
+            &headers,
+            &tag,
+            Some(mtime + Duration::from_secs(1))
+        ));
+
+        //etag overrides date
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!not_modified(&headers, &tag, Some(mtime)));
+
+        headers.insert(
+            IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", W/{}", tag)).unwrap(),
+        );
+        assert!(not_modified(&headers, &tag, Some(mtime)));
+    }
+
+    #[test]
+    fn test_if_range() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let tag = etag(Some(mtime), None, 100);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_static("bytes=0-9"));
+        assert_eq!(
+            range_for(&headers, 100, &tag, Some(mtime)),
+            RangeSpec::Partial(0, 9)
+        );
+
+        headers.insert(IF_RANGE, HeaderValue::from_static("\"stale\""));
+        assert_eq!(range_for(&headers, 100, &tag, Some(mtime)), RangeSpec::Full);
+
+        headers.insert(IF_RANGE, HeaderValue::from_str(&tag).unwrap());
+        assert_eq!(
+            range_for(&headers, 100, &tag, Some(mtime)),
+            RangeSpec::Partial(0, 9)
+        );
+    }
+}
@@ -1,7 +1,34 @@
 #![feature(nll)]
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate serde_derive;
 
+pub mod archive_index;
+pub mod auth;
 pub mod cache;
+pub mod catalogue;
+pub mod charset;
+pub mod config;
+pub mod disk_cache;
+pub mod error;
+pub mod eviction;
 pub mod fs;
+pub mod http;
+pub mod limits;
+pub mod listing;
+pub mod metadata;
+pub mod password;
+pub mod path_table;
+pub mod prefetch;
+pub mod progress;
+pub mod scanner;
 pub mod server;
+pub mod sort;
+pub mod store;
+pub mod tarball;
+pub mod thumbnail;
+pub mod tls;
+pub mod transcode;
+pub mod zipcrypto;
+pub mod zipdir;
@@ -0,0 +1,208 @@
+use std::io::Read;
+
+use super::cache::Binary;
+use super::error::{self, Error};
+
+pub const DEFAULT_MAX_DEPTH: usize = 3;
+pub const DEFAULT_MAX_ENTRY_SIZE: usize = 1024 * 1024 * 1024;
+pub const DEFAULT_MAX_RATIO: usize = 100;
+pub const DEFAULT_MAX_EXTRACT: usize = 2 * 1024 * 1024 * 1024;
+
+//what extracting archives may cost, declared sizes are never trusted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    //archives inside archive file, 0 refuses nested archives
+    pub max_depth: usize,
+    //uncompressed bytes of one entry
+    pub max_entry_size: usize,
+    //uncompressed bytes of one entry per byte of archive holding it.
+    //compressed size of entry is not known for every format
+    pub max_ratio: usize,
+    //uncompressed bytes extracted for one path, nested archives on
+    //the way included
+    pub max_extract: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            max_ratio: DEFAULT_MAX_RATIO,
+            max_extract: DEFAULT_MAX_EXTRACT,
+        }
+    }
+}
+
+impl Limits {
+    //archive at depth (0 for file on disk) may be opened
+    pub fn check_depth(&self, name: &str, depth: usize) -> error::Result<()> {
+        if depth > self.max_depth {
+            return Err(Error::TooLarge(format!(
+                "{:?} is nested {} deep, over max_depth {}",
+                name, depth, self.max_depth
+            )));
+        }
+        Ok(())
+    }
+}
+
+//bytes left to one request
+pub struct Budget {
+    limits: Limits,
+    left: usize,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Budget {
+        Budget {
+            limits,
+            left: limits.max_extract,
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    //tightest bound of entry in archive of archive_size bytes, by name
+    fn bound(&self, archive_size: usize) -> (usize, &'static str) {
+        let by_ratio = archive_size.saturating_mul(self.limits.max_ratio);
+
+        let mut ret = (self.limits.max_entry_size, "max_entry_size");
+        if by_ratio < ret.0 {
+            ret = (by_ratio, "max_ratio");
+        }
+        if self.left < ret.0 {
+            ret = (self.left, "max_extract");
+        }
+        ret
+    }
+
+    fn over(name: &str, size: usize, (cap, limit): (usize, &str)) -> Error {
+        Error::TooLarge(format!(
+            "{:?} has {} bytes or more, over {} ({} bytes)",
+            name, size, limit, cap
+        ))
+    }
+
+    //bytes entry may take, fails early if declared size is already over
+    pub fn allow(&self, name: &str, declared: usize, archive_size: usize) -> error::Result<usize> {
+        let bound = self.bound(archive_size);
+        if declared > bound.0 {
+            return Err(Budget::over(name, declared, bound));
+        }
+        Ok(bound.0)
+    }
+
+    //whole entry, reading stops as soon as it crosses a limit
+    pub fn read(
+        &mut self,
+        reader: &mut Read,
+        name: &str,
+        declared: usize,
+        archive_size: usize,
+    ) -> error::Result<Binary> {
+        let cap = self.allow(name, declared, archive_size)?;
+
+        //grown as bytes arrive, declared size may be a lie
+        let mut binary = Vec::new();
+        reader
+            .take(cap as u64 + 1)
+            .read_to_end(&mut binary)
+            .map_err(|e| Error::Unsupported(format!("can not extract {:?}: {}", name, e)))?;
+
+        if binary.len() > cap {
+            return Err(Budget::over(name, binary.len(), self.bound(archive_size)));
+        }
+
+        self.left -= binary.len();
+        Ok(binary)
+    }
+}
+
+//fails once more than cap bytes are read, for entries streamed
+//without reading them whole first
+pub struct Capped<R> {
+    inner: R,
+    left: usize,
+    name: String,
+}
+
+impl<R: Read> Capped<R> {
+    pub fn new(inner: R, cap: usize, name: &str) -> Capped<R> {
+        Capped {
+            inner,
+            left: cap,
+            name: name.to_owned(),
+        }
+    }
+}
+
+impl<R: Read> Read for Capped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > self.left {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{:?} is larger than extraction limits", self.name),
+            ));
+        }
+        self.left -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            max_depth: 1,
+            max_entry_size: 100,
+            max_ratio: 10,
+            max_extract: 150,
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut budget = Budget::new(limits());
+
+        let bin = budget.read(&mut &[1; 80][..], "a", 80, 1000).unwrap();
+        assert_eq!(bin.len(), 80);
+
+        //declared size lies, actual bytes decide
+        match budget.read(&mut &[1; 80][..], "b", 10, 1000) {
+            Err(Error::TooLarge(msg)) => assert!(msg.contains("max_extract"), "{}", msg),
+            other => panic!("{:?}", other.map(|b| b.len())),
+        }
+
+        let budget = Budget::new(limits());
+        match budget.allow("c", 60, 5) {
+            Err(Error::TooLarge(msg)) => assert!(msg.contains("max_ratio"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+        match budget.allow("d", 101, 1000) {
+            Err(Error::TooLarge(msg)) => assert!(msg.contains("max_entry_size"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(budget.allow("e", 0, 5).unwrap(), 50);
+
+        assert!(limits().check_depth("f", 1).is_ok());
+        assert!(limits().check_depth("f", 2).is_err());
+    }
+
+    #[test]
+    fn test_capped() {
+        let mut out = Vec::new();
+        assert!(Capped::new(&[1; 10][..], 10, "a")
+            .read_to_end(&mut out)
+            .is_ok());
+        assert!(Capped::new(&[1; 11][..], 10, "a")
+            .read_to_end(&mut out)
+            .is_err());
+    }
+}
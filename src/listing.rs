@@ -0,0 +1,209 @@
+extern crate serde_json;
+
+use super::cache::PathU8;
+use image::ImageDecoder;
+use std::io::{BufRead, Seek};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MIME_JSON: &str = "application/json";
+
+const TARBALL_MIMES: [(&str, &str); 9] = [
+    (".tar.gz", "application/gzip"),
+    (".tgz", "application/gzip"),
+    (".tar.bz2", "application/x-bzip2"),
+    (".tbz", "application/x-bzip2"),
+    (".tbz2", "application/x-bzip2"),
+    (".tar.xz", "application/x-xz"),
+    (".txz", "application/x-xz"),
+    (".tar.zst", "application/zstd"),
+    (".tzst", "application/zstd"),
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Dir,
+    File,
+    Archive,
+}
+
+//one child of a (real or in archive) dir
+#[derive(Serialize, Debug, Clone)]
+pub struct EntryInfo {
+    pub name: String,
+    pub kind: EntryKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    //seconds since unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u64>,
+    //cover page as told by ComicInfo
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cover: bool,
+}
+
+impl EntryInfo {
+    pub fn new(name: &str, kind: EntryKind) -> EntryInfo {
+        EntryInfo {
+            name: name.to_owned(),
+            kind,
+            size: None,
+            mtime: None,
+            mime: None,
+            width: None,
+            height: None,
+            cover: false,
+        }
+    }
+
+    pub fn set_mtime(&mut self, mtime: Option<SystemTime>) {
+        self.mtime = mtime
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+    }
+
+    pub fn set_dimensions(&mut self, dims: Option<(u64, u64)>) {
+        if let Some((w, h)) = dims {
+            self.width = Some(w);
+            self.height = Some(h);
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Listing<'a> {
+    path: &'a str,
+    entries: &'a [EntryInfo],
+}
+
+pub fn to_json(path: &PathU8, entries: &[EntryInfo]) -> Vec<u8> {
+    let listing = Listing {
+        path: path.to_str().unwrap_or(""),
+        entries,
+    };
+
+    //serializing plain structs can not fail
+    serde_json::to_vec(&listing).unwrap()
+}
+
+//one name per line, same as listing of NodeContents::Dir
+pub fn to_text(entries: &[EntryInfo]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for entry in entries {
+        ret.extend_from_slice(entry.name.as_bytes());
+        ret.push(b'\n');
+    }
+    ret
+}
+
+//when file content is not at hand, guess by name
+pub fn mime_from_name(name: &str) -> Option<&'static str> {
+    //compressed tarballs, matched on whole name as extension is only
+    //last part
+    let lower = name.to_ascii_lowercase();
+    let tarball = TARBALL_MIMES
+        .iter()
+        .find(|(suffix, _)| lower.ends_with(suffix));
+    if let Some((_, mime)) = tarball {
+        return Some(*mime);
+    }
+
+    let ext = PathU8::from(name)
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+
+    let mime = match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "zip" | "cbz" => "application/zip",
+        "rar" | "cbr" => "application/x-rar",
+        "7z" | "cb7" => "application/x-7z-compressed",
+        "tar" | "cbt" => "application/x-tar",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        _ => return None,
+    };
+
+    Some(mime)
+}
+
+//only parse image header, without decoding pixels
+pub fn image_dimensions<R: BufRead + Seek>(mut r: R) -> Option<(u64, u64)> {
+    let mut magic = [0u8; 16];
+    let n = r.read(&mut magic).ok()?;
+    r.seek(std::io::SeekFrom::Start(0)).ok()?;
+
+    let format = image::guess_format(&magic[..n]).ok()?;
+
+    let dims = match format {
+        image::ImageFormat::PNG => image::png::PNGDecoder::new(r).ok()?.dimensions(),
+        image::ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(r).ok()?.dimensions(),
+        image::ImageFormat::GIF => image::gif::Decoder::new(r).ok()?.dimensions(),
+        image::ImageFormat::BMP => image::bmp::BMPDecoder::new(r).ok()?.dimensions(),
+        _ => return None,
+    };
+
+    Some(dims)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let mut file = EntryInfo::new("page1.png", EntryKind::File);
+        file.size = Some(10);
+        let dir = EntryInfo::new("dir", EntryKind::Dir);
+
+        let json = to_json(&PathU8::from("a/b.zip"), &[file, dir]);
+
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+
+        assert_eq!(value["path"], "a/b.zip");
+        assert_eq!(value["entries"][0]["kind"], "file");
+        assert_eq!(value["entries"][0]["size"], 10);
+        assert_eq!(value["entries"][1]["kind"], "dir");
+        assert!(value["entries"][1].get("size").is_none());
+    }
+
+    #[test]
+    fn test_mime_from_name() {
+        assert_eq!(mime_from_name("a/B.JPG"), Some("image/jpeg"));
+        assert_eq!(mime_from_name("x.cbz"), Some("application/zip"));
+        assert_eq!(mime_from_name("x.cbt"), Some("application/x-tar"));
+        assert_eq!(mime_from_name("x.tar"), Some("application/x-tar"));
+        assert_eq!(mime_from_name("x.cb7"), Some("application/x-7z-compressed"));
+        assert_eq!(mime_from_name("x.tar.gz"), Some("application/gzip"));
+        assert_eq!(mime_from_name("x.TGZ"), Some("application/gzip"));
+        assert_eq!(mime_from_name("x.tar.bz2"), Some("application/x-bzip2"));
+        assert_eq!(mime_from_name("x.tar.xz"), Some("application/x-xz"));
+        assert_eq!(mime_from_name("x.tar.zst"), Some("application/zstd"));
+        //only whole suffix makes tarball
+        assert_eq!(mime_from_name("x.gz"), None);
+        assert_eq!(mime_from_name("noext"), None);
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        let img = image::DynamicImage::new_rgb8(7, 5);
+        let mut png = Vec::new();
+        img.write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+
+        assert_eq!(image_dimensions(std::io::Cursor::new(&png)), Some((7, 5)));
+        assert_eq!(image_dimensions(std::io::Cursor::new(b"not image")), None);
+    }
+}
@@ -1,32 +1,27 @@
 #[macro_use]
 extern crate log;
-
+extern crate archive_cache;
 extern crate simple_logger;
 
-mod cache;
-mod fs;
-mod server;
+use archive_cache::{config, server};
 
 fn main() {
-    let _ = simple_logger::init();
-
-    let _ = server::run_server(([0, 0, 0, 0], 31257).into(), &cache::PathU8::from("."));
-}
-
-#[cfg(test)]
-mod tests {
-
-    use super::*;
-
-    #[test]
-    fn test_server() {
-
-        /*
-        let thrd=std::thread::spawn(||{
-            server::run_server(([0, 0, 0, 0], 31257).into(), &cache::PathU8::from(env!("CARGO_MANIFEST_DIR")));
-        });
-
-        thrd.join();
-        */
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        //help and version end here too, with status 0
+        Err(config::LoadError::Args(e)) => e.exit(),
+        Err(config::LoadError::Invalid(e)) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let _ = simple_logger::init_with_level(config.log_level);
+
+    info!("starting with {:?}", config);
+
+    if let Err(e) = server::run_server(&config) {
+        error!("can not start server on {}: {}", config.bind, e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,701 @@
+extern crate quick_xml;
+extern crate serde_json;
+
+use super::auth::Scope;
+use super::cache::{join_may_empty, ArchiveCache, PathU8};
+use super::error;
+use super::fs::Fs;
+use super::listing::{mime_from_name, EntryInfo};
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::RwLock;
+
+pub const COMIC_INFO: &str = "ComicInfo.xml";
+
+//ComicInfo.xml larger than this is not a real one
+const COMIC_INFO_MAX: usize = 1024 * 1024;
+
+//end of central directory record: fixed part, then comment up to 64k
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const EOCD_SIZE: usize = 22;
+const EOCD_SEARCH: usize = EOCD_SIZE + 0xffff;
+
+//key of ComicBookInfo json, as written into zip comment by ComicRack
+//and comictagger
+const COMIC_BOOK_INFO: &str = "ComicBookInfo/1.0";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    ComicInfo,
+    Comment,
+    Filename,
+}
+
+impl Default for Source {
+    fn default() -> Source {
+        Source::Filename
+    }
+}
+
+//<Page Image="N" Type="..."/> of ComicInfo. image is index among
+//image entries in natural order
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Page {
+    pub image: usize,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+}
+
+impl Page {
+    fn is(&self, kind: &str) -> bool {
+        self.kind
+            .as_ref()
+            .map_or(false, |k| k.eq_ignore_ascii_case(kind))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub source: Source,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub writer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    //as declared, may differ from images found
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_count: Option<u64>,
+    #[serde(skip)]
+    pub pages: Vec<Page>,
+}
+
+fn non_empty(s: String) -> Option<String> {
+    let trimmed = s.trim();
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed.to_owned())
+    }
+}
+
+fn page_of<B: std::io::BufRead>(e: &BytesStart, reader: &Reader<B>) -> Option<Page> {
+    let mut image = None;
+    let mut kind = None;
+
+    for attr in e.attributes() {
+        let attr = attr.ok()?;
+        let value = attr.unescape_and_decode_value(reader).ok()?;
+
+        match attr.key {
+            b"Image" => image = value.trim().parse().ok(),
+            b"Type" => kind = non_empty(value),
+            _ => {}
+        }
+    }
+
+    Some(Page {
+        image: image?,
+        kind,
+    })
+}
+
+//None if xml is broken or not a ComicInfo
+pub fn parse_comic_info(bin: &[u8]) -> Option<Metadata> {
+    let mut reader = Reader::from_reader(bin);
+    reader.trim_text(true);
+
+    let mut ret = Metadata {
+        source: Source::ComicInfo,
+        ..Metadata::default()
+    };
+
+    let mut seen_root = false;
+    let mut element = Vec::new();
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => {
+                seen_root |= e.name() == b"ComicInfo";
+                element = e.name().to_vec();
+            }
+            Ok(Event::Empty(ref e)) => {
+                if e.name() == b"Page" {
+                    if let Some(page) = page_of(e, &reader) {
+                        ret.pages.push(page);
+                    }
+                }
+            }
+            Ok(Event::Text(ref e)) => {
+                let text = e.unescape_and_decode(&reader).ok()?;
+
+                match element.as_slice() {
+                    b"Title" => ret.title = non_empty(text),
+                    b"Series" => ret.series = non_empty(text),
+                    b"Number" => ret.number = non_empty(text),
+                    b"Volume" => ret.volume = non_empty(text),
+                    b"Year" => ret.year = text.trim().parse().ok(),
+                    b"Writer" => ret.writer = non_empty(text),
+                    b"Publisher" => ret.publisher = non_empty(text),
+                    b"Summary" => ret.summary = non_empty(text),
+                    b"PageCount" => ret.page_count = text.trim().parse().ok(),
+                    _ => {}
+                }
+            }
+            Ok(Event::End(_)) => element.clear(),
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                debug!("bad ComicInfo: {}", e);
+                return None;
+            }
+        }
+        buf.clear();
+    }
+
+    if seen_root {
+        Some(ret)
+    } else {
+        None
+    }
+}
+
+fn json_str(v: &serde_json::Value) -> Option<String> {
+    match v {
+        serde_json::Value::String(s) => non_empty(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+//ComicBookInfo json, or any other text which is kept as summary
+pub fn parse_comment(comment: &str) -> Option<Metadata> {
+    let mut ret = Metadata {
+        source: Source::Comment,
+        ..Metadata::default()
+    };
+
+    let value: Option<serde_json::Value> = serde_json::from_str(comment).ok();
+
+    let info = match value.as_ref().and_then(|v| v.get(COMIC_BOOK_INFO)) {
+        Some(info) => info,
+        None => {
+            ret.summary = Some(non_empty(comment.to_owned())?);
+            return Some(ret);
+        }
+    };
+
+    let field = |key: &str| info.get(key).and_then(json_str);
+
+    ret.title = field("title");
+    ret.series = field("series");
+    ret.number = field("issue");
+    ret.volume = field("volume");
+    ret.year = field("publicationYear").and_then(|y| y.parse().ok());
+    ret.publisher = field("publisher");
+    ret.summary = field("comments");
+
+    if let Some(credits) = info.get("credits").and_then(|c| c.as_array()) {
+        let writers: Vec<String> = credits
+            .iter()
+            .filter(|c| {
+                c.get("role")
+                    .and_then(|r| r.as_str())
+                    .map_or(false, |r| r.eq_ignore_ascii_case("writer"))
+            })
+            .filter_map(|c| c.get("person").and_then(json_str))
+            .collect();
+
+        if !writers.is_empty() {
+            ret.writer = Some(writers.join(", "));
+        }
+    }
+
+    Some(ret)
+}
+
+//comment of zip file, from its end of central directory record
+pub fn zip_comment<R: Read + Seek>(mut r: R) -> std::io::Result<Option<String>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    let tail_len = std::cmp::min(len, EOCD_SEARCH as u64);
+
+    r.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = Vec::with_capacity(tail_len as usize);
+    r.take(tail_len).read_to_end(&mut tail)?;
+
+    if tail.len() < EOCD_SIZE {
+        return Ok(None);
+    }
+
+    //last record whose comment length reaches exactly to end of file
+    for i in (0..=tail.len() - EOCD_SIZE).rev() {
+        let signature = u32::from(tail[i])
+            | u32::from(tail[i + 1]) << 8
+            | u32::from(tail[i + 2]) << 16
+            | u32::from(tail[i + 3]) << 24;
+
+        if signature != EOCD_SIGNATURE {
+            continue;
+        }
+
+        let comment_len = usize::from(tail[i + 20]) | usize::from(tail[i + 21]) << 8;
+        let start = i + EOCD_SIZE;
+
+        if start + comment_len != tail.len() {
+            continue;
+        }
+
+        let comment = String::from_utf8_lossy(&tail[start..]);
+        return Ok(non_empty(comment.into_owned()));
+    }
+
+    Ok(None)
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+//"Series Name v02 #013 (2019) [group].cbz" and alike
+pub fn parse_filename(name: &str) -> Metadata {
+    let stem = PathU8::from(name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(name)
+        .replace('_', " ");
+
+    let mut ret = Metadata::default();
+    let mut series = Vec::new();
+    let mut words = Vec::new();
+    let mut depth = 0;
+    let mut group = String::new();
+
+    //drop (..) and [..] groups, a 4 digit one is the year
+    for c in stem.chars() {
+        match c {
+            '(' | '[' | '{' => {
+                depth += 1;
+                group.clear();
+            }
+            ')' | ']' | '}' if depth > 0 => {
+                depth -= 1;
+                let inner = group.trim();
+                if ret.year.is_none() && inner.len() == 4 && is_digits(inner) {
+                    ret.year = inner.parse().ok();
+                }
+                words.push(String::new());
+            }
+            _ if depth > 0 => group.push(c),
+            ' ' => words.push(String::new()),
+            _ => match words.last_mut() {
+                Some(word) => word.push(c),
+                None => words.push(c.to_string()),
+            },
+        }
+    }
+
+    let mut words = words.into_iter().filter(|w| !w.is_empty()).peekable();
+
+    while let Some(word) = words.next() {
+        let lower = word.to_ascii_lowercase();
+
+        if lower.starts_with('#') && is_digits(&lower[1..]) {
+            ret.number = Some(word[1..].to_owned());
+        } else if lower.starts_with('v') && is_digits(&lower[1..]) {
+            ret.volume = Some(word[1..].to_owned());
+        } else if lower.starts_with("vol.") && is_digits(&lower[4..]) {
+            ret.volume = Some(word[4..].to_owned());
+        } else if lower == "vol" || lower == "vol." {
+            if words.peek().map_or(false, |w| is_digits(w)) {
+                ret.volume = words.next();
+            }
+        } else if is_digits(&word) && ret.number.is_none() && !series.is_empty() {
+            ret.number = Some(word);
+        } else if ret.number.is_none() && ret.volume.is_none() {
+            series.push(word);
+        }
+    }
+
+    if !series.is_empty() {
+        ret.series = Some(series.join(" "));
+    }
+
+    ret
+}
+
+impl Metadata {
+    //positions of names in reading order. only image entries are moved,
+    //they fill the slots images had in names, ordered by page list with
+    //cover first. unlisted images follow listed ones, deleted ones last
+    pub fn reading_order(&self, names: &[&str]) -> Vec<usize> {
+        let mut ret: Vec<usize> = (0..names.len()).collect();
+
+        if self.pages.is_empty() {
+            return ret;
+        }
+
+        let images: Vec<usize> = (0..names.len()).filter(|i| is_image(names[*i])).collect();
+
+        let rank = |image: usize| -> (u8, usize) {
+            match self.pages.iter().position(|p| p.image == image) {
+                Some(pos) if self.pages[pos].is("FrontCover") => (0, pos),
+                Some(pos) if self.pages[pos].is("Deleted") => (3, pos),
+                Some(pos) => (1, pos),
+                None => (2, image),
+            }
+        };
+
+        let mut ordered: Vec<usize> = (0..images.len()).collect();
+        ordered.sort_by_key(|image| rank(*image));
+
+        for (slot, image) in images.iter().zip(ordered) {
+            ret[*slot] = images[image];
+        }
+
+        ret
+    }
+
+    //position of cover in names, if page list says so
+    pub fn cover(&self, names: &[&str]) -> Option<usize> {
+        let page = self.pages.iter().find(|p| p.is("FrontCover"))?;
+
+        (0..names.len())
+            .filter(|i| is_image(names[*i]))
+            .nth(page.image)
+    }
+}
+
+fn is_image(name: &str) -> bool {
+    mime_from_name(name).map_or(false, |m| m.starts_with("image"))
+}
+
+pub fn find_comic_info<'a>(names: &[&'a str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find(|n| n.eq_ignore_ascii_case(COMIC_INFO))
+        .cloned()
+}
+
+//ComicInfo.xml directly under dir (a folder or archive), names are
+//children of dir
+pub fn comic_info(
+    fs: &Fs,
+    cache: &RwLock<ArchiveCache>,
+    dir: &PathU8,
+    names: &[&str],
+) -> Option<Metadata> {
+    let name = find_comic_info(names)?;
+    let path = join_may_empty(dir, &PathU8::from(name));
+
+    //names are from a scoped listing, ComicInfo among them is allowed
+    let mut bin = Vec::new();
+    let read = fs
+        .read(cache, &path, &mut bin, &Scope::All)
+        .and_then(|_| match bin.len() {
+            n if n > COMIC_INFO_MAX => Err(error::Error::Unsupported("too large".to_owned())),
+            _ => Ok(()),
+        });
+
+    if let Err(e) = read {
+        warn!("can not read {:?}: {:?}", path, e);
+        return None;
+    }
+
+    let ret = parse_comic_info(&bin);
+    if ret.is_none() {
+        warn!("{:?} is not a ComicInfo", path);
+    }
+    ret
+}
+
+//names of dir, as in plain listing
+pub fn children(
+    fs: &Fs,
+    cache: &RwLock<ArchiveCache>,
+    dir: &PathU8,
+    scope: &Scope,
+) -> error::Result<Vec<String>> {
+    let mut listing = Vec::new();
+    let meta = fs.read(cache, dir, &mut listing, scope)?;
+
+    if !meta.is_dir {
+        return Err(error::Error::BadRequest(format!(
+            "{:?} is not dir or archive",
+            dir
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&listing)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+//metadata of folder or archive: from its ComicInfo.xml, else from
+//archive comment, else guessed from its name. names are its children
+pub fn describe(
+    fs: &Fs,
+    cache: &RwLock<ArchiveCache>,
+    path: &PathU8,
+    names: &[&str],
+) -> error::Result<Metadata> {
+    if let Some(found) = comic_info(fs, cache, path, names) {
+        return Ok(found);
+    }
+
+    //only archive files on disk, nested ones are read as a whole
+    let comment = match fs.physical_file(path) {
+        Some(file) => {
+            let comment = std::fs::File::open(&file).and_then(zip_comment);
+            comment.map_err(|e| error::Error::io(format!("read comment of {:?}", file), e))?
+        }
+        None => None,
+    };
+
+    if let Some(found) = comment.as_ref().and_then(|c| parse_comment(c)) {
+        return Ok(found);
+    }
+
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    Ok(parse_filename(name))
+}
+
+//listing entries of dir (in natural order) put in reading order of
+//its ComicInfo, if any
+pub fn order_entries(
+    fs: &Fs,
+    cache: &RwLock<ArchiveCache>,
+    dir: &PathU8,
+    entries: &mut Vec<EntryInfo>,
+) {
+    let (order, cover) = {
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+
+        match comic_info(fs, cache, dir, &names) {
+            None => return,
+            Some(meta) => (meta.reading_order(&names), meta.cover(&names)),
+        }
+    };
+
+    if let Some(cover) = cover {
+        entries[cover].cover = true;
+    }
+
+    let mut taken: Vec<Option<EntryInfo>> = entries.drain(..).map(Some).collect();
+    entries.extend(order.into_iter().filter_map(|i| taken[i].take()));
+}
+
+//same for plain listing, None if it needs no change
+pub fn order_listing(
+    fs: &Fs,
+    cache: &RwLock<ArchiveCache>,
+    dir: &PathU8,
+    listing: &[u8],
+) -> Option<Vec<u8>> {
+    let text = String::from_utf8_lossy(listing);
+    let names: Vec<&str> = text.lines().collect();
+
+    let order = comic_info(fs, cache, dir, &names)?.reading_order(&names);
+
+    let mut ret = Vec::new();
+    for i in order {
+        ret.extend_from_slice(names[i].as_bytes());
+        ret.push(b'\n');
+    }
+    Some(ret)
+}
+
+#[derive(Serialize)]
+struct PageRef<'a> {
+    name: &'a str,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    kind: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Described<'a> {
+    path: &'a str,
+    #[serde(flatten)]
+    meta: &'a Metadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover: Option<&'a str>,
+    //images in reading order
+    pages: Vec<PageRef<'a>>,
+}
+
+pub fn to_json(path: &PathU8, meta: &Metadata, names: &[&str]) -> Vec<u8> {
+    let pages = meta
+        .reading_order(names)
+        .into_iter()
+        .map(|i| names[i])
+        .filter(|n| is_image(n))
+        .map(|name| {
+            let image = names
+                .iter()
+                .filter(|n| is_image(n))
+                .position(|n| *n == name);
+            let kind = meta
+                .pages
+                .iter()
+                .find(|p| Some(p.image) == image)
+                .and_then(|p| p.kind.as_ref())
+                .map(|k| k.as_str());
+            PageRef { name, kind }
+        })
+        .collect();
+
+    let described = Described {
+        path: path.to_str().unwrap_or(""),
+        meta,
+        cover: meta.cover(names).map(|i| names[i]),
+        pages,
+    };
+
+    serde_json::to_vec(&described).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<ComicInfo xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <Title>The &amp; Title</Title>
+  <Series>Series</Series>
+  <Number>3</Number>
+  <Year>2019</Year>
+  <Writer>Someone</Writer>
+  <Summary></Summary>
+  <PageCount>3</PageCount>
+  <Pages>
+    <Page Image="0" />
+    <Page Image="2" Type="FrontCover" />
+    <Page Image="1" Type="Deleted" />
+  </Pages>
+</ComicInfo>"#;
+
+    #[test]
+    fn test_comic_info() {
+        let meta = parse_comic_info(XML.as_bytes()).unwrap();
+
+        assert_eq!(meta.source, Source::ComicInfo);
+        assert_eq!(meta.title.as_ref().unwrap(), "The & Title");
+        assert_eq!(meta.number.as_ref().unwrap(), "3");
+        assert_eq!(meta.year, Some(2019));
+        assert_eq!(meta.summary, None);
+        assert_eq!(meta.page_count, Some(3));
+        assert_eq!(meta.pages.len(), 3);
+
+        let names = ["1.jpg", "2.jpg", "3.jpg", COMIC_INFO];
+        assert_eq!(meta.reading_order(&names), vec![2, 0, 1, 3]);
+        assert_eq!(meta.cover(&names), Some(2));
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_json(&PathU8::from("a.cbz"), &meta, &names)).unwrap();
+        assert_eq!(json["series"], "Series");
+        assert_eq!(json["cover"], "3.jpg");
+        assert_eq!(json["pages"][0]["name"], "3.jpg");
+        assert_eq!(json["pages"][2]["type"], "Deleted");
+
+        assert!(parse_comic_info(b"<other/>").is_none());
+        assert!(parse_comic_info(b"<ComicInfo><Title>x</Series>").is_none());
+    }
+
+    #[test]
+    fn test_comment() {
+        let json = r#"{"appID":"x","ComicBookInfo/1.0":{"series":"S","issue":12,
+            "publicationYear":2001,"credits":[{"person":"W","role":"Writer"},
+            {"person":"A","role":"Artist"}]}}"#;
+        let meta = parse_comment(json).unwrap();
+        assert_eq!(meta.source, Source::Comment);
+        assert_eq!(meta.series.as_ref().unwrap(), "S");
+        assert_eq!(meta.number.as_ref().unwrap(), "12");
+        assert_eq!(meta.year, Some(2001));
+        assert_eq!(meta.writer.as_ref().unwrap(), "W");
+
+        assert_eq!(
+            parse_comment(" free text ").unwrap().summary.unwrap(),
+            "free text"
+        );
+        assert!(parse_comment("  ").is_none());
+
+        //fixture has no comment
+        let fixture = PathU8::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("test.zip");
+        assert_eq!(
+            zip_comment(std::fs::File::open(fixture).unwrap()).unwrap(),
+            None
+        );
+
+        let mut zip = vec![0u8; 10];
+        zip.extend_from_slice(&[0x50, 0x4b, 0x05, 0x06]);
+        zip.extend_from_slice(&[0; 16]);
+        zip.extend_from_slice(&[5, 0]);
+        zip.extend_from_slice(b"hello");
+        assert_eq!(
+            zip_comment(std::io::Cursor::new(zip)).unwrap().unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn test_filename() {
+        let meta = parse_filename("Some_Series v02 #013 (2019) [group].cbz");
+        assert_eq!(meta.source, Source::Filename);
+        assert_eq!(meta.series.as_ref().unwrap(), "Some Series");
+        assert_eq!(meta.volume.as_ref().unwrap(), "02");
+        assert_eq!(meta.number.as_ref().unwrap(), "013");
+        assert_eq!(meta.year, Some(2019));
+
+        let meta = parse_filename("[group] Title 05.zip");
+        assert_eq!(meta.series.as_ref().unwrap(), "Title");
+        assert_eq!(meta.number.as_ref().unwrap(), "05");
+
+        let meta = parse_filename("Vol 3.cbr");
+        assert_eq!(meta.series, None);
+        assert_eq!(meta.volume.as_ref().unwrap(), "3");
+    }
+
+    #[test]
+    fn test_describe() {
+        let root = PathU8::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let fs = Fs::new(&root).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(1 << 20, 10));
+
+        let archive = PathU8::from("test.zip");
+        let names = children(&fs, &cache, &archive, &Scope::All).unwrap();
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+        assert_eq!(names, vec!["dir", "under_root"]);
+
+        let meta = describe(&fs, &cache, &archive, &names).unwrap();
+        assert_eq!(meta.source, Source::Filename);
+        assert_eq!(meta.series.as_ref().unwrap(), "test");
+
+        //no ComicInfo, left as is
+        assert!(order_listing(&fs, &cache, &archive, b"dir\nunder_root\n").is_none());
+
+        assert!(children(
+            &fs,
+            &cache,
+            &PathU8::from("test.zip/under_root"),
+            &Scope::All
+        )
+        .is_err());
+    }
+}
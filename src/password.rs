@@ -0,0 +1,232 @@
+use super::cache::PathU8;
+use super::config::invalid;
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Mutex;
+
+//[[archive]] tables of password file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    //virtual path of archive or folder holding archives, * matches
+    //any part of one component
+    path: String,
+    password: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PasswordFile {
+    #[serde(default)]
+    archive: Vec<Rule>,
+}
+
+//passwords of encrypted archives. rules from file apply to everyone,
+//passwords submitted by users are kept in memory for them only
+pub struct Passwords {
+    rules: Vec<Rule>,
+    //user => archive => password
+    remembered: Mutex<HashMap<String, HashMap<PathU8, String>>>,
+}
+
+//'*' in pattern matches any (maybe empty) part of name
+fn glob(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    let first = parts.next().unwrap_or("");
+    if !name.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..];
+    let mut parts: Vec<&str> = parts.collect();
+
+    let last = match parts.pop() {
+        //no '*' at all
+        None => return rest.is_empty(),
+        Some(last) => last,
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+//pattern matches archive itself or a folder holding it
+fn covers(pattern: &str, archive: &PathU8) -> bool {
+    let pattern = PathU8::from(pattern.trim_matches('/'));
+    let mut names = archive.iter();
+
+    pattern
+        .iter()
+        .all(|part| match (part.to_str(), names.next()) {
+            (Some(part), Some(name)) => name.to_str().map_or(false, |name| glob(part, name)),
+            _ => false,
+        })
+}
+
+impl Passwords {
+    //nothing but what users submit
+    pub fn new() -> Passwords {
+        Passwords::with_rules(Vec::new())
+    }
+
+    fn with_rules(rules: Vec<Rule>) -> Passwords {
+        Passwords {
+            rules,
+            remembered: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn load(path: &PathU8) -> std::io::Result<Passwords> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("can not read password file {:?}: {}", path, e),
+            )
+        })?;
+
+        Passwords::parse(&content).map_err(|e| invalid(format!("password file {:?}: {}", path, e)))
+    }
+
+    pub fn parse(content: &str) -> std::io::Result<Passwords> {
+        let file: PasswordFile = toml::from_str(content).map_err(|e| invalid(e.to_string()))?;
+
+        if let Some(rule) = file
+            .archive
+            .iter()
+            .find(|r| r.path.trim_matches('/').is_empty())
+        {
+            return Err(invalid(format!(
+                "empty path of rule {:?}, use \"*\" for every top level entry",
+                rule.path
+            )));
+        }
+
+        Ok(Passwords::with_rules(file.archive))
+    }
+
+    //submitted by user and known to open archive
+    pub fn remember(&self, user: &str, archive: &PathU8, password: &str) {
+        self.remembered
+            .lock()
+            .unwrap()
+            .entry(user.to_owned())
+            .or_insert_with(HashMap::new)
+            .insert(archive.clone(), password.to_owned());
+    }
+
+    //passwords to try on archive, the one user submitted first
+    pub fn candidates(&self, user: &str, archive: &PathU8) -> Vec<String> {
+        let mut ret: Vec<String> = self
+            .remembered
+            .lock()
+            .unwrap()
+            .get(user)
+            .and_then(|archives| archives.get(archive))
+            .cloned()
+            .into_iter()
+            .collect();
+
+        for rule in self.rules.iter().filter(|r| covers(&r.path, archive)) {
+            if !ret.contains(&rule.password) {
+                ret.push(rule.password.clone());
+            }
+        }
+
+        ret
+    }
+}
+
+//passwords one request may try, none for background work like
+//thumbnails, so nothing decrypted leaks to other users
+#[derive(Clone, Copy)]
+pub struct Keyring<'a> {
+    passwords: Option<&'a Passwords>,
+    user: &'a str,
+}
+
+impl<'a> Keyring<'a> {
+    pub fn new(passwords: &'a Passwords, user: &'a str) -> Keyring<'a> {
+        Keyring {
+            passwords: Some(passwords),
+            user,
+        }
+    }
+
+    pub fn empty() -> Keyring<'static> {
+        Keyring {
+            passwords: None,
+            user: "",
+        }
+    }
+
+    pub fn candidates(&self, archive: &PathU8) -> Vec<String> {
+        self.passwords
+            .map_or_else(Vec::new, |p| p.candidates(self.user, archive))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("a.zip", "a.zip"));
+        assert!(!glob("a.zip", "a.zip.bak"));
+        assert!(glob("*.zip", "a.zip"));
+        assert!(glob("vol*-*.cbz", "vol1-2.cbz"));
+        assert!(!glob("vol*-*.cbz", "vol1.cbz"));
+        assert!(glob("*", ""));
+
+        assert!(covers("private", &PathU8::from("private/a.zip")));
+        assert!(covers("/private/*.zip", &PathU8::from("private/a.zip")));
+        assert!(!covers("private/*.zip", &PathU8::from("private")));
+        assert!(!covers("private", &PathU8::from("privateer/a.zip")));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let passwords = Passwords::parse(
+            r#"
+            [[archive]]
+            path = "secret/*.zip"
+            password = "one"
+            [[archive]]
+            path = "secret"
+            password = "two"
+            "#,
+        )
+        .unwrap();
+
+        let archive = PathU8::from("secret/a.zip");
+        assert_eq!(passwords.candidates("kid", &archive), vec!["one", "two"]);
+        assert!(passwords
+            .candidates("kid", &PathU8::from("public/a.zip"))
+            .is_empty());
+
+        passwords.remember("kid", &archive, "mine");
+        assert_eq!(
+            passwords.candidates("kid", &archive),
+            vec!["mine", "one", "two"]
+        );
+        assert_eq!(passwords.candidates("other", &archive), vec!["one", "two"]);
+
+        assert!(Keyring::empty().candidates(&archive).is_empty());
+        assert_eq!(
+            Keyring::new(&passwords, "kid").candidates(&archive)[0],
+            "mine"
+        );
+
+        assert!(Passwords::parse("[[archive]]\npath = \"/\"\npassword = \"x\"").is_err());
+        assert!(Passwords::parse("[[archive]]\npath = \"a\"").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use super::cache::PathU8;
+
+//id of interned virtual path. only meaningful to table which gave it,
+//and may be given to other path once released
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+//virtual paths interned as small ids, same in debug and release. lookup
+//is by full path so two paths never share an id
+pub struct PathTable {
+    ids: HashMap<PathU8, NodeId>,
+    paths: Vec<Option<PathU8>>,
+    //released slots, reused before growing
+    free: Vec<usize>,
+}
+
+impl PathTable {
+    pub fn new() -> PathTable {
+        PathTable {
+            ids: HashMap::new(),
+            paths: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    //id of path, None if never interned or released
+    pub fn id_of(&self, path: &PathU8) -> Option<NodeId> {
+        self.ids.get(path).cloned()
+    }
+
+    pub fn intern(&mut self, path: &PathU8) -> NodeId {
+        if let Some(id) = self.id_of(path) {
+            return id;
+        }
+
+        let id = match self.free.pop() {
+            Some(slot) => {
+                self.paths[slot] = Some(path.clone());
+                NodeId(slot)
+            }
+            None => {
+                self.paths.push(Some(path.clone()));
+                NodeId(self.paths.len() - 1)
+            }
+        };
+
+        self.ids.insert(path.clone(), id);
+        id
+    }
+
+    pub fn path_of(&self, id: NodeId) -> Option<&PathU8> {
+        self.paths.get(id.0).and_then(|path| path.as_ref())
+    }
+
+    //caller should make sure nothing refers id any more
+    pub fn release(&mut self, id: NodeId) {
+        let path = match self.paths.get_mut(id.0).and_then(|path| path.take()) {
+            Some(path) => path,
+            None => return,
+        };
+
+        self.ids.remove(&path);
+        self.free.push(id.0);
+    }
+
+    //paths interned and not released
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl Default for PathTable {
+    fn default() -> PathTable {
+        PathTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let mut table = PathTable::default();
+        assert!(table.is_empty());
+
+        let a = table.intern(&PathU8::from("a.zip"));
+        let b = table.intern(&PathU8::from("a.zip/b"));
+
+        assert_ne!(a, b);
+        assert_eq!(table.intern(&PathU8::from("a.zip")), a);
+        assert_eq!(table.id_of(&PathU8::from("a.zip/b")), Some(b));
+        assert_eq!(table.id_of(&PathU8::from("a.zip/c")), None);
+        assert_eq!(table.path_of(b), Some(&PathU8::from("a.zip/b")));
+
+        table.release(b);
+        assert_eq!(table.path_of(b), None);
+        assert_eq!(table.id_of(&PathU8::from("a.zip/b")), None);
+        assert_eq!(table.len(), 1);
+
+        //slot is reused, old path does not resolve to it
+        let c = table.intern(&PathU8::from("a.zip/c"));
+        assert_eq!(c, b);
+        assert_eq!(table.path_of(c), Some(&PathU8::from("a.zip/c")));
+        assert_eq!(table.path_of(a), Some(&PathU8::from("a.zip")));
+
+        table.release(b);
+        table.release(b);
+        assert_eq!(table.len(), 1);
+
+        table.release(a);
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use super::cache::{ArchiveCache, PathU8};
+use super::fs::STREAM_ENTRY_MIN;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+pub const DEFAULT_PREFETCH: usize = 4;
+pub const DEFAULT_PREFETCH_BUDGET: usize = 32 * 1024 * 1024;
+
+type Clients = Arc<Mutex<HashMap<IpAddr, Arc<AtomicBool>>>>;
+
+struct Job {
+    client: IpAddr,
+    entries: Vec<(PathU8, usize)>,
+    cancel: Arc<AtomicBool>,
+}
+
+//extracts entries following the one just served into cache on a
+//background thread. each client has at most one job, a newer request
+//(usually of next page, or of another archive) cancels the older one
+pub struct Prefetcher {
+    count: usize,
+    //cancel flag of latest job per client
+    clients: Clients,
+    tx: Mutex<Option<Sender<Job>>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+fn run(cache: &RwLock<ArchiveCache>, job: &Job, budget: usize) {
+    let mut left = budget;
+
+    for (path, size) in job.entries.iter() {
+        if job.cancel.load(Ordering::SeqCst) {
+            trace!("prefetch for {} cancelled", job.client);
+            return;
+        }
+
+        if *size >= STREAM_ENTRY_MIN || *size > left {
+            return;
+        }
+        left -= size;
+
+        if let Err(e) = ArchiveCache::load(cache, path) {
+            //archive evicted or changed, rest is not worth trying
+            debug!("prefetch {:?} failed: {:?}", path, e);
+            return;
+        }
+
+        trace!("prefetched {:?}", path);
+    }
+}
+
+impl Prefetcher {
+    //count 0 disables prefetch. budget is bytes extracted per job
+    pub fn start(cache: Arc<RwLock<ArchiveCache>>, count: usize, budget: usize) -> Prefetcher {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+
+        if count == 0 {
+            return Prefetcher {
+                count,
+                clients,
+                tx: Mutex::new(None),
+                thread: None,
+            };
+        }
+
+        let (tx, rx) = channel::<Job>();
+        let clients_t = clients.clone();
+
+        let thread = std::thread::spawn(move || {
+            for job in rx {
+                run(&cache, &job, budget);
+
+                //forget client unless it asked again meanwhile
+                let mut clients = clients_t.lock().unwrap();
+                if clients
+                    .get(&job.client)
+                    .map_or(false, |latest| Arc::ptr_eq(latest, &job.cancel))
+                {
+                    clients.remove(&job.client);
+                }
+            }
+
+            debug!("prefetch thread exit");
+        });
+
+        Prefetcher {
+            count,
+            clients,
+            tx: Mutex::new(Some(tx)),
+            thread: Some(thread),
+        }
+    }
+
+    //client was just served path, queue entries after it
+    pub fn hint(&self, cache: &RwLock<ArchiveCache>, client: IpAddr, path: &PathU8) {
+        if self.count == 0 {
+            return;
+        }
+
+        let entries = cache.read().unwrap().following_entries(path, self.count);
+
+        //held until flag is in, so worker can not finish job before
+        let mut clients = self.clients.lock().unwrap();
+
+        //newer request cancels older job, even if it queues none
+        if let Some(older) = clients.remove(&client) {
+            older.store(true, Ordering::SeqCst);
+        }
+
+        if entries.is_empty() {
+            return;
+        }
+
+        trace!("prefetch {} entries after {:?}", entries.len(), path);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let queued = self.tx.lock().unwrap().as_ref().map_or(false, |tx| {
+            tx.send(Job {
+                client,
+                entries,
+                cancel: cancel.clone(),
+            })
+            .is_ok()
+        });
+
+        //worker removes it once job is done
+        if queued {
+            clients.insert(client, cancel);
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        for cancel in self.clients.lock().unwrap().values() {
+            cancel.store(true, Ordering::SeqCst);
+        }
+
+        //closing channel ends worker loop
+        self.tx.lock().unwrap().take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn opened() -> Arc<RwLock<ArchiveCache>> {
+        let cache = Arc::new(RwLock::new(ArchiveCache::new(1 << 20, 10)));
+        let d = PathU8::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("test.zip");
+        ArchiveCache::open_archive(&cache, &PathU8::from("test.zip"), &d).unwrap();
+        cache
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let cache = opened();
+        let first = PathU8::from("test.zip/dir");
+
+        let following = cache.read().unwrap().following_entries(&first, 2);
+        assert_eq!(following, vec![(PathU8::from("test.zip/under_root"), 10)]);
+
+        let prefetcher = Prefetcher::start(cache.clone(), 2, DEFAULT_PREFETCH_BUDGET);
+        prefetcher.hint(&cache, "127.0.0.1".parse().unwrap(), &first);
+
+        let loaded = (0..100).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            cache.read().unwrap().quick_try(&following[0].0).is_some()
+        });
+        assert!(loaded);
+
+        //cached ones are not offered again
+        assert!(cache
+            .read()
+            .unwrap()
+            .following_entries(&first, 2)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cache = opened();
+        let client: IpAddr = "127.0.0.1".parse().unwrap();
+
+        //no worker, jobs are only queued and cancel flags checked
+        let (tx, queue) = channel();
+        let prefetcher = Prefetcher {
+            count: 2,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            tx: Mutex::new(Some(tx)),
+            thread: None,
+        };
+
+        prefetcher.hint(&cache, client, &PathU8::from("test.zip/dir"));
+        let first = prefetcher.clients.lock().unwrap()[&client].clone();
+        assert_eq!(queue.try_iter().count(), 1);
+
+        //nothing to queue, older job is still cancelled and no flag left
+        prefetcher.hint(&cache, client, &PathU8::from("other.zip/1.jpg"));
+        assert!(first.load(Ordering::SeqCst));
+        assert!(prefetcher.clients.lock().unwrap().is_empty());
+        assert_eq!(queue.try_iter().count(), 0);
+
+        //cancelled job extracts nothing
+        let job = Job {
+            client,
+            entries: cache
+                .read()
+                .unwrap()
+                .following_entries(&PathU8::from("test.zip/dir"), 2),
+            cancel: first,
+        };
+        assert_eq!(job.entries.len(), 1);
+        run(&cache, &job, DEFAULT_PREFETCH_BUDGET);
+        for (path, _) in job.entries.iter() {
+            assert!(cache.read().unwrap().quick_try(path).is_none());
+        }
+    }
+}
@@ -0,0 +1,484 @@
+extern crate rusqlite;
+extern crate serde_json;
+extern crate sha2;
+
+use super::cache::PathU8;
+use super::catalogue::now;
+use super::fs::Fs;
+use super::store::{db_error, decode};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+//user of requests when authentication is disabled
+pub const ANONYMOUS: &str = "";
+
+//bytes hashed from each end of archive file. enough to tell archives
+//apart, without reading whole file on every lookup
+const FINGERPRINT_SPAN: u64 = 1024 * 1024;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS progress (
+        user TEXT NOT NULL,
+        book TEXT NOT NULL,
+        path TEXT NOT NULL,
+        page INTEGER NOT NULL,
+        completed INTEGER NOT NULL,
+        updated INTEGER NOT NULL,
+        PRIMARY KEY (user, book)
+    );
+    CREATE TABLE IF NOT EXISTS bookmarks (
+        user TEXT NOT NULL,
+        book TEXT NOT NULL,
+        name TEXT NOT NULL,
+        page INTEGER NOT NULL,
+        created INTEGER NOT NULL,
+        PRIMARY KEY (user, book, name)
+    );
+    CREATE TABLE IF NOT EXISTS fingerprints (
+        path TEXT PRIMARY KEY NOT NULL,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        hash TEXT NOT NULL
+    );
+";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Bookmark {
+    pub name: String,
+    pub page: u64,
+    pub created: u64,
+}
+
+//what a user has read of one archive or folder. times are seconds
+//since unix epoch
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<u64>,
+    pub completed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<u64>,
+    pub bookmarks: Vec<Bookmark>,
+}
+
+pub fn to_json(progress: &Progress) -> Vec<u8> {
+    serde_json::to_vec(progress).unwrap()
+}
+
+//what client asked by method and query keys
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Get,
+    //fields not given are kept
+    Set {
+        page: Option<u64>,
+        completed: Option<bool>,
+    },
+    Clear,
+    Bookmark {
+        name: String,
+        page: u64,
+    },
+    Unbookmark {
+        name: String,
+    },
+}
+
+impl Action {
+    //GET reads, PUT (or POST) with page, completed or bookmark keys
+    //writes, DELETE clears progress or drops named bookmark
+    pub fn parse(method: &str, query: Option<&str>) -> Result<Action, String> {
+        let mut page = None;
+        let mut completed = None;
+        let mut bookmark = None;
+
+        for pair in query.unwrap_or("").split('&') {
+            let mut kv = pair.splitn(2, '=');
+            let (k, v) = (kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+
+            match k {
+                "page" => {
+                    page = Some(
+                        v.parse::<u64>()
+                            .map_err(|_| format!("page should be number, got {:?}", v))?,
+                    )
+                }
+                "completed" => {
+                    completed = Some(match v {
+                        "1" | "true" | "yes" => true,
+                        "0" | "false" | "no" => false,
+                        _ => return Err(format!("completed should be 0 or 1, got {:?}", v)),
+                    })
+                }
+                "bookmark" => {
+                    let name = decode(v)?;
+                    if name.is_empty() {
+                        return Err("bookmark needs a name".to_owned());
+                    }
+                    bookmark = Some(name)
+                }
+                _ => {}
+            }
+        }
+
+        match (method, bookmark) {
+            ("GET", _) | ("HEAD", _) => Ok(Action::Get),
+            ("PUT", Some(name)) | ("POST", Some(name)) => Ok(Action::Bookmark {
+                name,
+                page: page.ok_or("bookmark needs page")?,
+            }),
+            ("PUT", None) | ("POST", None) => {
+                if page.is_none() && completed.is_none() {
+                    return Err("nothing to set, expect page or completed".to_owned());
+                }
+                Ok(Action::Set { page, completed })
+            }
+            ("DELETE", Some(name)) => Ok(Action::Unbookmark { name }),
+            ("DELETE", None) => Ok(Action::Clear),
+            (other, _) => Err(format!("{} is not supported for progress", other)),
+        }
+    }
+}
+
+//hash of file size and its both ends, see FINGERPRINT_SPAN
+pub fn fingerprint<R: Read + Seek>(mut r: R) -> std::io::Result<String> {
+    let len = r.seek(SeekFrom::End(0))?;
+
+    let mut hasher = Sha256::new();
+    hasher.input(len.to_string().as_bytes());
+
+    let mut buf = Vec::new();
+
+    r.seek(SeekFrom::Start(0))?;
+    (&mut r).take(FINGERPRINT_SPAN).read_to_end(&mut buf)?;
+
+    //ends overlap for small file, it is hashed as a whole
+    if len > FINGERPRINT_SPAN {
+        let tail = std::cmp::max(FINGERPRINT_SPAN, len - FINGERPRINT_SPAN);
+        r.seek(SeekFrom::Start(tail))?;
+        r.take(FINGERPRINT_SPAN).read_to_end(&mut buf)?;
+    }
+
+    hasher.input(&buf);
+
+    Ok(hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+//reading progress and bookmarks per user. archives on disk are keyed
+//by fingerprint of their contents, so renamed or moved archive keeps
+//its progress. folders and nested archives are keyed by path
+pub struct ProgressStore {
+    conn: Mutex<Connection>,
+}
+
+impl ProgressStore {
+    pub fn open(path: &PathU8) -> std::io::Result<ProgressStore> {
+        ProgressStore::init(Connection::open(path).map_err(db_error)?)
+    }
+
+    fn init(conn: Connection) -> std::io::Result<ProgressStore> {
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        Ok(ProgressStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    //key progress of path is stored under
+    pub fn book_of(&self, fs: &Fs, path: &PathU8) -> std::io::Result<String> {
+        let virtual_path = path.to_str().unwrap_or("").to_owned();
+
+        let file = match fs.physical_file(path) {
+            Some(file) => file,
+            None => return Ok(format!("path:{}", virtual_path)),
+        };
+
+        let attr = file.metadata()?;
+        let size = attr.len() as i64;
+        let mtime = attr
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        let known: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT hash FROM fingerprints WHERE path = ?1 AND size = ?2 AND mtime = ?3",
+                params![virtual_path, size, mtime],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error)?;
+
+        if let Some(hash) = known {
+            return Ok(format!("sha256:{}", hash));
+        }
+
+        //hashed without holding lock
+        let hash = fingerprint(std::fs::File::open(&file)?)?;
+        trace!("fingerprint of {:?} is {}", path, hash);
+
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO fingerprints (path, size, mtime, hash)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![virtual_path, size, mtime, hash],
+            )
+            .map_err(db_error)?;
+
+        Ok(format!("sha256:{}", hash))
+    }
+
+    //path is where book is now, not necessarily where it was read
+    pub fn get(&self, user: &str, book: &str, path: &str) -> std::io::Result<Progress> {
+        let conn = self.conn.lock().unwrap();
+
+        let read: Option<(i64, bool, i64)> = conn
+            .query_row(
+                "SELECT page, completed, updated FROM progress WHERE user = ?1 AND book = ?2",
+                params![user, book],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(db_error)?;
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT name, page, created FROM bookmarks
+                 WHERE user = ?1 AND book = ?2 ORDER BY page, name",
+            )
+            .map_err(db_error)?;
+
+        let bookmarks = stmt
+            .query_map(params![user, book], |row| {
+                Ok(Bookmark {
+                    name: row.get(0)?,
+                    page: row.get::<_, i64>(1)? as u64,
+                    created: row.get::<_, i64>(2)? as u64,
+                })
+            })
+            .map_err(db_error)?
+            .collect::<Result<_, _>>()
+            .map_err(db_error)?;
+
+        Ok(Progress {
+            path: path.to_owned(),
+            page: read.map(|(page, _, _)| page as u64),
+            completed: read.map_or(false, |(_, completed, _)| completed),
+            updated: read.map(|(_, _, updated)| updated as u64),
+            bookmarks,
+        })
+    }
+
+    pub fn set(
+        &self,
+        user: &str,
+        book: &str,
+        path: &str,
+        page: Option<u64>,
+        completed: Option<bool>,
+    ) -> std::io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO progress (user, book, path, page, completed, updated)
+                 VALUES (?1, ?2, ?3, COALESCE(?4, 0), COALESCE(?5, 0), ?6)
+                 ON CONFLICT (user, book) DO UPDATE SET
+                    path = excluded.path,
+                    page = COALESCE(?4, page),
+                    completed = COALESCE(?5, completed),
+                    updated = excluded.updated",
+                params![
+                    user,
+                    book,
+                    path,
+                    page.map(|p| p as i64),
+                    completed,
+                    now() as i64
+                ],
+            )
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    //bookmarks are kept
+    pub fn clear(&self, user: &str, book: &str) -> std::io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM progress WHERE user = ?1 AND book = ?2",
+                params![user, book],
+            )
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    //same name moves bookmark to page
+    pub fn bookmark(&self, user: &str, book: &str, name: &str, page: u64) -> std::io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO bookmarks (user, book, name, page, created)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![user, book, name, page as i64, now() as i64],
+            )
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    //false if there was no such bookmark
+    pub fn unbookmark(&self, user: &str, book: &str, name: &str) -> std::io::Result<bool> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM bookmarks WHERE user = ?1 AND book = ?2 AND name = ?3",
+                params![user, book, name],
+            )
+            .map(|n| n > 0)
+            .map_err(db_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Action::parse("GET", Some("progress")).unwrap(), Action::Get);
+        assert_eq!(
+            Action::parse("PUT", Some("progress&page=12")).unwrap(),
+            Action::Set {
+                page: Some(12),
+                completed: None
+            }
+        );
+        assert_eq!(
+            Action::parse("POST", Some("bookmark=fight+scene&page=3")).unwrap(),
+            Action::Bookmark {
+                name: "fight scene".to_owned(),
+                page: 3
+            }
+        );
+        assert_eq!(
+            Action::parse("DELETE", Some("bookmark=a")).unwrap(),
+            Action::Unbookmark {
+                name: "a".to_owned()
+            }
+        );
+        assert_eq!(
+            Action::parse("DELETE", Some("progress")).unwrap(),
+            Action::Clear
+        );
+
+        assert!(Action::parse("PUT", Some("progress")).is_err());
+        assert!(Action::parse("PUT", Some("progress&page=x")).is_err());
+        assert!(Action::parse("PUT", Some("bookmark=a")).is_err());
+        assert!(Action::parse("PATCH", Some("progress")).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let small = fingerprint(std::io::Cursor::new(b"abc".to_vec())).unwrap();
+        assert_eq!(small.len(), 64);
+        assert_ne!(
+            small,
+            fingerprint(std::io::Cursor::new(b"abd".to_vec())).unwrap()
+        );
+
+        //middle of large file is not hashed
+        let mut large = vec![0u8; 3 * FINGERPRINT_SPAN as usize];
+        let before = fingerprint(std::io::Cursor::new(large.clone())).unwrap();
+        large[FINGERPRINT_SPAN as usize + 1] = 1;
+        assert_eq!(
+            before,
+            fingerprint(std::io::Cursor::new(large.clone())).unwrap()
+        );
+        *large.last_mut().unwrap() = 1;
+        assert_ne!(before, fingerprint(std::io::Cursor::new(large)).unwrap());
+    }
+
+    #[test]
+    fn test_progress() {
+        let store = ProgressStore::open(&PathU8::from(":memory:")).unwrap();
+
+        let empty = store.get("u", "b", "a.zip").unwrap();
+        assert_eq!(empty.page, None);
+        assert!(!empty.completed);
+
+        store.set("u", "b", "a.zip", Some(5), None).unwrap();
+        store.set("u", "b", "a.zip", None, Some(true)).unwrap();
+        store.bookmark("u", "b", "later", 9).unwrap();
+        store.bookmark("u", "b", "first", 2).unwrap();
+
+        let read = store.get("u", "b", "a.zip").unwrap();
+        assert_eq!(read.page, Some(5));
+        assert!(read.completed);
+        assert_eq!(read.bookmarks[0].name, "first");
+        assert_eq!(read.bookmarks[1].page, 9);
+
+        //per user
+        assert_eq!(store.get("other", "b", "a.zip").unwrap().page, None);
+
+        assert!(store.unbookmark("u", "b", "first").unwrap());
+        assert!(!store.unbookmark("u", "b", "first").unwrap());
+        store.clear("u", "b").unwrap();
+
+        let cleared = store.get("u", "b", "a.zip").unwrap();
+        assert_eq!(cleared.page, None);
+        assert_eq!(cleared.bookmarks.len(), 1);
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = std::env::temp_dir().join(format!("progress-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+
+        let fixture = PathU8::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("test.zip");
+        std::fs::copy(&fixture, dir.join("a.zip")).unwrap();
+
+        let fs = Fs::new(&PathU8::from(&dir)).unwrap();
+        let store = ProgressStore::open(&PathU8::from(":memory:")).unwrap();
+
+        let book = store.book_of(&fs, &PathU8::from("a.zip")).unwrap();
+        assert!(book.starts_with("sha256:"));
+        store.set(ANONYMOUS, &book, "a.zip", Some(7), None).unwrap();
+
+        std::fs::rename(dir.join("a.zip"), dir.join("sub").join("b.zip")).unwrap();
+
+        let moved = store.book_of(&fs, &PathU8::from("sub/b.zip")).unwrap();
+        assert_eq!(moved, book);
+        assert_eq!(
+            store.get(ANONYMOUS, &moved, "sub/b.zip").unwrap().page,
+            Some(7)
+        );
+
+        assert_eq!(
+            store.book_of(&fs, &PathU8::from("sub")).unwrap(),
+            "path:sub"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
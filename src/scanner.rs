@@ -0,0 +1,286 @@
+use super::archive_index::ArchiveIndex;
+use super::auth::Scope;
+use super::cache::{join_may_empty, ArchiveCache, PathU8};
+use super::catalogue::{now, Catalogue, Item};
+use super::charset::Charset;
+use super::error;
+use super::fs::Fs;
+use super::limits::Limits;
+use super::listing::EntryKind;
+use super::tarball::Spool;
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+//scanner has its own cache, so walking library does not evict
+//archives being read by clients
+const SCAN_MEM_LIMIT: usize = 16 * 1024 * 1024;
+const SCAN_ARCHIVE_LIMIT: usize = 2;
+
+//how often scanner thread checks for shutdown
+const SCAN_POLL: Duration = Duration::from_millis(200);
+
+struct Walk {
+    fs: Arc<Fs>,
+    cache: RwLock<ArchiveCache>,
+    catalogue: Arc<Catalogue>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Walk {
+    fn count_pages(&self, path: &PathU8) -> std::io::Result<u64> {
+        let mut pages = 0;
+
+        for entry in self.fs.list(&self.cache, path, &Scope::All)? {
+            match entry.kind {
+                EntryKind::Dir => pages += self.count_pages(&path.join(&entry.name))?,
+                EntryKind::File => {
+                    if entry.mime.map_or(false, |m| m.starts_with("image")) {
+                        pages += 1;
+                    }
+                }
+                //nested archive is another title
+                EntryKind::Archive => {}
+            }
+        }
+
+        Ok(pages)
+    }
+
+    fn scan_archive(&self, path: &PathU8, item: &mut Item) -> std::io::Result<()> {
+        if let Some((size, mtime, Some(pages))) = self.catalogue.stamp(&item.path)? {
+            if size == item.size && mtime == item.mtime {
+                trace!("{:?} unchanged", path);
+                item.pages = Some(pages);
+                return Ok(());
+            }
+        }
+
+        let counted = self.count_pages(path);
+        self.cache.write().unwrap().invalid_path(path);
+
+        match counted {
+            Ok(pages) => item.pages = Some(pages),
+            //still listed, without page count
+            Err(e) => warn!("can not count pages of {:?}: {:?}", path, e),
+        }
+
+        Ok(())
+    }
+
+    //record dirs and archives under path, recursively
+    fn scan(&self, path: &PathU8) -> std::io::Result<()> {
+        let entries = match self.fs.list(&self.cache, path, &Scope::All) {
+            Ok(entries) => entries,
+            Err(error::Error::NotFound(_)) => {
+                return self.catalogue.remove(path.to_str().unwrap_or(""));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut names = Vec::new();
+
+        for entry in entries {
+            if self.stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            if entry.kind == EntryKind::File {
+                continue;
+            }
+
+            let child = join_may_empty(path, &PathU8::from(&entry.name));
+
+            let mut item = Item {
+                path: child.to_str().unwrap_or("").to_owned(),
+                name: entry.name.clone(),
+                kind: entry.kind,
+                pages: None,
+                size: entry.size,
+                mtime: entry.mtime,
+                added: now(),
+            };
+
+            if entry.kind == EntryKind::Archive {
+                self.scan_archive(&child, &mut item)?;
+            }
+
+            self.catalogue.upsert(&item)?;
+
+            if entry.kind == EntryKind::Dir {
+                if let Err(e) = self.scan(&child) {
+                    warn!("scan {:?} failed: {:?}", child, e);
+                }
+            }
+
+            names.push(entry.name);
+        }
+
+        self.catalogue
+            .retain_children(path.to_str().unwrap_or(""), &names)
+    }
+
+    fn run(&self, rx: &Receiver<PathU8>) {
+        info!("scanning library");
+        if let Err(e) = self.scan(&PathU8::new()) {
+            warn!("scan library failed: {:?}", e);
+        }
+        info!("library scanned");
+
+        while !self.stop.load(Ordering::SeqCst) {
+            let first = match rx.recv_timeout(SCAN_POLL) {
+                Ok(path) => path,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            //a change is handled by rescanning its parent
+            let mut dirs = BTreeSet::new();
+            for changed in std::iter::once(first).chain(rx.try_iter()) {
+                dirs.insert(changed.parent().map(PathU8::from).unwrap_or_default());
+            }
+
+            //sorted, so ancestor comes before its children
+            let mut done: Vec<PathU8> = Vec::new();
+            for dir in dirs {
+                if done.iter().any(|d| dir.starts_with(d)) {
+                    continue;
+                }
+
+                debug!("rescan {:?}", dir);
+                if let Err(e) = self.scan(&dir) {
+                    warn!("rescan {:?} failed: {:?}", dir, e);
+                }
+                done.push(dir);
+            }
+        }
+
+        debug!("scanner thread exit");
+    }
+}
+
+//keeps catalogue in sync with library: full scan on start, then
+//rescans what watcher reports. stops and joins thread on drop
+pub struct Scanner {
+    tx: Sender<PathU8>,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Scanner {
+    pub fn start(
+        fs: Arc<Fs>,
+        catalogue: Arc<Catalogue>,
+        index: Option<Arc<ArchiveIndex>>,
+        spool: Option<Arc<Spool>>,
+        charset: Charset,
+        limits: Limits,
+    ) -> Scanner {
+        //names decoded as served, and index records shared with server
+        let mut cache = ArchiveCache::new(SCAN_MEM_LIMIT, SCAN_ARCHIVE_LIMIT)
+            .with_charset(charset)
+            .with_limits(limits);
+        if let Some(index) = index {
+            cache = cache.with_index(index);
+        }
+        //tarballs unpacked while scanning are ready when first browsed
+        if let Some(spool) = spool {
+            cache = cache.with_spool(spool);
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let walk = Walk {
+            fs,
+            cache: RwLock::new(cache),
+            catalogue,
+            stop: stop.clone(),
+        };
+
+        let (tx, rx) = channel();
+
+        let thread = std::thread::spawn(move || walk.run(&rx));
+
+        Scanner {
+            tx,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    //virtual paths sent here are rescanned
+    pub fn changes(&self) -> Sender<PathU8> {
+        self.tx.clone()
+    }
+}
+
+impl Drop for Scanner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::catalogue::Query;
+
+    fn walk(catalogue: &Arc<Catalogue>) -> Walk {
+        let root = PathU8::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+
+        Walk {
+            fs: Arc::new(Fs::new(&root).unwrap()),
+            cache: RwLock::new(ArchiveCache::new(SCAN_MEM_LIMIT, SCAN_ARCHIVE_LIMIT)),
+            catalogue: catalogue.clone(),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn search(catalogue: &Catalogue, q: &str) -> Vec<Item> {
+        catalogue
+            .search(&Query::parse(&PathU8::new(), Some(q)).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_scan() {
+        let catalogue = Arc::new(Catalogue::open(&PathU8::from(":memory:")).unwrap());
+        let walk = walk(&catalogue);
+
+        //stale one from last run
+        catalogue
+            .upsert(&Item {
+                path: "gone.zip".to_owned(),
+                name: "gone.zip".to_owned(),
+                kind: EntryKind::Archive,
+                pages: None,
+                size: None,
+                mtime: None,
+                added: 0,
+            })
+            .unwrap();
+
+        walk.scan(&PathU8::new()).unwrap();
+
+        let found = search(&catalogue, "search=test");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, "test.zip");
+        assert_eq!(found[0].kind, EntryKind::Archive);
+        //no images inside
+        assert_eq!(found[0].pages, Some(0));
+        assert!(found[0].size.is_some());
+
+        assert_eq!(search(&catalogue, "search=nested").len(), 1);
+        assert!(search(&catalogue, "search=gone").is_empty());
+
+        //opened archives are released
+        assert_eq!(walk.cache.read().unwrap().mem_size(), 0);
+    }
+}
@@ -6,21 +6,68 @@ extern crate simple_logger;
 
 use pretty_bytes::converter::convert;
 
+use super::archive_index::ArchiveIndex;
+use super::auth::{self, Auth, Scope, Visibility};
 use super::cache;
+use super::catalogue::{self, Catalogue};
+use super::config::{Config, ImageConfig};
+use super::disk_cache::{key_of, DiskCache};
+use super::error::Error;
 use super::fs;
+use super::http;
+use super::listing;
+use super::metadata;
+use super::password::{Keyring, Passwords};
+use super::prefetch::Prefetcher;
+use super::progress::{self, ProgressStore};
+use super::scanner::Scanner;
+use super::tarball::Spool;
+use super::thumbnail;
+use super::tls::{self, Tls};
+use super::transcode;
+use futures::future::Either;
 use futures::sync::oneshot::Receiver;
+use futures::{Sink, Stream};
+use hyper::header::HeaderName;
+use hyper::header::{
+    HeaderValue, ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, HOST, LAST_MODIFIED, LOCATION, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+};
 use hyper::rt::Future;
-use hyper::service::service_fn_ok;
-use hyper::{Body, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn, service_fn_ok};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use image::GenericImageView;
-use std::sync::{Arc, Mutex};
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, RwLock};
+
+const MIME_TEXT: &str = "text/plain; charset=utf-8";
+
+//seconds client should wait before retrying 503
+const RETRY_AFTER_SECS: &str = "1";
+
+//tells client to prompt for password of archive, not to log in again
+const ARCHIVE_PASSWORD: &str = "x-archive-password";
+
+//largest request body read, only password form is posted
+const MAX_POSTED: usize = 4096;
+
+#[derive(Serialize)]
+struct Failure<'a> {
+    status: u16,
+    error: &'a str,
+}
+
+//quality to re-encode with, None if image should be sent as is
+fn conversion_quality(bin: &[u8], mime: &str, settings: &ImageConfig) -> Option<u8> {
+    if !settings.convert {
+        return None;
+    }
 
-fn convert_if_needed(bin: &[u8], mime: String) -> Option<std::io::Cursor<Vec<u8>>> {
     if !mime.starts_with("image") {
         return None;
     }
 
-    if bin.len() < 300 * 1024 {
+    if bin.len() < settings.convert_threshold {
         return None;
     }
 
@@ -30,7 +77,19 @@ fn convert_if_needed(bin: &[u8], mime: String) -> Option<std::io::Cursor<Vec<u8>
 
     //png size reduce is huge, but jpg is not. we need to lower q very much
     //for jpg
-    let q = if mime.ends_with("jpeg") { 60 } else { 80 };
+    if mime.ends_with("jpeg") {
+        Some(settings.jpeg_quality)
+    } else {
+        Some(settings.png_quality)
+    }
+}
+
+fn convert_if_needed(
+    bin: &[u8],
+    mime: String,
+    settings: &ImageConfig,
+) -> Option<std::io::Cursor<Vec<u8>>> {
+    let q = conversion_quality(bin, &mime, settings)?;
 
     let decode = image::load_from_memory(bin);
     if decode.is_err() {
@@ -59,12 +118,580 @@ fn convert_if_needed(bin: &[u8], mime: String) -> Option<std::io::Cursor<Vec<u8>
     Some(reduced)
 }
 
+//image on disk or streamed out of archive read into memory, so it can
+//be converted. larger ones are left as they are
+fn buffered(content: fs::Content, max: usize, path: &cache::PathU8) -> Result<fs::Content, Error> {
+    let reader: Box<Read> = match content {
+        fs::Content::File(file, len) if len <= max as u64 => Box::new(file),
+        fs::Content::Entry(entry, len) if len <= max as u64 => Box::new(entry),
+        other => return Ok(other),
+    };
+
+    //declared size of entry may be a lie
+    let mut bin = Vec::new();
+    reader
+        .take(max as u64 + 1)
+        .read_to_end(&mut bin)
+        .map_err(|e| Error::io(format!("read {:?}", path), e))?;
+
+    if bin.len() > max {
+        return Err(Error::TooLarge(format!(
+            "{:?} is larger than declared",
+            path
+        )));
+    }
+
+    Ok(fs::Content::Mem(bin))
+}
+
+//same source converted with same quality gives same result, so keep
+//it on disk if disk cache enabled
+fn convert_cached(
+    bin: &[u8],
+    mime: String,
+    settings: &ImageConfig,
+    disk: Option<&DiskCache>,
+    path: &cache::PathU8,
+    meta: &fs::Meta,
+) -> Option<Vec<u8>> {
+    let disk = match disk {
+        None => return convert_if_needed(bin, mime, settings).map(|c| c.into_inner()),
+        Some(disk) => disk,
+    };
+
+    let q = conversion_quality(bin, &mime, settings)?;
+
+    let key = key_of(&[
+        "convert",
+        path.to_str().unwrap_or(""),
+        &http::etag(meta.mtime, meta.crc, bin.len() as u64),
+        &q.to_string(),
+    ]);
+
+    if let Some(hit) = disk.get(&key) {
+        return Some(hit);
+    }
+
+    let converted = convert_if_needed(bin, mime, settings)?.into_inner();
+
+    if let Err(e) = disk.put(&key, &converted) {
+        warn!("can not save converted {:?}: {:?}", path, e);
+    }
+
+    Some(converted)
+}
+
+//client asked transcode, disk cached like automatic conversion
+fn transcode_cached(
+    bin: &[u8],
+    mime: &str,
+    params: &transcode::Params,
+    settings: &ImageConfig,
+    disk: Option<&DiskCache>,
+    path: &cache::PathU8,
+    meta: &fs::Meta,
+) -> std::io::Result<Option<(Vec<u8>, transcode::OutputFormat)>> {
+    let disk = match disk {
+        None => return transcode::transcode(bin, mime, params, settings),
+        Some(disk) => disk,
+    };
+
+    let key = key_of(&[
+        "transcode",
+        path.to_str().unwrap_or(""),
+        &http::etag(meta.mtime, meta.crc, bin.len() as u64),
+        &params.key(),
+    ]);
+
+    if let Some(hit) = disk.get(&key) {
+        return Ok(Some((hit, params.target(mime))));
+    }
+
+    let converted = transcode::transcode(bin, mime, params, settings)?;
+
+    if let Some((ref out, _)) = converted {
+        if let Err(e) = disk.put(&key, out) {
+            warn!("can not save transcoded {:?}: {:?}", path, e);
+        }
+    }
+
+    Ok(converted)
+}
+
+//value of key in urlencoded pairs, still encoded
+fn pair_value<'a>(pairs: &'a str, key: &str) -> Option<&'a str> {
+    pairs.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next()? == key {
+            Some(kv.next().unwrap_or(""))
+        } else {
+            None
+        }
+    })
+}
+
+fn query_value<'a>(req: &'a Request<Body>, key: &str) -> Option<&'a str> {
+    pair_value(req.uri().query()?, key)
+}
+
+fn form_value<'a>(posted: &'a [u8], key: &str) -> Option<&'a str> {
+    pair_value(std::str::from_utf8(posted).ok()?, key)
+}
+
+//small error body, json for clients asking for json listings
+fn failure(req: &Request<Body>, status: StatusCode, msg: &str) -> Response<Body> {
+    let mut builder = Response::builder();
+    builder.status(status);
+
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        builder.header(RETRY_AFTER, RETRY_AFTER_SECS);
+    }
+
+    if wants_json(req) {
+        let failed = Failure {
+            status: status.as_u16(),
+            error: msg,
+        };
+
+        builder
+            .header(CONTENT_TYPE, listing::MIME_JSON)
+            .body(Body::from(serde_json::to_vec(&failed).unwrap()))
+            .unwrap()
+    } else {
+        builder
+            .header(CONTENT_TYPE, MIME_TEXT)
+            .body(Body::from(msg.to_owned()))
+            .unwrap()
+    }
+}
+
+//status by failure mode. cause is only logged, it may tell paths on disk
+fn error_response(req: &Request<Body>, e: &Error, path: &cache::PathU8) -> Response<Body> {
+    let status = match e {
+        Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+        Error::Forbidden(_) => StatusCode::FORBIDDEN,
+        Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::PasswordRequired(_) => StatusCode::FORBIDDEN,
+        //limits are fixed, so retrying would not help. same as
+        //archive that can not be decoded
+        Error::TooLarge(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        Error::Io(..) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    if status.is_server_error() {
+        error!("{:?} failed: {}", path, e);
+    } else {
+        debug!("{:?} failed: {}", path, e);
+    }
+
+    let mut response = failure(req, status, e.reason());
+
+    if let Error::PasswordRequired(_) = e {
+        response.headers_mut().insert(
+            HeaderName::from_static(ARCHIVE_PASSWORD),
+            HeaderValue::from_static("required"),
+        );
+    }
+
+    response
+}
+
+fn unauthorized(req: &Request<Body>) -> Response<Body> {
+    let mut unauthorized = failure(req, StatusCode::UNAUTHORIZED, "login required");
+    unauthorized.headers_mut().insert(
+        WWW_AUTHENTICATE,
+        HeaderValue::from_str(&format!(
+            "Basic realm=\"{}\", charset=\"UTF-8\"",
+            auth::REALM
+        ))
+        .unwrap(),
+    );
+    unauthorized
+}
+
+//plain http listener only sends clients over to https
+fn redirect_response(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = req.headers().get(HOST).and_then(|v| v.to_str().ok());
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+
+    match tls::redirect_location(host, https_port, path) {
+        Some(location) => Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .unwrap(),
+        None => failure(req, StatusCode::BAD_REQUEST, "host required"),
+    }
+}
+
+fn search_response(
+    req: &Request<Body>,
+    catalogue: Option<&Catalogue>,
+    scope: &Scope,
+    folder: &cache::PathU8,
+) -> Response<Body> {
+    let catalogue = match catalogue {
+        Some(catalogue) => catalogue,
+        None => return failure(req, StatusCode::NOT_FOUND, "search is not enabled"),
+    };
+
+    let query = match catalogue::Query::parse(folder, req.uri().query()) {
+        Ok(query) => query,
+        Err(msg) => return failure(req, StatusCode::BAD_REQUEST, &msg),
+    };
+
+    match catalogue.search(&query) {
+        Ok(mut items) => {
+            items.retain(|i| scope.visibility(&cache::PathU8::from(&i.path)) == Visibility::Inside);
+
+            Response::builder()
+                .header(CONTENT_TYPE, listing::MIME_JSON)
+                .body(Body::from(catalogue::to_json(&items)))
+                .unwrap()
+        }
+        Err(e) => {
+            error!("search {:?} failed: {:?}", query, e);
+            failure(req, StatusCode::INTERNAL_SERVER_ERROR, "search failed")
+        }
+    }
+}
+
+fn progress_response(
+    req: &Request<Body>,
+    fs: &fs::Fs,
+    store: Option<&ProgressStore>,
+    user: &str,
+    path: &cache::PathU8,
+) -> Response<Body> {
+    let store = match store {
+        Some(store) => store,
+        None => return failure(req, StatusCode::NOT_FOUND, "progress is not enabled"),
+    };
+
+    let action = match progress::Action::parse(req.method().as_str(), req.uri().query()) {
+        Ok(action) => action,
+        Err(msg) => return failure(req, StatusCode::BAD_REQUEST, &msg),
+    };
+
+    //nothing on disk along the path
+    if fs.mtime(path).ok().and_then(|m| m).is_none() {
+        return failure(req, StatusCode::NOT_FOUND, "nothing");
+    }
+
+    let at = path.to_str().unwrap_or("");
+
+    let done = store.book_of(fs, path).and_then(|book| {
+        let found = match action {
+            progress::Action::Get => true,
+            progress::Action::Set { page, completed } => {
+                store.set(user, &book, at, page, completed)?;
+                true
+            }
+            progress::Action::Clear => {
+                store.clear(user, &book)?;
+                true
+            }
+            progress::Action::Bookmark { ref name, page } => {
+                store.bookmark(user, &book, name, page)?;
+                true
+            }
+            progress::Action::Unbookmark { ref name } => store.unbookmark(user, &book, name)?,
+        };
+
+        Ok((found, store.get(user, &book, at)?))
+    });
+
+    match done {
+        Ok((true, read)) => Response::builder()
+            .header(CONTENT_TYPE, listing::MIME_JSON)
+            .body(Body::from(progress::to_json(&read)))
+            .unwrap(),
+        Ok((false, _)) => failure(req, StatusCode::NOT_FOUND, "no such bookmark"),
+        Err(e) => {
+            error!("progress of {:?} failed: {:?}", path, e);
+            failure(req, StatusCode::INTERNAL_SERVER_ERROR, "progress failed")
+        }
+    }
+}
+
+//POST with password key checks it against archive, and remembers it
+//for user if it opens. only logged in users, anonymous ones would share
+fn password_response(
+    req: &Request<Body>,
+    posted: &[u8],
+    fs: &fs::Fs,
+    cache: &RwLock<cache::ArchiveCache>,
+    passwords: &Passwords,
+    logged_in: bool,
+    user: &str,
+    path: &cache::PathU8,
+) -> Response<Body> {
+    if req.method() != Method::POST {
+        return failure(
+            req,
+            StatusCode::METHOD_NOT_ALLOWED,
+            "password must be posted",
+        );
+    }
+
+    if !logged_in {
+        return failure(
+            req,
+            StatusCode::FORBIDDEN,
+            "login required to submit password",
+        );
+    }
+
+    if posted.len() > MAX_POSTED {
+        return failure(
+            req,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "password form too large",
+        );
+    }
+
+    //posted as form, never in uri where it would be logged
+    let raw = match form_value(posted, "password") {
+        Some(raw) => raw,
+        None => return failure(req, StatusCode::BAD_REQUEST, "password missing"),
+    };
+    let password =
+        match percent_encoding::percent_decode(raw.replace('+', " ").as_bytes()).decode_utf8() {
+            Ok(password) => password.into_owned(),
+            Err(_) => return failure(req, StatusCode::BAD_REQUEST, "password is not utf-8"),
+        };
+
+    match fs.unlock(cache, path, &password) {
+        Ok(true) => {
+            passwords.remember(user, path, &password);
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap()
+        }
+        Ok(false) => error_response(
+            req,
+            &Error::PasswordRequired(format!("wrong password of {:?}", path)),
+            path,
+        ),
+        Err(e) => error_response(req, &e, path),
+    }
+}
+
+fn metadata_response(
+    req: &Request<Body>,
+    fs: &fs::Fs,
+    cache: &RwLock<cache::ArchiveCache>,
+    scope: &Scope,
+    path: &cache::PathU8,
+) -> Response<Body> {
+    let described = metadata::children(fs, cache, path, scope).and_then(|names| {
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+        let meta = metadata::describe(fs, cache, path, &names)?;
+        Ok(metadata::to_json(path, &meta, &names))
+    });
+
+    match described {
+        Ok(json) => Response::builder()
+            .header(CONTENT_TYPE, listing::MIME_JSON)
+            .body(Body::from(json))
+            .unwrap(),
+        Err(e) => error_response(req, &e, path),
+    }
+}
+
+fn thumbnail_response(
+    req: &Request<Body>,
+    fs: &fs::Fs,
+    cache: &RwLock<cache::ArchiveCache>,
+    thumbnailer: &thumbnail::Thumbnailer,
+    path: &cache::PathU8,
+    size: &str,
+) -> Response<Body> {
+    let (w, h) = match thumbnail::parse_size(size) {
+        Some(wh) => wh,
+        None => return failure(req, StatusCode::BAD_REQUEST, "thumb should be WxH"),
+    };
+
+    match thumbnailer.thumbnail(fs, cache, path, w, h) {
+        Ok((bin, mtime)) => {
+            let meta = fs::Meta {
+                mime: "image/jpeg".to_owned(),
+                is_dir: false,
+                mtime,
+                crc: None,
+            };
+            respond(req, fs::Content::Mem(bin.to_vec()), &meta.mime, &meta)
+        }
+        Err(e) => error_response(req, &e, path),
+    }
+}
+
+//json listing is opt-in, by ?format=json or Accept header
+fn wants_json(req: &Request<Body>) -> bool {
+    let by_query = query_value(req, "format") == Some("json");
+
+    let by_accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.contains(listing::MIME_JSON));
+
+    by_query || by_accept
+}
+
+//send reader in chunks from a separated thread, at most STREAM_QUEUE
+//chunks are buffered if client is slower than disk
+fn stream_body(mut reader: Box<Read + Send>, skip: u64, take: u64) -> Body {
+    let (tx, rx) = futures::sync::mpsc::channel::<std::io::Result<Vec<u8>>>(fs::STREAM_QUEUE);
+
+    std::thread::spawn(move || {
+        let mut tx = tx;
+
+        if skip > 0 {
+            if let Err(e) = std::io::copy(&mut (&mut reader).take(skip), &mut std::io::sink()) {
+                let _ = tx.send(Err(e)).wait();
+                return;
+            }
+        }
+
+        let mut reader = reader.take(take);
+
+        loop {
+            let mut chunk = vec![0; fs::STREAM_CHUNK];
+
+            let item = match reader.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Ok(chunk)
+                }
+                Err(e) => Err(e),
+            };
+
+            let failed = item.is_err();
+
+            match tx.send(item).wait() {
+                Ok(next) => tx = next,
+                //client gone
+                Err(_) => return,
+            }
+
+            if failed {
+                return;
+            }
+        }
+    });
+
+    Body::wrap_stream(rx.then(|item| match item {
+        Ok(Ok(chunk)) => Ok(chunk),
+        Ok(Err(e)) => Err(e),
+        Err(()) => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "stream closed",
+        )),
+    }))
+}
+
+//body of [start, start + len) of content
+fn body_of(content: fs::Content, start: u64, len: u64) -> Body {
+    match content {
+        fs::Content::Mem(mut bin) => {
+            bin.truncate((start + len) as usize);
+            Body::from(bin.split_off(start as usize))
+        }
+        fs::Content::File(mut file, _) => {
+            if let Err(e) = file.seek(SeekFrom::Start(start)) {
+                error!("seek failed {:?}", e);
+                return Body::empty();
+            }
+            stream_body(Box::new(file), 0, len)
+        }
+        fs::Content::Entry(reader, _) => stream_body(reader, start, len),
+    }
+}
+
+fn respond(
+    req: &Request<Body>,
+    content: fs::Content,
+    mime: &str,
+    meta: &fs::Meta,
+) -> Response<Body> {
+    let is_head = req.method() == Method::HEAD;
+    let len = content.len();
+
+    let mut builder = Response::builder();
+    builder.header(CONTENT_TYPE, mime);
+
+    if meta.is_dir {
+        builder.header(CONTENT_LENGTH, len);
+        let body = if is_head {
+            Body::empty()
+        } else {
+            body_of(content, 0, len)
+        };
+        return builder.status(StatusCode::OK).body(body).unwrap();
+    }
+
+    let etag = http::etag(meta.mtime, meta.crc, len);
+
+    builder.header(ETAG, etag.as_str());
+    builder.header(ACCEPT_RANGES, "bytes");
+
+    if let Some(mtime) = meta.mtime {
+        builder.header(LAST_MODIFIED, http::fmt_date(mtime));
+    }
+
+    if http::not_modified(req.headers(), &etag, meta.mtime) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    match http::range_for(req.headers(), len, &etag, meta.mtime) {
+        http::RangeSpec::Full => {
+            builder.header(CONTENT_LENGTH, len);
+            let body = if is_head {
+                Body::empty()
+            } else {
+                body_of(content, 0, len)
+            };
+            builder.status(StatusCode::OK).body(body).unwrap()
+        }
+        http::RangeSpec::Partial(start, end) => {
+            builder.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+            builder.header(CONTENT_LENGTH, end - start + 1);
+
+            let body = if is_head {
+                Body::empty()
+            } else {
+                body_of(content, start, end - start + 1)
+            };
+
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .body(body)
+                .unwrap()
+        }
+        http::RangeSpec::Unsatisfiable => {
+            builder.header(CONTENT_RANGE, format!("bytes */{}", len));
+            builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+}
+
 pub fn run_server_from_listener(
     listener: std::net::TcpListener,
-    fs_root: &cache::PathU8,
+    config: &Config,
     rx: Receiver<()>,
 ) {
-    let res = fs::Fs::new(fs_root);
+    let res = fs::Fs::with_roots(&config.roots);
 
     if res.is_err() {
         error!("{:?}", res.err());
@@ -73,91 +700,565 @@ pub fn run_server_from_listener(
 
     let filesystem = Arc::new(res.unwrap());
 
-    let cache = Arc::new(Mutex::new(cache::ArchiveCache::new(100, 100)));
+    let index = match config.index_dir.as_ref() {
+        None => None,
+        Some(dir) => match ArchiveIndex::open(dir) {
+            Ok(index) => Some(Arc::new(index)),
+            Err(e) => {
+                error!("can not open archive index {:?}: {:?}", dir, e);
+                std::process::exit(1);
+            }
+        },
+    };
 
-    crossbeam::thread::scope(|s| {
-        s.spawn(|_| {
-            let mut cache_t1 = cache.clone();
+    let spool = match config.tar_spool_dir.as_ref() {
+        None => None,
+        Some(dir) => match Spool::open(dir, config.tar_spool_limit as u64) {
+            Ok(spool) => Some(Arc::new(spool)),
+            Err(e) => {
+                error!("can not open tar spool {:?}: {:?}", dir, e);
+                std::process::exit(1);
+            }
+        },
+    };
 
-            //filesystem.start_watch(&*cache_t1);
-        });
-    })
-    .unwrap();
+    let mut archive_cache = cache::ArchiveCache::new(config.mem_limit, config.archive_limit)
+        .with_eviction(config.eviction)
+        .with_charset(config.archive_charset)
+        .with_limits(config.limits);
+
+    if let Some(index) = index.clone() {
+        archive_cache = archive_cache.with_index(index);
+    }
+    if let Some(spool) = spool.clone() {
+        archive_cache = archive_cache.with_spool(spool);
+    }
+
+    let cache = Arc::new(RwLock::new(archive_cache));
+
+    let catalogue = match config.catalogue.as_ref() {
+        None => None,
+        Some(path) => match Catalogue::open(path) {
+            Ok(catalogue) => Some(Arc::new(catalogue)),
+            Err(e) => {
+                error!("can not open catalogue {:?}: {:?}", path, e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let scanner = catalogue.as_ref().map(|catalogue| {
+        Scanner::start(
+            filesystem.clone(),
+            catalogue.clone(),
+            index.clone(),
+            spool.clone(),
+            config.archive_charset,
+            config.limits,
+        )
+    });
+
+    //serving still works without watch, but changed archives are
+    //not noticed until evicted
+    let watch = match filesystem.start_watch(cache.clone(), scanner.as_ref().map(Scanner::changes))
+    {
+        Ok(watch) => Some(watch),
+        Err(e) => {
+            warn!("{}", e);
+            None
+        }
+    };
+
+    let progress_store = match config.progress.as_ref() {
+        None => None,
+        Some(path) => match ProgressStore::open(path) {
+            Ok(store) => Some(Arc::new(store)),
+            Err(e) => {
+                error!("can not open progress store {:?}: {:?}", path, e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let auth = match config.users.as_ref() {
+        None => None,
+        Some(path) => match Auth::load(path) {
+            Ok(auth) => Some(Arc::new(auth)),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let tls = match (config.tls_cert.as_ref(), config.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => match Tls::load(cert, key) {
+            Ok(tls) => Some(Arc::new(tls)),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    let reload = tls.clone().map(tls::start_reload);
+
+    let passwords = match config.passwords.as_ref() {
+        None => Arc::new(Passwords::new()),
+        Some(path) => match Passwords::load(path) {
+            Ok(passwords) => Arc::new(passwords),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let image_config = Arc::new(config.image.clone());
+    let sort_order = config.sort;
+
+    let disk_cache = match config.disk_cache_dir.as_ref() {
+        None => None,
+        Some(dir) => match DiskCache::open(dir, config.disk_cache_limit as u64) {
+            Ok(disk) => Some(Arc::new(disk)),
+            Err(e) => {
+                error!("can not open disk cache {:?}: {:?}", dir, e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let thumbnailer = Arc::new(thumbnail::Thumbnailer::new(
+        config.image.thumb_cache,
+        config.image.thumb_quality,
+        config.image.thumb_max,
+        disk_cache.clone(),
+    ));
 
-    let new_service = move || {
+    let prefetcher = Arc::new(Prefetcher::start(
+        cache.clone(),
+        config.prefetch,
+        config.prefetch_budget,
+    ));
+
+    let new_service = make_service_fn(move |conn: &tls::Conn| {
+        let client = conn.remote_addr().ip();
         let arc = cache.clone();
         let fs = filesystem.clone();
+        let image_config = image_config.clone();
+        let thumbnailer = thumbnailer.clone();
+        let disk_cache = disk_cache.clone();
+        let prefetcher = prefetcher.clone();
+        let catalogue = catalogue.clone();
+        let progress_store = progress_store.clone();
+        let auth = auth.clone();
+        let passwords = passwords.clone();
+
+        let handle = Arc::new(
+            move |req: &Request<Body>, posted: &[u8]| -> Response<Body> {
+                let raw_path = req.uri().path();
+
+                let path = match percent_encoding::percent_decode(raw_path.as_bytes()).decode_utf8()
+                {
+                    Ok(path) => path,
+                    Err(e) => {
+                        debug!("{:?} is not utf8: {}", raw_path, e);
+                        return failure(req, StatusCode::BAD_REQUEST, "path is not utf-8");
+                    }
+                };
+
+                trace!("access {}", path);
+
+                let rel = cache::PathU8::from(path.trim_start_matches('/'));
+                trace!("convert to rel {:?}", rel);
+
+                let all = Scope::All;
+
+                let (user, scope) = match auth.as_ref() {
+                    None => (progress::ANONYMOUS, &all),
+                    Some(auth) => {
+                        let authorization = req
+                            .headers()
+                            .get(AUTHORIZATION)
+                            .and_then(|v| v.to_str().ok());
+
+                        match auth.authenticate(authorization) {
+                            Some(found) => found,
+                            None => return unauthorized(req),
+                        }
+                    }
+                };
 
-        service_fn_ok(move |req| {
-            let raw_path = req.uri().path();
+                let visibility = match scope.check(&rel) {
+                    Ok(visibility) => visibility,
+                    Err(e) => return error_response(req, &e.into(), &rel),
+                };
 
-            let path = percent_encoding::percent_decode(raw_path.as_bytes())
-                .decode_utf8()
-                .unwrap();
+                if query_value(req, "search").is_some() {
+                    return search_response(req, catalogue.as_ref().map(|c| &**c), scope, &rel);
+                }
+
+                //per path features are shared by users, path itself must be
+                //allowed, not just on the way to allowed ones
+                let per_path = ["progress", "bookmark", "meta", "thumb", "password"];
+                if visibility != Visibility::Inside
+                    && per_path.iter().any(|key| query_value(req, key).is_some())
+                {
+                    return failure(req, StatusCode::FORBIDDEN, "not allowed");
+                }
+
+                if query_value(req, "progress").is_some() || query_value(req, "bookmark").is_some()
+                {
+                    return progress_response(
+                        req,
+                        &fs,
+                        progress_store.as_ref().map(|s| &**s),
+                        user,
+                        &rel,
+                    );
+                }
 
-            trace!("access {}", path);
+                if query_value(req, "meta").is_some() {
+                    return metadata_response(req, &fs, &arc, scope, &rel);
+                }
 
-            let rel = cache::PathU8::from(path[1..].to_owned());
-            trace!("convert to rel {:?}", rel);
+                if let Some(size) = query_value(req, "thumb") {
+                    return thumbnail_response(req, &fs, &arc, &thumbnailer, &rel, size);
+                }
 
-            let mut cursor = std::io::Cursor::new(Vec::new());
+                if query_value(req, "password").is_some() {
+                    return password_response(
+                        req,
+                        posted,
+                        &fs,
+                        &arc,
+                        &passwords,
+                        auth.is_some(),
+                        user,
+                        &rel,
+                    );
+                }
 
-            let res = fs.read(&*arc, &rel, &mut cursor);
+                let keys = Keyring::new(&passwords, user);
+                let (mut content, meta) = match fs.open_with_keys(&*arc, &rel, scope, keys) {
+                    Ok(opened) => opened,
+                    Err(e) => return error_response(req, &e, &rel),
+                };
+
+                let order = match sort_order.with_query(req.uri().query()) {
+                    Ok(order) => order,
+                    Err(msg) => return failure(req, StatusCode::BAD_REQUEST, &msg),
+                };
+
+                //plain listing is already in natural name order
+                if meta.is_dir && (wants_json(req) || !order.by_name_only()) {
+                    return match fs.list(&*arc, &rel, scope) {
+                        Ok(mut entries) => {
+                            order.sort(&mut entries);
+
+                            //reads header of every image, so only on request
+                            if query_value(req, "dimensions") == Some("1") {
+                                fs.add_dimensions(&*arc, &rel, &mut entries);
+                            }
+
+                            if order.by_name_only() {
+                                metadata::order_entries(&fs, &arc, &rel, &mut entries);
+                            }
+
+                            if wants_json(req) {
+                                respond(
+                                    req,
+                                    fs::Content::Mem(listing::to_json(&rel, &entries)),
+                                    listing::MIME_JSON,
+                                    &meta,
+                                )
+                            } else {
+                                respond(
+                                    req,
+                                    fs::Content::Mem(listing::to_text(&entries)),
+                                    &meta.mime,
+                                    &meta,
+                                )
+                            }
+                        }
+                        Err(e) => error_response(req, &e, &rel),
+                    };
+                }
+
+                if meta.is_dir {
+                    if let fs::Content::Mem(ref mut names) = content {
+                        if let Some(ordered) = metadata::order_listing(&fs, &arc, &rel, names) {
+                            *names = ordered;
+                        }
+                    }
+                } else {
+                    prefetcher.hint(&*arc, client, &rel);
+                }
+
+                let mut mime = meta.mime.clone();
+
+                let requested = if mime.starts_with("image") {
+                    let accept = req.headers().get(ACCEPT).and_then(|v| v.to_str().ok());
+                    transcode::Params::parse(req.uri().query(), accept, &mime, &image_config)
+                } else {
+                    Ok(None)
+                };
+
+                let requested = match requested {
+                    Ok(requested) => requested,
+                    Err(msg) => return failure(req, StatusCode::BAD_REQUEST, &msg),
+                };
+
+                //files and streamed entries are read whole to be converted,
+                //if no larger than entries kept in memory
+                let wants_conversion = requested.is_some()
+                    || (image_config.convert
+                        && content.len() >= image_config.convert_threshold as u64);
+                if mime.starts_with("image") && wants_conversion {
+                    content = match buffered(content, fs::STREAM_ENTRY_MIN, &rel) {
+                        Ok(buffered) => buffered,
+                        Err(e) => return error_response(req, &e, &rel),
+                    };
+                }
+
+                let in_mem = if let fs::Content::Mem(_) = content {
+                    true
+                } else {
+                    false
+                };
+                if requested.is_some() && !in_mem {
+                    return error_response(
+                        req,
+                        &Error::TooLarge(format!("{:?} is too large to transcode", rel)),
+                        &rel,
+                    );
+                }
 
-            if let Ok(mut mime) = res {
-                let converted = convert_if_needed(cursor.get_ref(), mime.clone());
+                if let (Some(params), fs::Content::Mem(ref mut bin)) = (requested, &mut content) {
+                    match transcode_cached(
+                        bin,
+                        &mime,
+                        &params,
+                        &image_config,
+                        disk_cache.as_ref().map(|d| &**d),
+                        &rel,
+                        &meta,
+                    ) {
+                        Ok(Some((newbin, format))) => {
+                            *bin = newbin;
+                            mime = format.mime().to_owned();
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("transcode {:?} failed: {:?}", rel, e);
+                            return failure(
+                                req,
+                                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                                "can not transcode",
+                            );
+                        }
+                    }
+                } else if let fs::Content::Mem(ref mut bin) = content {
+                    let converted = convert_cached(
+                        bin,
+                        mime.clone(),
+                        &image_config,
+                        disk_cache.as_ref().map(|d| &**d),
+                        &rel,
+                        &meta,
+                    );
 
-                if let Some(newbin) = converted {
-                    cursor = newbin;
-                    mime = tree_magic::from_u8(cursor.get_ref());
+                    if let Some(newbin) = converted {
+                        *bin = newbin;
+                        mime = tree_magic::from_u8(bin);
+                    }
                 }
 
-                let mut resp = Response::new(Body::from(cursor.get_ref().clone()));
+                let mut response = respond(req, content, &mime, &meta);
 
-                *resp.status_mut() = StatusCode::OK;
+                //format may be picked by Accept, caches must tell clients apart
+                if meta.mime.starts_with("image") {
+                    response
+                        .headers_mut()
+                        .insert(VARY, HeaderValue::from_static("accept"));
+                }
 
-                let header = resp.headers_mut();
+                response
+            },
+        );
 
-                header.insert(
-                    "content-type",
-                    hyper::header::HeaderValue::from_str(&mime).unwrap(),
-                );
-                header.insert(
-                    "content-length",
-                    hyper::header::HeaderValue::from_str(&cursor.get_ref().len().to_string())
-                        .unwrap(),
-                );
+        futures::future::ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+            let handle = handle.clone();
 
-                return resp;
+            //only password is posted, everything else is answered from uri
+            if req.method() != Method::POST || query_value(&req, "password").is_none() {
+                return Either::A(futures::future::ok(handle(&req, &[])));
             }
 
-            let mut not_found = Response::new(Body::from("nothing"));
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            let (parts, body) = req.into_parts();
+            Either::B(
+                body.fold(Vec::new(), |mut posted, chunk| {
+                    //keep one byte over limit so handler can tell
+                    if posted.len() <= MAX_POSTED {
+                        let room = MAX_POSTED + 1 - posted.len();
+                        posted.extend_from_slice(&chunk[..chunk.len().min(room)]);
+                    }
+                    futures::future::ok::<_, hyper::Error>(posted)
+                })
+                .map(move |posted| handle(&Request::from_parts(parts, Body::empty()), &posted)),
+            )
+        }))
+    });
+
+    let local = match listener.local_addr() {
+        Ok(local) => local,
+        Err(e) => {
+            error!("can not get listen address: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-            not_found
-        })
+    let incoming = match tls::incoming(listener, tls.clone()) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            error!("can not listen on {}: {}", local, e);
+            std::process::exit(1);
+        }
     };
 
-    let server = Server::from_tcp(listener).unwrap().serve(new_service);
+    let server = Server::builder(incoming).serve(new_service);
 
-    let local = server.local_addr();
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Listening on {}://{}", scheme, local);
 
-    info!("Listening on http://{}", local);
+    let redirect = config.http_redirect.map(|addr| {
+        let builder = std::net::TcpListener::bind(addr)
+            .map_err(|e| e.to_string())
+            .and_then(|listener| Server::from_tcp(listener).map_err(|e| e.to_string()));
+
+        let builder = match builder {
+            Ok(builder) => builder,
+            Err(e) => {
+                error!("can not listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+
+        let https_port = local.port();
+        let server = builder.serve(move || {
+            service_fn_ok(move |req: Request<Body>| redirect_response(&req, https_port))
+        });
 
-    let fut = server.with_graceful_shutdown(rx).map_err(|e| {
-        error!("server error: {}", e);
-        panic!();
+        info!("Redirecting http://{} to https", server.local_addr());
+        server
     });
 
-    hyper::rt::run(fut);
+    //both listeners stop on same signal
+    let rx = rx.shared();
+
+    let fut = server
+        .with_graceful_shutdown(rx.clone().map(|_| ()))
+        .map_err(|e| {
+            error!("server error: {}", e);
+            panic!();
+        });
+
+    let redirect_fut = match redirect {
+        None => Either::A(futures::future::ok(())),
+        Some(redirect) => Either::B(
+            redirect
+                .with_graceful_shutdown(rx.map(|_| ()))
+                .map_err(|e| error!("redirect server error: {}", e)),
+        ),
+    };
+
+    hyper::rt::run(fut.join(redirect_fut).map(|_| ()));
+
+    drop(reload);
+    drop(watch);
+    drop(scanner);
 }
 
-pub fn run_server(addr: std::net::SocketAddr, fs_root: &cache::PathU8) -> std::io::Result<bool> {
-    let listener = std::net::TcpListener::bind(addr)?;
+pub fn run_server(config: &Config) -> std::io::Result<bool> {
+    let listener = std::net::TcpListener::bind(config.bind)?;
 
     let (_tx, rx) = futures::sync::oneshot::channel::<()>();
 
-    run_server_from_listener(listener, fs_root, rx);
+    run_server_from_listener(listener, config, rx);
 
     Ok(true)
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn status_of(e: Error) -> Response<Body> {
+        let req = Request::get("/a.zip/1.jpg").body(Body::empty()).unwrap();
+        error_response(&req, &e, &cache::PathU8::from("a.zip/1.jpg"))
+    }
+
+    #[test]
+    fn test_error_status() {
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "disk");
+        let m = String::new;
+        let cases = vec![
+            (Error::BadRequest(m()), StatusCode::BAD_REQUEST),
+            (Error::Forbidden(m()), StatusCode::FORBIDDEN),
+            (Error::NotFound(m()), StatusCode::NOT_FOUND),
+            (Error::Unsupported(m()), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (Error::Unavailable(m()), StatusCode::SERVICE_UNAVAILABLE),
+            (Error::PasswordRequired(m()), StatusCode::FORBIDDEN),
+            (Error::TooLarge(m()), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (Error::Io(m(), io), StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+
+        //clients only need to handle these
+        let known = [400, 403, 404, 415, 500, 503];
+        for (e, status) in cases {
+            assert!(known.contains(&status.as_u16()), "{}", status);
+            assert_eq!(status_of(e).status(), status);
+        }
+    }
+
+    #[test]
+    fn test_error_headers() {
+        let busy = status_of(Error::Unavailable(String::new()));
+        assert_eq!(busy.headers()[RETRY_AFTER], RETRY_AFTER_SECS);
+
+        let locked = status_of(Error::PasswordRequired(String::new()));
+        assert_eq!(locked.headers()[ARCHIVE_PASSWORD], "required");
+
+        let missing = status_of(Error::NotFound(String::new()));
+        assert!(missing.headers().get(RETRY_AFTER).is_none());
+        assert!(missing.headers().get(ARCHIVE_PASSWORD).is_none());
+    }
+
+    #[test]
+    fn test_error_through_io() {
+        //fs code still on io::Result must not turn 503 into 500
+        let io: std::io::Error = Error::Unavailable("evicted".to_owned()).into();
+        let busy = status_of(Error::from(io));
+        assert_eq!(busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_error_json() {
+        let req = Request::get("/a.zip?format=json")
+            .body(Body::empty())
+            .unwrap();
+        let response = error_response(
+            &req,
+            &Error::NotFound(String::new()),
+            &cache::PathU8::from("a.zip"),
+        );
+        assert_eq!(response.headers()[CONTENT_TYPE], listing::MIME_JSON);
+
+        let body = response.into_body().concat2().wait().unwrap();
+        let failed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(failed["status"], 404);
+        assert_eq!(failed["error"], "nothing");
+    }
+}
@@ -0,0 +1,280 @@
+use super::listing::{EntryInfo, EntryKind};
+
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+fn take_digits(it: &mut Peekable<Chars>) -> String {
+    let mut ret = String::new();
+    while let Some(c) = it.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        ret.push(*c);
+        it.next();
+    }
+    ret
+}
+
+fn cmp_numeric(lhs: &str, rhs: &str) -> Ordering {
+    let l = lhs.trim_start_matches('0');
+    let r = rhs.trim_start_matches('0');
+
+    //longer digit run is larger number, no overflow for any length
+    l.len()
+        .cmp(&r.len())
+        .then_with(|| l.cmp(r))
+        //"01" after "1" to keep order total
+        .then_with(|| lhs.len().cmp(&rhs.len()))
+}
+
+//numeric aware, case insensitive compare: page2 < Page10
+pub fn natural_cmp(lhs: &str, rhs: &str) -> Ordering {
+    let mut l = lhs.chars().peekable();
+    let mut r = rhs.chars().peekable();
+
+    loop {
+        match (l.peek().cloned(), r.peek().cloned()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(lc), Some(rc)) => {
+                if lc.is_ascii_digit() && rc.is_ascii_digit() {
+                    let ord = cmp_numeric(&take_digits(&mut l), &take_digits(&mut r));
+                    if ord != Ordering::Equal {
+                        return ord;
+                    }
+                    continue;
+                }
+
+                let ord = lc.to_lowercase().cmp(rc.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                l.next();
+                r.next();
+            }
+        }
+    }
+
+    //only differ in case, still need stable order
+    lhs.cmp(rhs)
+}
+
+//names of dir in natural order, for plain text listing
+pub fn sorted_names<'a, I: Iterator<Item = &'a String>>(names: I) -> Vec<&'a String> {
+    let mut ret: Vec<&String> = names.collect();
+    ret.sort_by(|a, b| natural_cmp(a, b));
+    ret
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    Name,
+    Mtime,
+    Size,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortKey, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "name" => Ok(SortKey::Name),
+            "mtime" | "time" => Ok(SortKey::Mtime),
+            "size" => Ok(SortKey::Size),
+            other => Err(format!(
+                "unknown sort key {:?}, expect name, mtime or size",
+                other
+            )),
+        }
+    }
+}
+
+//how listing entries are ordered. entries with same key are
+//always in natural name order, so result is deterministic
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub dirs_first: bool,
+    pub descending: bool,
+}
+
+impl Default for SortOrder {
+    fn default() -> SortOrder {
+        SortOrder {
+            key: SortKey::Name,
+            dirs_first: false,
+            descending: false,
+        }
+    }
+}
+
+fn flag(key: &str, v: &str) -> Result<bool, String> {
+    match v {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(format!("{} should be 0 or 1, got {:?}", key, v)),
+    }
+}
+
+impl SortOrder {
+    //client may override server default by sort, dirs_first
+    //and order (asc or desc) query keys
+    pub fn with_query(&self, query: Option<&str>) -> Result<SortOrder, String> {
+        let mut ret = *self;
+
+        for pair in query.unwrap_or("").split('&') {
+            let mut kv = pair.splitn(2, '=');
+            let (k, v) = match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => (k, v),
+                _ => continue,
+            };
+
+            match k {
+                "sort" => ret.key = v.parse()?,
+                "dirs_first" => ret.dirs_first = flag(k, v)?,
+                "order" => {
+                    ret.descending = match v {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => return Err(format!("order should be asc or desc, got {:?}", v)),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(ret)
+    }
+
+    //names alone are enough, see sorted_names
+    pub fn by_name_only(&self) -> bool {
+        *self == SortOrder::default()
+    }
+
+    pub fn cmp(&self, lhs: &EntryInfo, rhs: &EntryInfo) -> Ordering {
+        //archives are browsed like dirs
+        let is_file = |e: &EntryInfo| e.kind == EntryKind::File;
+        let group = if self.dirs_first {
+            is_file(lhs).cmp(&is_file(rhs))
+        } else {
+            Ordering::Equal
+        };
+
+        let by_key = match self.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Mtime => lhs.mtime.cmp(&rhs.mtime),
+            SortKey::Size => lhs.size.cmp(&rhs.size),
+        }
+        .then_with(|| natural_cmp(&lhs.name, &rhs.name));
+
+        //descending reverses key only, dirs stay first
+        let by_key = if self.descending {
+            by_key.reverse()
+        } else {
+            by_key
+        };
+
+        group.then(by_key)
+    }
+
+    pub fn sort(&self, entries: &mut [EntryInfo]) {
+        entries.sort_by(|a, b| self.cmp(a, b));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec![
+            "page10.jpg",
+            "Page2.jpg",
+            "page1.jpg",
+            "page02.jpg",
+            "cover.jpg",
+            "page1a.jpg",
+            "99999999999999999999999.jpg",
+            "1.jpg",
+        ];
+
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            vec![
+                "1.jpg",
+                "99999999999999999999999.jpg",
+                "cover.jpg",
+                "page1.jpg",
+                "page1a.jpg",
+                "Page2.jpg",
+                "page02.jpg",
+                "page10.jpg",
+            ]
+        );
+
+        assert_eq!(natural_cmp("a", "A"), "a".cmp("A"));
+        assert_eq!(natural_cmp("same", "same"), Ordering::Equal);
+    }
+
+    fn entry(name: &str, kind: EntryKind, size: u64, mtime: u64) -> EntryInfo {
+        let mut ret = EntryInfo::new(name, kind);
+        ret.size = Some(size);
+        ret.mtime = Some(mtime);
+        ret
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let mut entries = vec![
+            entry("page10.jpg", EntryKind::File, 1, 30),
+            entry("page2.jpg", EntryKind::File, 3, 10),
+            entry("sub", EntryKind::Dir, 0, 20),
+            entry("b.cbz", EntryKind::Archive, 2, 20),
+        ];
+
+        let names = |entries: &[EntryInfo]| -> Vec<String> {
+            entries.iter().map(|e| e.name.clone()).collect()
+        };
+
+        let order = SortOrder::default();
+        order.sort(&mut entries);
+        assert_eq!(names(&entries), ["b.cbz", "page2.jpg", "page10.jpg", "sub"]);
+
+        let order = order.with_query(Some("dirs_first=1")).unwrap();
+        order.sort(&mut entries);
+        assert_eq!(names(&entries), ["b.cbz", "sub", "page2.jpg", "page10.jpg"]);
+
+        let order = order.with_query(Some("sort=mtime&dirs_first=0")).unwrap();
+        order.sort(&mut entries);
+        assert_eq!(names(&entries), ["page2.jpg", "b.cbz", "sub", "page10.jpg"]);
+
+        let order = order.with_query(Some("sort=size&order=desc")).unwrap();
+        order.sort(&mut entries);
+        assert_eq!(names(&entries), ["page2.jpg", "b.cbz", "page10.jpg", "sub"]);
+
+        let order = order
+            .with_query(Some("sort=name&dirs_first=1&order=desc"))
+            .unwrap();
+        order.sort(&mut entries);
+        assert_eq!(names(&entries), ["sub", "b.cbz", "page10.jpg", "page2.jpg"]);
+
+        assert!(order.with_query(Some("sort=color")).is_err());
+        assert!(order.with_query(Some("order=up")).is_err());
+        assert!(SortOrder::default()
+            .with_query(Some("thumb=1"))
+            .unwrap()
+            .by_name_only());
+    }
+
+    #[test]
+    fn test_sorted_names() {
+        let names = vec!["p10".to_owned(), "P2".to_owned(), "p1".to_owned()];
+        assert_eq!(sorted_names(names.iter()), vec!["p1", "P2", "p10"]);
+    }
+}
@@ -0,0 +1,17 @@
+extern crate rusqlite;
+
+use std::io::{Error, ErrorKind};
+
+//helpers shared by sqlite backed stores, catalogue and progress
+
+pub fn db_error(e: rusqlite::Error) -> Error {
+    Error::new(ErrorKind::Other, format!("database: {}", e))
+}
+
+//value of query pair, '+' stands for space
+pub fn decode(v: &str) -> Result<String, String> {
+    percent_encoding::percent_decode(v.replace('+', " ").as_bytes())
+        .decode_utf8()
+        .map(|s| s.into_owned())
+        .map_err(|_| format!("{:?} is not utf8", v))
+}
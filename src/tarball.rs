@@ -0,0 +1,349 @@
+extern crate bzip2;
+extern crate flate2;
+extern crate xz2;
+extern crate zstd;
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::archive_index::Stamp;
+use super::cache::Binary;
+use super::disk_cache::{key_of, DiskCache, Pin};
+use super::error::{self, Error};
+use super::limits::{Budget, Limits};
+
+//tar inside a compression stream. archive library reads plain tar
+//only, and compressed stream can not be entered at entry offset, so
+//tarball is unpacked once: into spool on disk if there is one,
+//otherwise into memory held by opened archive, as long as it fits
+//mem_limit. entries are then read by offset like any other archive
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+const TAR_BLOCK: usize = 512;
+const TAR_MAGIC_AT: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+//plain tar (.tar, .cbt) is known to is_archive by extension
+const TARBALL_SUFFIXES: [&str; 9] = [
+    ".tgz", ".tbz", ".tbz2", ".txz", ".tzst", ".tar.gz", ".tar.bz2", ".tar.xz", ".tar.zst",
+];
+
+//leading bytes of file without known extension, enough for one block
+const HEAD_LEN: u64 = 64 * 1024;
+
+pub const DEFAULT_SPOOL_LIMIT: usize = 4 * 1024 * 1024 * 1024;
+
+pub fn compression_of(head: &[u8]) -> Option<Compression> {
+    if head.starts_with(&[0x1f, 0x8b]) {
+        Some(Compression::Gzip)
+    } else if head.starts_with(b"BZh") {
+        Some(Compression::Bzip2)
+    } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Some(Compression::Xz)
+    } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Some(Compression::Zstd)
+    } else {
+        None
+    }
+}
+
+fn decoder<'a, R: Read + 'a>(
+    compression: Compression,
+    inner: R,
+) -> std::io::Result<Box<Read + 'a>> {
+    Ok(match compression {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(inner)),
+        Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(inner)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(inner)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(inner)?),
+    })
+}
+
+pub fn is_tarball_name(name: &Path) -> bool {
+    name.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| {
+            TARBALL_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        })
+}
+
+//compressed, and first block decompressed is a tar header. head may
+//be only leading part of the stream
+pub fn is_tarball(head: &[u8]) -> bool {
+    let compression = match compression_of(head) {
+        Some(compression) => compression,
+        None => return false,
+    };
+
+    let mut block = [0; TAR_BLOCK];
+    decoder(compression, head)
+        .and_then(|mut d| d.read_exact(&mut block))
+        .map_or(false, |_| {
+            &block[TAR_MAGIC_AT..TAR_MAGIC_AT + TAR_MAGIC.len()] == TAR_MAGIC
+        })
+}
+
+pub fn is_tarball_file(path: &Path) -> bool {
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(HEAD_LEN).read_to_end(&mut head))
+        .is_ok()
+        && is_tarball(&head)
+}
+
+//whole plain tar in memory, counted against budget of request.
+//compressed_len bounds it by max_ratio
+pub fn unpack<R: Read>(
+    compression: Compression,
+    compressed: R,
+    compressed_len: usize,
+    name: &str,
+    budget: &mut Budget,
+) -> error::Result<Binary> {
+    let mut reader = decoder(compression, compressed)
+        .map_err(|e| Error::Unsupported(format!("can not decompress {:?}: {}", name, e)))?;
+
+    budget.read(&mut reader, name, 0, compressed_len)
+}
+
+//plain tars unpacked from tarball files, kept on disk and bounded like
+//disk cache. keyed by path and stamp of tarball, so changed one is
+//unpacked again
+pub struct Spool {
+    cache: DiskCache,
+}
+
+impl Spool {
+    pub fn open(dir: &PathBuf, limit: u64) -> std::io::Result<Spool> {
+        Ok(Spool {
+            cache: DiskCache::open(dir, limit)?,
+        })
+    }
+
+    fn unpack(
+        &self,
+        tarball: &Path,
+        compression: Compression,
+        stamp: &Stamp,
+        limits: &Limits,
+    ) -> error::Result<PlainFile> {
+        let key = key_of(&[&tarball.to_string_lossy(), &stamp.to_key()]);
+
+        //held by opened archive, and by streams reading it again by path
+        let pin = Some(Arc::new(self.cache.pin(&key)));
+
+        if let Some(path) = self.cache.locate(&key) {
+            return Ok(PlainFile { path, _pin: pin });
+        }
+
+        debug!("unpack {:?} into spool", tarball);
+
+        let file = File::open(tarball).map_err(|e| Error::io(format!("open {:?}", tarball), e))?;
+        let size = file
+            .metadata()
+            .map_err(|e| Error::io(format!("stat {:?}", tarball), e))?
+            .len();
+
+        //entry limits are for memory, tar on disk only goes by ratio
+        let max = size.saturating_mul(limits.max_ratio as u64);
+
+        let broken = |e| Error::Unsupported(format!("can not decompress {:?}: {}", tarball, e));
+
+        let mut reader = decoder(compression, BufReader::new(file)).map_err(broken)?;
+
+        let path = self
+            .cache
+            .put_from(&key, &mut reader, max)
+            .map_err(broken)?
+            .ok_or_else(|| {
+                Error::TooLarge(format!(
+                    "{:?} unpacks to more than max_ratio or spool limit allows",
+                    tarball
+                ))
+            })?;
+
+        Ok(PlainFile { path, _pin: pin })
+    }
+}
+
+//plain archive on disk. one in spool is pinned there while any clone
+//is alive
+#[derive(Clone)]
+pub struct PlainFile {
+    pub path: PathBuf,
+    _pin: Option<Arc<Pin>>,
+}
+
+//what archive library should read for archive file
+pub enum Plain {
+    File(PlainFile),
+    Mem(Binary),
+}
+
+//archive file itself, or plain tar unpacked from it. without spool,
+//tar is unpacked into memory no larger than mem_limit
+pub fn plain_of(
+    archive: &Path,
+    spool: Option<&Spool>,
+    limits: &Limits,
+    mem_limit: usize,
+) -> error::Result<Plain> {
+    let open = |e| Error::io(format!("open {:?}", archive), e);
+
+    let mut head = Vec::new();
+    File::open(archive)
+        .and_then(|file| file.take(6).read_to_end(&mut head))
+        .map_err(open)?;
+
+    let compression = match compression_of(&head) {
+        None => {
+            return Ok(Plain::File(PlainFile {
+                path: archive.to_path_buf(),
+                _pin: None,
+            }))
+        }
+        Some(compression) => compression,
+    };
+
+    if let (Some(spool), Some(stamp)) = (spool, Stamp::of(archive)) {
+        return spool
+            .unpack(archive, compression, &stamp, limits)
+            .map(Plain::File);
+    }
+
+    //decompressed as read, compressed file is never held whole
+    let file = File::open(archive).map_err(open)?;
+    let size = file
+        .metadata()
+        .map_err(|e| Error::io(format!("stat {:?}", archive), e))?
+        .len() as usize;
+
+    let in_mem = Limits {
+        max_entry_size: std::cmp::min(limits.max_entry_size, mem_limit),
+        ..*limits
+    };
+
+    unpack(
+        compression,
+        BufReader::new(file),
+        size,
+        &archive.to_string_lossy(),
+        &mut Budget::new(in_mem),
+    )
+    .map(Plain::Mem)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const MEM: usize = 1024 * 1024;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tarball")
+            .join(name)
+    }
+
+    #[test]
+    fn test_detect() {
+        let plain = std::fs::read(fixture("book.cbt")).unwrap();
+
+        for name in &["book.tar.gz", "book.tar.bz2", "book.tar.xz", "book.tar.zst"] {
+            let path = fixture(name);
+            assert!(is_tarball_name(&path), "{}", name);
+            assert!(is_tarball_file(&path), "{}", name);
+
+            let compressed = std::fs::read(&path).unwrap();
+            let compression = compression_of(&compressed).unwrap();
+            let unpacked = unpack(
+                compression,
+                &compressed[..],
+                compressed.len(),
+                name,
+                &mut Budget::new(Limits::default()),
+            )
+            .unwrap();
+            assert_eq!(unpacked, plain);
+        }
+
+        assert!(!is_tarball_name(&fixture("book.cbt")));
+        assert!(!is_tarball_file(&fixture("book.cbt")));
+        assert!(!is_tarball_name(Path::new("notes.gz")));
+
+        //not compressed, or too short to hold a tar header
+        assert!(!is_tarball(b"PK\x03\x04"));
+        assert!(!is_tarball(b"\x1f\x8b"));
+    }
+
+    #[test]
+    fn test_plain_of() {
+        let tarball = fixture("book.tar.xz");
+        let plain = std::fs::read(fixture("book.cbt")).unwrap();
+
+        match plain_of(&fixture("book.cbt"), None, &Limits::default(), MEM).unwrap() {
+            Plain::File(file) => assert_eq!(file.path, fixture("book.cbt")),
+            Plain::Mem(_) => panic!("plain tar is read in place"),
+        }
+
+        match plain_of(&tarball, None, &Limits::default(), MEM).unwrap() {
+            Plain::Mem(bin) => assert_eq!(bin, plain),
+            Plain::File(_) => panic!("no spool, unpacked in memory"),
+        }
+
+        let temp_dir = |name: &str| {
+            let dir =
+                std::env::temp_dir().join(format!("archive-cache-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            dir
+        };
+
+        let dir = temp_dir("spool");
+        let spool = Spool::open(&dir, 1024 * 1024).unwrap();
+
+        let unpacked = match plain_of(&tarball, Some(&spool), &Limits::default(), MEM).unwrap() {
+            Plain::File(file) => file.path,
+            Plain::Mem(_) => panic!("unpacked into spool"),
+        };
+        assert!(unpacked.starts_with(&dir));
+        assert_eq!(std::fs::read(&unpacked).unwrap(), plain);
+
+        //second open finds it in spool
+        match plain_of(&tarball, Some(&spool), &Limits::default(), MEM).unwrap() {
+            Plain::File(file) => assert_eq!(file.path, unpacked),
+            Plain::Mem(_) => panic!("unpacked into spool"),
+        }
+
+        let strict = Limits {
+            max_ratio: 2,
+            ..Limits::default()
+        };
+        let strict_dir = temp_dir("spool-strict");
+        let strict_spool = Spool::open(&strict_dir, 1024 * 1024).unwrap();
+        for spool in &[None, Some(&strict_spool)] {
+            match plain_of(&fixture("book.tar.gz"), *spool, &strict, MEM) {
+                Err(Error::TooLarge(_)) => {}
+                other => panic!("{:?}", other.map(|_| ())),
+            }
+        }
+
+        //unpacked tar must fit in memory without spool
+        match plain_of(&tarball, None, &Limits::default(), plain.len() - 1) {
+            Err(Error::TooLarge(_)) => {}
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&strict_dir);
+    }
+}
@@ -0,0 +1,312 @@
+extern crate lru;
+
+use super::auth::Scope;
+use super::cache::{join_may_empty, ArchiveCache, PathU8};
+use super::disk_cache::{key_of, DiskCache};
+use super::error::{self, Error};
+use super::fs::Fs;
+use super::http;
+use super::listing::{mime_from_name, EntryInfo, EntryKind};
+use super::metadata;
+
+use lru::LruCache;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+//how deep to look for cover under folders, a comic archive usually
+//holds one dir with all pages
+const COVER_SEARCH_DEPTH: usize = 2;
+
+type ThumbKey = (PathU8, u32, u32);
+
+//what thumbnail was rendered from: path itself, or cover found under
+//it, which may change without mtime of folder changing
+#[derive(Clone, PartialEq)]
+struct Source {
+    mtime: Option<SystemTime>,
+    cover: PathU8,
+    cover_mtime: Option<SystemTime>,
+}
+
+//parse "WxH" or "N" (N x N box)
+pub fn parse_size(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.splitn(2, |c| c == 'x' || c == 'X');
+
+    let w = parts.next()?.trim().parse::<u32>().ok()?;
+    let h = match parts.next() {
+        Some(h) => h.trim().parse::<u32>().ok()?,
+        None => w,
+    };
+
+    if w == 0 || h == 0 {
+        return None;
+    }
+
+    Some((w, h))
+}
+
+fn is_image(info: &EntryInfo) -> bool {
+    if info.kind != EntryKind::File {
+        return false;
+    }
+
+    let by_name = mime_from_name(&info.name).map_or(false, |m| m.starts_with("image"));
+
+    by_name || info.mime.as_ref().map_or(false, |m| m.starts_with("image"))
+}
+
+fn is_cover(info: &EntryInfo) -> bool {
+    is_image(info)
+        && PathU8::from(&info.name)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .map_or(false, |s| s.eq_ignore_ascii_case("cover"))
+}
+
+//scaled down images, kept apart from file cache so browsing many
+//archives does not evict pages being read
+pub struct Thumbnailer {
+    //validated by mtime of path and of its cover on disk
+    cache: Mutex<LruCache<ThumbKey, (Source, Arc<Vec<u8>>)>>,
+    quality: u8,
+    max_size: u32,
+    disk: Option<Arc<DiskCache>>,
+}
+
+impl Thumbnailer {
+    pub fn new(
+        entries: usize,
+        quality: u8,
+        max_size: u32,
+        disk: Option<Arc<DiskCache>>,
+    ) -> Thumbnailer {
+        Thumbnailer {
+            cache: Mutex::new(LruCache::new(entries)),
+            quality,
+            max_size,
+            disk,
+        }
+    }
+
+    //image used to represent path: itself if image, else cover of
+    //archive or folder
+    pub fn find_cover(
+        &self,
+        fs: &Fs,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        depth: usize,
+    ) -> error::Result<Option<PathU8>> {
+        //already in natural order
+        let entries = fs.list(cache, path, &Scope::All)?;
+
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+
+        //ComicInfo knows better than names
+        let hinted = metadata::comic_info(fs, cache, path, &names)
+            .and_then(|meta| meta.cover(&names))
+            .map(|i| &entries[i]);
+
+        let picked = hinted
+            .or_else(|| entries.iter().find(|e| is_cover(e)))
+            .or_else(|| entries.iter().find(|e| is_image(e)));
+
+        if let Some(picked) = picked {
+            return Ok(Some(join_may_empty(path, &PathU8::from(&picked.name))));
+        }
+
+        if depth == 0 {
+            return Ok(None);
+        }
+
+        for nested in entries.iter().filter(|e| e.kind != EntryKind::File) {
+            let nested_path = join_may_empty(path, &PathU8::from(&nested.name));
+
+            //broken nested archive should not hide covers of siblings
+            if let Ok(Some(found)) = self.find_cover(fs, cache, &nested_path, depth - 1) {
+                return Ok(Some(found));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn render(&self, bin: &[u8], w: u32, h: u32) -> error::Result<Vec<u8>> {
+        let img = image::load_from_memory(bin).map_err(|e| Error::Unsupported(format!("{}", e)))?;
+
+        let mut out = Vec::new();
+
+        img.thumbnail(w, h)
+            .write_to(&mut out, image::ImageOutputFormat::JPEG(self.quality))
+            .map_err(|e| {
+                let e = std::io::Error::new(std::io::ErrorKind::Other, e.to_string());
+                Error::io(format!("encode thumbnail {}x{}", w, h), e)
+            })?;
+
+        Ok(out)
+    }
+
+    //jpeg fits into w x h box, and newer mtime of path and its cover.
+    //thumbnails are shared by all users, caller makes sure path is in
+    //user's scope
+    pub fn thumbnail(
+        &self,
+        fs: &Fs,
+        cache: &RwLock<ArchiveCache>,
+        path: &PathU8,
+        w: u32,
+        h: u32,
+    ) -> error::Result<(Arc<Vec<u8>>, Option<SystemTime>)> {
+        let w = std::cmp::min(w, self.max_size);
+        let h = std::cmp::min(h, self.max_size);
+
+        let mtime = fs.mtime(path)?;
+        let key = (path.clone(), w, h);
+
+        let cached = self.cache.lock().unwrap().get(&key).cloned();
+        if let Some((source, bin)) = cached {
+            //cover found last time is checked again, new one added to
+            //folder changes folder mtime anyway
+            if source.mtime == mtime && fs.mtime(&source.cover)? == source.cover_mtime {
+                trace!("thumbnail cache hit {:?}", key);
+                return Ok((bin, std::cmp::max(mtime, source.cover_mtime)));
+            }
+        }
+
+        let (_, meta) = fs.open(cache, path, &Scope::All)?;
+
+        let cover = if meta.is_dir {
+            self.find_cover(fs, cache, path, COVER_SEARCH_DEPTH)?
+                .ok_or_else(|| Error::NotFound(format!("no cover under {:?}", path)))?
+        } else {
+            path.clone()
+        };
+
+        let source = Source {
+            mtime,
+            cover_mtime: fs.mtime(&cover)?,
+            cover,
+        };
+        let newer = std::cmp::max(mtime, source.cover_mtime);
+
+        let disk_key = key_of(&[
+            "thumb",
+            path.to_str().unwrap_or(""),
+            &http::etag(mtime, None, 0),
+            source.cover.to_str().unwrap_or(""),
+            &http::etag(source.cover_mtime, None, 0),
+            &w.to_string(),
+            &h.to_string(),
+            &self.quality.to_string(),
+        ]);
+
+        if let Some(disk) = self.disk.as_ref() {
+            if let Some(bin) = disk.get(&disk_key) {
+                let thumb = Arc::new(bin);
+                self.cache.lock().unwrap().put(key, (source, thumb.clone()));
+                return Ok((thumb, newer));
+            }
+        }
+
+        trace!("thumbnail of {:?} from {:?}", path, source.cover);
+
+        let mut bin = Vec::new();
+        fs.read(cache, &source.cover, &mut bin, &Scope::All)?;
+
+        let thumb = Arc::new(self.render(&bin, w, h)?);
+
+        if let Some(disk) = self.disk.as_ref() {
+            if let Err(e) = disk.put(&disk_key, &thumb) {
+                warn!("can not save thumbnail of {:?}: {:?}", path, e);
+            }
+        }
+
+        self.cache.lock().unwrap().put(key, (source, thumb.clone()));
+
+        Ok((thumb, newer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("200x300"), Some((200, 300)));
+        assert_eq!(parse_size("128"), Some((128, 128)));
+        assert_eq!(parse_size("0x10"), None);
+        assert_eq!(parse_size("axb"), None);
+    }
+
+    #[test]
+    fn test_cover_pick() {
+        let mut cover = EntryInfo::new("Cover.PNG", EntryKind::File);
+        cover.mime = None;
+        assert!(is_cover(&cover));
+
+        let page = EntryInfo::new("page1.jpg", EntryKind::File);
+        assert!(is_image(&page));
+        assert!(!is_cover(&page));
+
+        let dir = EntryInfo::new("cover.jpg", EntryKind::Dir);
+        assert!(!is_image(&dir));
+    }
+
+    #[test]
+    fn test_render() {
+        let t = Thumbnailer::new(4, 80, 64, None);
+
+        let img = image::DynamicImage::new_rgb8(200, 100);
+        let mut png = Vec::new();
+        img.write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+
+        let thumb = t.render(&png, 50, 50).unwrap();
+        let decoded = image::load_from_memory(&thumb).unwrap();
+
+        use image::GenericImageView;
+        assert_eq!(decoded.dimensions(), (50, 25));
+    }
+
+    #[test]
+    fn test_folder_follows_cover() {
+        extern crate filetime;
+        use image::GenericImageView;
+
+        let root = std::env::temp_dir().join(format!("thumb-folder-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let folder = root.join("book");
+        std::fs::create_dir_all(&folder).unwrap();
+
+        let cover = folder.join("cover.png");
+        let save = |w, h| {
+            image::DynamicImage::new_rgb8(w, h).save(&cover).unwrap();
+        };
+        save(200, 100);
+
+        let fs = Fs::new(&root).unwrap();
+        let cache = RwLock::new(ArchiveCache::new(1 << 20, 10));
+        let t = Thumbnailer::new(4, 80, 64, None);
+        let dims = |t: &Thumbnailer| {
+            let (thumb, _) = t
+                .thumbnail(&fs, &cache, &PathU8::from("book"), 50, 50)
+                .unwrap();
+            image::load_from_memory(&thumb).unwrap().dimensions()
+        };
+        assert_eq!(dims(&t), (50, 25));
+
+        //cover rewritten in place, folder mtime kept
+        let folder_mtime =
+            filetime::FileTime::from_last_modification_time(&std::fs::metadata(&folder).unwrap());
+        save(100, 200);
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        filetime::set_file_mtime(&cover, filetime::FileTime::from_system_time(later)).unwrap();
+        filetime::set_file_mtime(&folder, folder_mtime).unwrap();
+
+        assert_eq!(dims(&t), (25, 50));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,268 @@
+use super::config::ImageConfig;
+use super::listing::image_dimensions;
+use image::GenericImageView;
+
+use std::io::{Error, ErrorKind};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    pub fn mime(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<OutputFormat> {
+        match mime.trim() {
+            "image/jpeg" | "image/jpg" => Some(OutputFormat::Jpeg),
+            "image/png" => Some(OutputFormat::Png),
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &str) -> Result<OutputFormat, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            //image crate can decode webp but not encode it
+            "webp" => Err("webp output is not supported".to_owned()),
+            other => Err(format!("unknown output format {:?}", other)),
+        }
+    }
+}
+
+//what client asked for, already clamped to server limits
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub format: Option<OutputFormat>,
+    pub quality: Option<u8>,
+}
+
+fn parse_dimension(key: &str, v: &str, limit: u32) -> Result<u32, String> {
+    match v.parse::<u32>() {
+        Ok(0) | Err(_) => Err(format!("{} should be positive number, got {:?}", key, v)),
+        Ok(n) => Ok(std::cmp::min(n, limit)),
+    }
+}
+
+//first supported image type in Accept, unless client accepts anything
+fn format_from_accept(accept: &str, source_mime: &str) -> Option<OutputFormat> {
+    let types: Vec<&str> = accept
+        .split(',')
+        .map(|t| t.split(';').next().unwrap_or("").trim())
+        .collect();
+
+    if types
+        .iter()
+        .any(|t| *t == "*/*" || *t == "image/*" || *t == source_mime)
+    {
+        return None;
+    }
+
+    types
+        .iter()
+        .filter_map(|t| OutputFormat::from_mime(t))
+        .next()
+}
+
+impl Params {
+    //w, h, q and format query keys, Accept header is used when no
+    //format given. Ok(None) if nothing asked
+    pub fn parse(
+        query: Option<&str>,
+        accept: Option<&str>,
+        source_mime: &str,
+        settings: &ImageConfig,
+    ) -> Result<Option<Params>, String> {
+        let mut params = Params::default();
+
+        for pair in query.unwrap_or("").split('&') {
+            let mut kv = pair.splitn(2, '=');
+            let (k, v) = match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => (k, v),
+                _ => continue,
+            };
+
+            match k {
+                "w" => params.max_width = Some(parse_dimension(k, v, settings.transcode_max)?),
+                "h" => params.max_height = Some(parse_dimension(k, v, settings.transcode_max)?),
+                "q" => {
+                    let q = v
+                        .parse::<u8>()
+                        .map_err(|_| format!("q should be 1..=100, got {:?}", v))?;
+                    params.quality = Some(std::cmp::max(1, std::cmp::min(q, 100)));
+                }
+                "format" => params.format = Some(OutputFormat::from_name(v)?),
+                _ => {}
+            }
+        }
+
+        if params.format.is_none() {
+            params.format = accept.and_then(|a| format_from_accept(a, source_mime));
+        }
+
+        if params == Params::default() {
+            return Ok(None);
+        }
+
+        Ok(Some(params))
+    }
+
+    //string form used as cache key
+    pub fn key(&self) -> String {
+        format!(
+            "{:?}x{:?}-{:?}-{:?}",
+            self.max_width, self.max_height, self.format, self.quality
+        )
+    }
+
+    //format of transcoded output from source of mime
+    pub fn target(&self, mime: &str) -> OutputFormat {
+        self.format
+            .or_else(|| OutputFormat::from_mime(mime))
+            .unwrap_or(OutputFormat::Jpeg)
+    }
+
+    fn box_of(&self, w: u64, h: u64) -> (u64, u64) {
+        (
+            self.max_width.map_or(w, u64::from),
+            self.max_height.map_or(h, u64::from),
+        )
+    }
+}
+
+//re-encode image to satisfy params. None if original can be sent as is
+pub fn transcode(
+    bin: &[u8],
+    mime: &str,
+    params: &Params,
+    settings: &ImageConfig,
+) -> std::io::Result<Option<(Vec<u8>, OutputFormat)>> {
+    let source_format = OutputFormat::from_mime(mime);
+    let target = params.target(mime);
+
+    let dims = image_dimensions(std::io::Cursor::new(bin));
+
+    let fits = dims.map_or(false, |(w, h)| {
+        let (bw, bh) = params.box_of(w, h);
+        w <= bw && h <= bh
+    });
+
+    if fits && Some(target) == source_format && params.quality.is_none() {
+        trace!("image already fits {:?}, send as is", params);
+        return Ok(None);
+    }
+
+    let mut img = image::load_from_memory(bin)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}", e)))?;
+
+    let (w, h) = (u64::from(img.width()), u64::from(img.height()));
+    let (bw, bh) = params.box_of(w, h);
+
+    //only scale down, keeping aspect ratio
+    if w > bw || h > bh {
+        img = img.resize(bw as u32, bh as u32, image::FilterType::CatmullRom);
+    }
+
+    let output = match target {
+        OutputFormat::Jpeg => {
+            image::ImageOutputFormat::JPEG(params.quality.unwrap_or(settings.jpeg_quality))
+        }
+        OutputFormat::Png => image::ImageOutputFormat::PNG,
+    };
+
+    let mut out = Vec::new();
+    img.write_to(&mut out, output)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))?;
+
+    Ok(Some((out, target)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn png(w: u32, h: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        image::DynamicImage::new_rgb8(w, h)
+            .write_to(&mut out, image::ImageOutputFormat::PNG)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_parse() {
+        let settings = ImageConfig::default();
+
+        assert_eq!(
+            Params::parse(None, None, "image/png", &settings).unwrap(),
+            None
+        );
+
+        let params = Params::parse(
+            Some("w=100000&q=200&format=JPG&other=1"),
+            None,
+            "image/png",
+            &settings,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(params.max_width, Some(settings.transcode_max));
+        assert_eq!(params.quality, Some(100));
+        assert_eq!(params.format, Some(OutputFormat::Jpeg));
+
+        assert!(Params::parse(Some("w=0"), None, "image/png", &settings).is_err());
+        assert!(Params::parse(Some("format=webp"), None, "image/png", &settings).is_err());
+
+        //accept anything, no transcode
+        assert_eq!(
+            Params::parse(None, Some("image/webp,*/*;q=0.8"), "image/png", &settings).unwrap(),
+            None
+        );
+
+        let by_accept = Params::parse(None, Some("image/jpeg"), "image/png", &settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_accept.format, Some(OutputFormat::Jpeg));
+    }
+
+    #[test]
+    fn test_transcode() {
+        let settings = ImageConfig::default();
+        let source = png(400, 200);
+
+        let fits = Params {
+            max_width: Some(800),
+            ..Params::default()
+        };
+        assert!(transcode(&source, "image/png", &fits, &settings)
+            .unwrap()
+            .is_none());
+
+        let shrink = Params {
+            max_width: Some(100),
+            max_height: Some(100),
+            format: Some(OutputFormat::Jpeg),
+            quality: None,
+        };
+
+        let (out, format) = transcode(&source, "image/png", &shrink, &settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(format, OutputFormat::Jpeg);
+        assert_eq!(
+            image::load_from_memory(&out).unwrap().dimensions(),
+            (100, 50)
+        );
+    }
+}
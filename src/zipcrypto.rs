@@ -0,0 +1,277 @@
+extern crate flate2;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::error::{self, Error};
+use super::zipdir::{read_central, u16_at, u32_at, Central, LOCAL_SIG};
+
+//zip traditional (pkware) encryption, which archive library can not
+//read. entry is found in central directory, since local header of
+//encrypted entry usually leaves crc and sizes to data descriptor
+
+const FLAG_ENCRYPTED: u16 = 1;
+const FLAG_DESCRIPTOR: u16 = 1 << 3;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+const METHOD_AES: u16 = 99;
+
+//encryption header before entry data
+const HEADER_LEN: usize = 12;
+
+struct Crc32([u32; 256]);
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        let mut table = [0; 256];
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 {
+                    0xEDB8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *slot = c;
+        }
+        Crc32(table)
+    }
+
+    fn byte(&self, crc: u32, b: u8) -> u32 {
+        self.0[((crc ^ u32::from(b)) & 0xFF) as usize] ^ (crc >> 8)
+    }
+
+    fn of(&self, data: &[u8]) -> u32 {
+        !data.iter().fold(!0, |crc, b| self.byte(crc, *b))
+    }
+}
+
+struct Cipher<'a> {
+    crc: &'a Crc32,
+    keys: [u32; 3],
+}
+
+impl<'a> Cipher<'a> {
+    fn new(crc: &'a Crc32, password: &[u8]) -> Cipher<'a> {
+        let mut ret = Cipher {
+            crc,
+            keys: [0x1234_5678, 0x2345_6789, 0x3456_7890],
+        };
+        for b in password {
+            ret.update(*b);
+        }
+        ret
+    }
+
+    fn update(&mut self, b: u8) {
+        self.keys[0] = self.crc.byte(self.keys[0], b);
+        self.keys[1] = self.keys[1]
+            .wrapping_add(self.keys[0] & 0xFF)
+            .wrapping_mul(134_775_813)
+            .wrapping_add(1);
+        self.keys[2] = self.crc.byte(self.keys[2], (self.keys[1] >> 24) as u8);
+    }
+
+    fn decrypt(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            let temp = (self.keys[2] | 2) & 0xFFFF;
+            *b ^= (temp.wrapping_mul(temp ^ 1) >> 8) as u8;
+            self.update(*b);
+        }
+    }
+}
+
+fn broken(archive: &Path, what: &str) -> Error {
+    Error::Unsupported(format!("{:?} is not a readable zip: {}", archive, what))
+}
+
+fn io_failed(archive: &Path) -> impl Fn(std::io::Error) -> Error + '_ {
+    move |e| Error::io(format!("read {:?}", archive), e)
+}
+
+//every entry of central directory, not a zip is broken
+fn central(file: &mut File, archive: &Path) -> error::Result<Vec<Central>> {
+    read_central(file).map_err(|e| match e.kind() {
+        std::io::ErrorKind::InvalidData => broken(archive, &e.to_string()),
+        _ => io_failed(archive)(e),
+    })
+}
+
+fn central_of(file: &mut File, archive: &Path, offset: i64) -> error::Result<Central> {
+    central(file, archive)?
+        .into_iter()
+        .find(|c| c.offset == offset)
+        .ok_or_else(|| Error::NotFound(format!("no entry at {} in {:?}", offset, archive)))
+}
+
+//whether entry at offset (of local header) is encrypted
+pub fn is_encrypted(archive: &Path, offset: i64) -> error::Result<bool> {
+    let mut file = File::open(archive).map_err(io_failed(archive))?;
+    Ok(central_of(&mut file, archive, offset)?.flags & FLAG_ENCRYPTED != 0)
+}
+
+//offset of first encrypted entry, to check a password against
+pub fn first_encrypted(archive: &Path) -> error::Result<Option<i64>> {
+    let mut file = File::open(archive).map_err(io_failed(archive))?;
+    Ok(central(&mut file, archive)?
+        .into_iter()
+        .find(|c| c.flags & FLAG_ENCRYPTED != 0)
+        .map(|c| c.offset))
+}
+
+fn too_large(archive: &Path, cap: usize) -> Error {
+    Error::TooLarge(format!(
+        "encrypted entry in {:?} is larger than {} bytes allowed",
+        archive, cap
+    ))
+}
+
+//decrypted entry at offset, None if password is wrong. reading stops
+//once plain text is over cap
+pub fn read(
+    archive: &Path,
+    offset: i64,
+    password: &[u8],
+    cap: usize,
+) -> error::Result<Option<Vec<u8>>> {
+    let mut file = File::open(archive).map_err(io_failed(archive))?;
+    let central = central_of(&mut file, archive, offset)?;
+
+    if central.flags & FLAG_ENCRYPTED == 0 {
+        return Err(Error::BadRequest(format!(
+            "entry at {} in {:?} is not encrypted",
+            offset, archive
+        )));
+    }
+
+    if central.method == METHOD_AES {
+        return Err(Error::Unsupported(format!(
+            "aes encrypted entry in {:?} is not supported",
+            archive
+        )));
+    }
+
+    let mut local = [0; 30];
+    file.seek(SeekFrom::Start(offset as u64))
+        .and_then(|_| file.read_exact(&mut local))
+        .map_err(io_failed(archive))?;
+
+    if u32_at(&local, 0) != LOCAL_SIG {
+        return Err(broken(archive, "bad local header"));
+    }
+
+    let skip = u64::from(u16_at(&local, 26)) + u64::from(u16_at(&local, 28));
+
+    if central.csize < HEADER_LEN as u64 {
+        return Err(broken(archive, "encrypted entry too short"));
+    }
+
+    //not trusted for allocation before it is known to fit in file
+    let len = file.metadata().map_err(io_failed(archive))?.len();
+    if central.csize > len {
+        return Err(broken(archive, "encrypted entry beyond end of file"));
+    }
+
+    let mut data = vec![0; central.csize as usize];
+    file.seek(SeekFrom::Current(skip as i64))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(io_failed(archive))?;
+
+    let crc = Crc32::new();
+    let mut cipher = Cipher::new(&crc, password);
+    cipher.decrypt(&mut data);
+
+    //last header byte is checked before anything is decompressed
+    let check = if central.flags & FLAG_DESCRIPTOR != 0 {
+        (central.mtime >> 8) as u8
+    } else {
+        (central.crc >> 24) as u8
+    };
+
+    if data[HEADER_LEN - 1] != check {
+        return Ok(None);
+    }
+
+    let body = &data[HEADER_LEN..];
+
+    //one in 256 wrong passwords pass header check, data tells
+    let plain = match central.method {
+        METHOD_STORED if body.len() > cap => return Err(too_large(archive, cap)),
+        METHOD_STORED => body.to_vec(),
+        METHOD_DEFLATED => {
+            let mut plain = Vec::new();
+            let inflated = flate2::read::DeflateDecoder::new(body)
+                .take(cap as u64 + 1)
+                .read_to_end(&mut plain);
+
+            match inflated {
+                Err(_) => return Ok(None),
+                Ok(n) if n > cap => return Err(too_large(archive, cap)),
+                Ok(_) => plain,
+            }
+        }
+        method => {
+            return Err(Error::Unsupported(format!(
+                "compression method {} in {:?} is not supported",
+                method, archive
+            )))
+        }
+    };
+
+    if crc.of(&plain) != central.crc {
+        return Ok(None);
+    }
+
+    Ok(Some(plain))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const CAP: usize = 1024 * 1024;
+
+    #[test]
+    fn test_read() {
+        let zip = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/encrypted.zip");
+
+        //stored entry first, deflated one after it
+        assert!(is_encrypted(&zip, 0).unwrap());
+        assert_eq!(
+            read(&zip, 0, b"secret", CAP).unwrap().unwrap(),
+            b"cover image"
+        );
+        assert!(read(&zip, 0, b"wrong", CAP).unwrap().is_none());
+
+        let mut file = File::open(&zip).unwrap();
+        let mut bin = Vec::new();
+        file.read_to_end(&mut bin).unwrap();
+
+        let second = (1..bin.len())
+            .find(|at| u32_at(&bin, *at) == LOCAL_SIG)
+            .unwrap() as i64;
+        let page = read(&zip, second, b"secret", CAP).unwrap().unwrap();
+        assert_eq!(page, "page one ".repeat(50).as_bytes());
+
+        //over cap, whatever is declared
+        match read(&zip, second, b"secret", 100) {
+            Err(Error::TooLarge(_)) => {}
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        match read(&zip, 1, b"secret", CAP) {
+            Err(Error::NotFound(_)) => {}
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        assert_eq!(first_encrypted(&zip).unwrap(), Some(0));
+
+        let plain = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/test.zip");
+        assert!(!is_encrypted(&plain, 0).unwrap());
+        assert_eq!(first_encrypted(&plain).unwrap(), None);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+//central directory of zip, read by us since archive library tells
+//neither where an entry is nor its name as stored
+
+pub const LOCAL_SIG: u32 = 0x0403_4b50;
+const CENTRAL_SIG: u32 = 0x0201_4b50;
+const END_SIG: u32 = 0x0605_4b50;
+
+const CENTRAL_LEN: usize = 46;
+const END_LEN: usize = 22;
+//end record may be followed by a comment this long at most
+const END_SEARCH: u64 = END_LEN as u64 + 0xFFFF;
+
+pub fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from(b[at]) | u16::from(b[at + 1]) << 8
+}
+
+pub fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from(u16_at(b, at)) | u32::from(u16_at(b, at + 2)) << 16
+}
+
+//what central directory says about one entry
+#[derive(Clone, Debug)]
+pub struct Central {
+    //as stored, charset is up to whoever decodes it
+    pub name: Vec<u8>,
+    //of local header
+    pub offset: i64,
+    pub flags: u16,
+    pub method: u16,
+    pub mtime: u16,
+    pub crc: u32,
+    pub csize: u64,
+    pub size: u64,
+}
+
+impl Central {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with(b"/")
+    }
+}
+
+fn not_zip(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, what.to_owned())
+}
+
+//every entry of central directory in stored order. InvalidData if
+//reader does not hold a zip
+pub fn read_central<R: Read + Seek>(reader: &mut R) -> std::io::Result<Vec<Central>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let tail_start = len.saturating_sub(END_SEARCH);
+
+    let mut tail = Vec::new();
+    reader.seek(SeekFrom::Start(tail_start))?;
+    reader.read_to_end(&mut tail)?;
+
+    let end = (0..=tail.len().saturating_sub(END_LEN))
+        .rev()
+        .find(|at| tail.len() >= END_LEN && u32_at(&tail, *at) == END_SIG)
+        .ok_or_else(|| not_zip("no end of central directory"))?;
+
+    let dir_len = u32_at(&tail, end + 12) as usize;
+    let dir_start = u64::from(u32_at(&tail, end + 16));
+
+    //sizes come from file itself, do not allocate more than it holds
+    if dir_start + dir_len as u64 > len {
+        return Err(not_zip("central directory beyond end of file"));
+    }
+
+    let mut dir = vec![0; dir_len];
+    reader.seek(SeekFrom::Start(dir_start))?;
+    reader.read_exact(&mut dir)?;
+
+    let mut ret = Vec::new();
+
+    let mut at = 0;
+    while at + CENTRAL_LEN <= dir.len() && u32_at(&dir, at) == CENTRAL_SIG {
+        let name_len = u16_at(&dir, at + 28) as usize;
+        let extra_len = u16_at(&dir, at + 30) as usize;
+        let comment_len = u16_at(&dir, at + 32) as usize;
+
+        let name = dir
+            .get(at + CENTRAL_LEN..at + CENTRAL_LEN + name_len)
+            .ok_or_else(|| not_zip("name beyond central directory"))?;
+
+        ret.push(Central {
+            name: name.to_vec(),
+            offset: i64::from(u32_at(&dir, at + 42)),
+            flags: u16_at(&dir, at + 8),
+            method: u16_at(&dir, at + 10),
+            mtime: u16_at(&dir, at + 12),
+            crc: u32_at(&dir, at + 16),
+            csize: u64::from(u32_at(&dir, at + 20)),
+            size: u64::from(u32_at(&dir, at + 24)),
+        });
+
+        at += CENTRAL_LEN + name_len + extra_len + comment_len;
+    }
+
+    Ok(ret)
+}
+
+//central entry of each one archive library listed, in its order.
+//library skips some (like dirs) and names by its own guess, so the
+//two lists are walked together matching size, and name where stored
+//one is utf8. None if they can not be matched all
+pub fn pair<'a>(names: &[(&str, usize)], central: &'a [Central]) -> Option<Vec<&'a Central>> {
+    let mut left = central.iter().filter(|c| !c.is_dir());
+
+    names
+        .iter()
+        .map(|(name, size)| {
+            left.find(|c| {
+                c.size == *size as u64
+                    && std::str::from_utf8(&c.name).map_or(true, |stored| stored == *name)
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_read_central() {
+        let zip = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/test.zip");
+        let central = read_central(&mut std::fs::File::open(&zip).unwrap()).unwrap();
+
+        let under_root = central.iter().find(|c| c.name == b"under_root").unwrap();
+        let bin = std::fs::read(&zip).unwrap();
+        assert_eq!(u32_at(&bin, under_root.offset as usize), LOCAL_SIG);
+
+        let files: Vec<(&str, usize)> = central
+            .iter()
+            .filter(|c| !c.is_dir())
+            .map(|c| (std::str::from_utf8(&c.name).unwrap(), c.size as usize))
+            .collect();
+        assert_eq!(pair(&files, &central).unwrap().len(), files.len());
+
+        //library lists something zip does not hold
+        assert!(pair(&[("missing", 1)], &central).is_none());
+
+        let mut not_zip = std::io::Cursor::new(b"not a zip".to_vec());
+        assert_eq!(
+            read_central(&mut not_zip).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        //end record claiming a directory far larger than file
+        let mut end = END_SIG.to_le_bytes().to_vec();
+        end.resize(END_LEN, 0);
+        end[12..16].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert_eq!(
+            read_central(&mut std::io::Cursor::new(end))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}